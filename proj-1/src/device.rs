@@ -5,6 +5,7 @@ use std::{
 
 pub struct BlockDevice {
     file: File,
+    pub path: String,
     pub block_size: u64,
     pub reads: u64,
     pub writes: u64,
@@ -12,9 +13,10 @@ pub struct BlockDevice {
 
 impl BlockDevice {
     pub fn new(filename: String, blocksize: u64, truncate: bool) -> Result<BlockDevice, std::io::Error> {
-        let file: File = OpenOptions::new().truncate(truncate).read(true).write(true).create(true).open(filename)?;
+        let file: File = OpenOptions::new().truncate(truncate).read(true).write(true).create(true).open(&filename)?;
         let device = BlockDevice {
-            file: file,
+            file,
+            path: filename,
             block_size: blocksize,
             reads: 0,
             writes: 0,
@@ -22,18 +24,18 @@ impl BlockDevice {
         Ok(device)
     }
 
-    pub fn read_internal(&mut self, buf: &mut Vec<u8>,lba: u64) -> Result<(), std::io::Error> {
+    pub fn read_internal(&mut self, buf: &mut [u8], lba: u64) -> Result<(), std::io::Error> {
         self.file.seek(SeekFrom::Start(lba * self.block_size))?;
         self.file.read_exact(buf)?;
         Ok(())
     }
 
-    pub fn read(&mut self, buf: &mut Vec<u8>,lba: u64) -> Result<(), std::io::Error> {
+    pub fn read(&mut self, buf: &mut [u8], lba: u64) -> Result<(), std::io::Error> {
         self.reads += 1;
         self.read_internal(buf, lba)
     }
 
-    pub fn write_internal(&mut self, lba: u64, buf: &Vec<u8>) -> Result<usize, std::io::Error> {
+    pub fn write_internal(&mut self, lba: u64, buf: &[u8]) -> Result<usize, std::io::Error> {
         if buf.len() != self.block_size as usize {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
@@ -44,7 +46,7 @@ impl BlockDevice {
         self.file.write(buf)
     }
 
-    pub fn write(&mut self, lba: u64, buf: &Vec<u8>) -> Result<usize, std::io::Error> {
+    pub fn write(&mut self, lba: u64, buf: &[u8]) -> Result<usize, std::io::Error> {
         self.writes += 1;
         self.write_internal(lba, buf)
     }
@@ -0,0 +1,133 @@
+use std::cmp::Ordering;
+
+/*
+ * Tournament over the heads of `k` sorted sequences. Every inner node keeps
+ * the loser of the match played in it, the overall winner is kept aside, so
+ * replacing the winner's head replays a single path of `log k` matches.
+ * Exhausted sequences are represented by `None` and lose every match, equal
+ * heads are won by the lower sequence, which keeps the merge stable. Heads
+ * are ordered by `compare`, the one the sequences are sorted by.
+ */
+pub struct LoserTree<T, C: Fn(&T, &T) -> Ordering> {
+    heads: Vec<Option<T>>,
+    // `nodes[0]` is the winner, `nodes[1..k]` are the losers of inner nodes
+    nodes: Vec<usize>,
+    compare: C,
+}
+
+impl<T, C: Fn(&T, &T) -> Ordering> LoserTree<T, C> {
+    pub fn new(heads: Vec<Option<T>>, compare: C) -> LoserTree<T, C> {
+        let count = heads.len();
+        let mut tree = LoserTree {
+            heads,
+            nodes: vec![0; count.max(1)],
+            compare,
+        };
+
+        /*
+         * Leaf `i` sits at position `count + i`, winners of the matches are
+         * collected bottom-up, losers stay in their nodes
+         */
+        let mut winners = vec![0; 2 * count];
+        for index in 0..count {
+            winners[count + index] = index;
+        }
+        for node in (1..count).rev() {
+            let (left, right) = (winners[2 * node], winners[2 * node + 1]);
+            let (winner, loser) = match tree.beats(left, right) {
+                true => (left, right),
+                false => (right, left),
+            };
+            winners[node] = winner;
+            tree.nodes[node] = loser;
+        }
+        if count > 1 {
+            tree.nodes[0] = winners[1];
+        }
+
+        tree
+    }
+
+    fn beats(&self, first: usize, second: usize) -> bool {
+        match (&self.heads[first], &self.heads[second]) {
+            (Some(a), Some(b)) => match (self.compare)(a, b) {
+                Ordering::Less => true,
+                Ordering::Equal => first < second,
+                Ordering::Greater => false,
+            },
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => first < second,
+        }
+    }
+
+    /*
+     * Index of the sequence with the smallest head and the head itself,
+     * `None` once every sequence is exhausted
+     */
+    pub fn winner(&self) -> Option<(usize, &T)> {
+        let index = self.nodes[0];
+        self.heads
+            .get(index)
+            .and_then(|head| head.as_ref())
+            .map(|head| (index, head))
+    }
+
+    /*
+     * Puts the next head of the winning sequence in place of the current one
+     * and plays it up to the root
+     */
+    pub fn replace(&mut self, head: Option<T>) {
+        let count = self.heads.len();
+        let mut winner = self.nodes[0];
+        self.heads[winner] = head;
+
+        let mut node = (count + winner) / 2;
+        while node > 0 {
+            if self.beats(self.nodes[node], winner) {
+                std::mem::swap(&mut self.nodes[node], &mut winner);
+            }
+            node /= 2;
+        }
+        self.nodes[0] = winner;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(sequences: Vec<Vec<u32>>) -> Vec<(usize, u32)> {
+        let mut iters = sequences
+            .into_iter()
+            .map(|sequence| sequence.into_iter())
+            .collect::<Vec<_>>();
+        let mut tree = LoserTree::new(iters.iter_mut().map(|iter| iter.next()).collect(), u32::cmp);
+        let mut merged = vec![];
+
+        while let Some((index, head)) = tree.winner() {
+            merged.push((index, *head));
+            tree.replace(iters[index].next());
+        }
+        merged
+    }
+
+    #[test]
+    fn test_loser_tree() {
+        assert_eq!(merge(vec![]), vec![]);
+        assert_eq!(merge(vec![vec![]]), vec![]);
+        assert_eq!(merge(vec![vec![1, 2]]), vec![(0, 1), (0, 2)]);
+
+        let merged = merge(vec![vec![1, 4, 7], vec![], vec![2, 5], vec![0, 3, 6, 8], vec![9]]);
+        assert_eq!(
+            merged.iter().map(|(_, head)| *head).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+
+        /*
+         * Equal heads come out in the order of their sequences
+         */
+        let merged = merge(vec![vec![1, 2], vec![1], vec![0, 1]]);
+        assert_eq!(merged, vec![(2, 0), (0, 1), (1, 1), (2, 1), (0, 2)]);
+    }
+}
@@ -1,86 +1,194 @@
 pub mod device;
+pub mod loser_tree;
+pub mod merge;
 pub mod record;
+pub mod runs;
 pub mod tape;
 use std::io::BufRead;
 use std::{env, io};
 
-use crate::record::{IntRecord, Record};
+use crate::record::{IntRecord, Record, RecordOrder};
 
 use crate::device::BlockDevice;
 
+use crate::runs::RunFormation;
+
 use crate::tape::Tape;
 
 fn help() {
     println!(
         "usage:
+    -b <block size>
     -s
     -f <path>
-    -r <count>"
+    -r <count>
+    -m <natural|polyphase|balanced>
+    -t <helper tapes>, for polyphase (at least 2) and balanced (at least 4) only
+    -i <natural|replacement|memory>
+    --memory-blocks <count>
+    -o <key[:asc|desc],...>, keys: primes, sum, lex, field<index>"
     );
 }
 
+/*
+ * Where the records of the sorted tape come from
+ */
+enum Source {
+    Stdin,
+    Random(u32),
+    File(String),
+}
+
 fn main() {
-    //run().expect("Error while running:");
     let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => help(),
-        _ => (),
+
+    let mut blocksize: u64 = 230;
+    let mut source = None;
+    let mut mode = "natural".to_string();
+    let mut helpers: Option<u64> = None;
+    let mut formation = "natural".to_string();
+    let mut memory_blocks: u64 = 4;
+    let mut order = RecordOrder::default();
+
+    let mut index = 1;
+    while index < args.len() {
+        let value = args.get(index + 1);
+        match args[index].as_str() {
+            "-b" => {
+                blocksize = match value.map(|value| value.parse()) {
+                    Some(Ok(num)) => num,
+                    _ => panic!("Error when parsing `-b`"),
+                };
+                index += 1;
+            }
+            "-s" => source = Some(Source::Stdin),
+            "-r" => {
+                source = match value.map(|value| value.parse()) {
+                    Some(Ok(num)) => Some(Source::Random(num)),
+                    _ => panic!("Error when parsing `-r`"),
+                };
+                index += 1;
+            }
+            "-f" => {
+                source = Some(Source::File(value.expect("Missing path after `-f`").to_string()));
+                index += 1;
+            }
+            "-m" => {
+                mode = match value.map(|value| value.as_str()) {
+                    Some(mode @ ("natural" | "polyphase" | "balanced")) => mode.to_string(),
+                    _ => panic!("Error when parsing `-m`"),
+                };
+                index += 1;
+            }
+            "-t" => {
+                helpers = match value.map(|value| value.parse()) {
+                    Some(Ok(num)) => Some(num),
+                    _ => panic!("Error when parsing `-t`"),
+                };
+                index += 1;
+            }
+            "-i" => {
+                formation = match value.map(|value| value.as_str()) {
+                    Some(formation @ ("natural" | "replacement" | "memory")) => formation.to_string(),
+                    _ => panic!("Error when parsing `-i`"),
+                };
+                index += 1;
+            }
+            "-o" => {
+                order = match value.map(|value| RecordOrder::parse(value)) {
+                    Some(Ok(order)) => order,
+                    _ => panic!("Error when parsing `-o`"),
+                };
+                index += 1;
+            }
+            "--memory-blocks" => {
+                memory_blocks = match value.map(|value| value.parse()) {
+                    Some(Ok(num)) if num >= 1 => num,
+                    _ => panic!("Error when parsing `--memory-blocks`"),
+                };
+                index += 1;
+            }
+            _ => {
+                help();
+                return;
+            }
+        }
+        index += 1;
+    }
+
+    let minimum_helpers = match mode.as_str() {
+        "polyphase" => 2,
+        "balanced" => 4,
+        _ => 0,
+    };
+    if helpers.is_some() && minimum_helpers == 0 {
+        println!("{} merge takes no helper tapes", mode);
+        help();
+        return;
+    }
+    if helpers.is_some_and(|helpers| helpers < minimum_helpers) {
+        println!("{} merge needs at least {} helper tapes", mode, minimum_helpers);
+        help();
+        return;
     }
 
     let mut device: BlockDevice;
     let mut tape: Tape<IntRecord>;
     let mut record = IntRecord::new();
-    let mut blocksize: u64 = 230;
 
-    if args[1] == "-b" {
-        blocksize = match args[2].parse() {
-            Ok(num) => num,
-            Err(e) => panic!("Error when parsing `-b` : {}", e.to_string()),
-        };
-    }
+    match source {
+        Some(Source::Stdin) => {
+            device = BlockDevice::new("tape.txt".to_string(), blocksize, true)
+                .expect("Could not create device!");
+            tape = Tape::<IntRecord>::new(&mut device);
 
-    if args[3] == "-s" {
-        device =
-            BlockDevice::new("tape.txt".to_string(), blocksize, true).expect("Could not create device!");
-        tape = Tape::<IntRecord>::new(&mut device);
-
-        println!("Please write single record and follow it by `return`");
-        let stdin = io::stdin();
-        let handle = stdin.lock();
-        let lines = handle.lines();
-        for line in lines {
-            match record.from_string(line.expect("Could not read line")) {
-                Ok(_) => record.print(),
-                Err(_) => println!("Could not read the line into a record"),
+            println!("Please write single record and follow it by `return`");
+            let stdin = io::stdin();
+            let handle = stdin.lock();
+            let lines = handle.lines();
+            for line in lines {
+                match record.from_string(line.expect("Could not read line")) {
+                    Ok(_) => record.print(),
+                    Err(_) => println!("Could not read the line into a record"),
+                }
+                tape.write_next_record(&record);
             }
-            tape.write_next_record(&record);
         }
-    } else if args[3] == "-r" {
-        device =
-            BlockDevice::new("tape.txt".to_string(), blocksize, true).expect("Could not create device!");
-        tape = Tape::<IntRecord>::new(&mut device);
-
-        let num: u32 = match args[4].parse() {
-            Ok(num) => num,
-            Err(e) => panic!("Error when parsing `-r` : {}", e.to_string()),
-        };
+        Some(Source::Random(num)) => {
+            device = BlockDevice::new("tape.txt".to_string(), blocksize, true)
+                .expect("Could not create device!");
+            tape = Tape::<IntRecord>::new(&mut device);
 
-        for _ in 0..num {
-            record
-                .from_random()
-                .expect("Could not generate random record");
-            tape.write_next_record(&record);
+            for _ in 0..num {
+                record
+                    .from_random()
+                    .expect("Could not generate random record");
+                tape.write_next_record(&record);
+            }
+        }
+        Some(Source::File(path)) => {
+            device = BlockDevice::new(path, blocksize, false).expect("Could not open device!");
+            tape = Tape::<IntRecord>::new(&mut device);
+            // In order to read first buffer into memory
+            tape.read_next_record();
+            tape.set_head(0, 0);
+        }
+        None => {
+            help();
+            return;
         }
-    } else if args[3] == "-f" {
-        device = BlockDevice::new(args[4].to_string(), blocksize, false).expect("Could not open device!");
-        tape = Tape::<IntRecord>::new(&mut device);
-        // In order to read first buffer into memory
-        tape.read_next_record();
-        tape.set_head(0, 0);
-    } else {
-        panic!("Specify options for creation of tape!")
     }
 
-    tape.sort();
+    tape.set_order(order);
+    tape.set_run_formation(match formation.as_str() {
+        "replacement" => RunFormation::Replacement(memory_blocks),
+        "memory" => RunFormation::Memory(memory_blocks),
+        _ => RunFormation::Natural,
+    });
+    match mode.as_str() {
+        "polyphase" => tape.sort_polyphase(helpers.unwrap_or(2)),
+        "balanced" => tape.sort_balanced(helpers.unwrap_or(4)),
+        _ => tape.sort(),
+    }
     tape.flush();
 }
@@ -0,0 +1,572 @@
+use std::{cmp::Ordering, collections::VecDeque, path::Path};
+
+use colored::Colorize;
+
+use crate::{
+    device::BlockDevice,
+    loser_tree::LoserTree,
+    record::Record,
+    runs::{RunFormation, RunReader, SortReport},
+    tape::{print_run, print_summary, Tape},
+};
+
+/*
+ * Helper tapes are kept next to the sorted one, `tape.txt` gets
+ * `tape_helper1.txt`, `tape_helper2.txt` and so on
+ */
+pub fn helper_path(path: &str, index: u64) -> String {
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}_helper{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}_helper{}", stem, index),
+    };
+
+    path.with_file_name(file_name).to_string_lossy().to_string()
+}
+
+pub fn helper_devices(path: &str, count: u64, block_size: u64) -> Vec<BlockDevice> {
+    (1..=count)
+        .map(|index| {
+            BlockDevice::new(helper_path(path, index), block_size, true)
+                .expect("Could not create device!")
+        })
+        .collect()
+}
+
+pub fn reads<T: Record>(tapes: &[Tape<T>]) -> u64 {
+    tapes.iter().map(|tape| tape.reads()).sum()
+}
+
+pub fn writes<T: Record>(tapes: &[Tape<T>]) -> u64 {
+    tapes.iter().map(|tape| tape.writes()).sum()
+}
+
+/*
+ * Numbers of runs per tape on the next level of a polyphase distribution,
+ * from the tape with most runs. Each tape gets the runs of the first one
+ * plus those of the tape after it, so a single merge phase brings a level
+ * back to the previous one.
+ */
+fn next_level(level: &[u64]) -> Vec<u64> {
+    (0..level.len())
+        .map(|index| level[0] + level.get(index + 1).copied().unwrap_or(0))
+        .collect()
+}
+
+/*
+ * Next record of a run with `left` records still to read
+ */
+fn next_in_run<T: Record>(tape: &mut Tape<T>, left: &mut u64) -> Option<T> {
+    if *left == 0 {
+        return None;
+    }
+    *left -= 1;
+    tape.read_next_record()
+}
+
+/*
+ * Merges one run from each of `inputs`, given as tape index and run length,
+ * into `output` in the order given by `compare`. Runs of length 0 are
+ * dummies. Returns the length of the merged run.
+ */
+fn merge_run<T: Record, C: Fn(&T, &T) -> Ordering>(
+    tapes: &mut [Tape<T>],
+    inputs: &[(usize, u64)],
+    output: usize,
+    compare: &C,
+) -> u64 {
+    let mut left = inputs.iter().map(|(_, length)| *length).collect::<Vec<u64>>();
+    let mut heads = LoserTree::new(
+        inputs
+            .iter()
+            .zip(left.iter_mut())
+            .map(|((index, _), left)| next_in_run(&mut tapes[*index], left))
+            .collect(),
+        compare,
+    );
+
+    while let Some((position, record)) = heads.winner().map(|(position, record)| (position, *record)) {
+        tapes[output].write_next_record(&record);
+        heads.replace(next_in_run(&mut tapes[inputs[position].0], &mut left[position]));
+    }
+
+    inputs.iter().map(|(_, length)| length).sum()
+}
+
+/*
+ * Ends the output of a phase and rewinds the tape for reading
+ */
+fn finish_output<T: Record>(tape: &mut Tape<T>) {
+    tape.write_next_record(&T::new());
+    tape.flush();
+    tape.set_head(0, 0);
+}
+
+/*
+ * Copies a run of `length` records from the start of a helper back onto the
+ * first tape, when a merge ends on a helper
+ */
+fn copy_back<T: Record>(tapes: &mut [Tape<T>], from: usize, length: u64) {
+    println!("{}", format!("---->{: <53}", format!(" COPY FROM {} ", from)).green());
+    tapes[0].set_head(0, 0);
+    tapes[from].set_head(0, 0);
+    let mut left = length;
+    while let Some(record) = next_in_run(&mut tapes[from], &mut left) {
+        tapes[0].write_next_record(&record);
+    }
+    tapes[0].write_next_record(&T::new());
+    tapes[0].flush();
+}
+
+/*
+ * Whether the runs given by their lengths per tape make a single one, which
+ * then ends the sort. Natural runs are left on the first tape as they are,
+ * a formed run is copied back from its helper.
+ */
+fn single_run<T: Record>(tapes: &mut [Tape<T>], runs: &[VecDeque<u64>]) -> bool {
+    let mut filled = (0..tapes.len()).filter(|index| runs[*index].iter().any(|length| *length > 0));
+    match (filled.next(), filled.next()) {
+        (Some(_), Some(_)) => false,
+        (Some(index), None) if tapes[0].formation() != RunFormation::Natural => {
+            let length = runs[index].iter().sum();
+            copy_back(tapes, index, length);
+            true
+        }
+        _ => true,
+    }
+}
+
+fn print_series(series: usize) {
+    println!(
+        "{}",
+        format!(">{:->57}", format!(" SERIES {} ", series)).bright_blue()
+    );
+}
+
+/*
+ * Spreads runs of the first tape over the others so that their counts
+ * reach the next level of the distribution, topping them up with dummy
+ * runs in front. Lengths of the runs are kept aside, so runs that happen to
+ * continue each other on one tape are still merged separately. Returns the
+ * runs of every tape, none for the first one.
+ */
+fn distribute<T: Record>(tapes: &mut [Tape<T>]) -> Vec<VecDeque<u64>> {
+    println!("{}", format!("---->{: <57}", " DISTRIBUTE ").green());
+
+    let helpers = tapes.len() - 1;
+    let mut targets = vec![1u64; helpers];
+    let mut runs = vec![VecDeque::<u64>::new(); tapes.len()];
+    let mut reader = RunReader::new(tapes[0].formation(), tapes[0].order().clone());
+    let mut current = 0;
+
+    for tape in tapes.iter_mut() {
+        tape.set_head(0, 0);
+    }
+
+    while let Some((record, starts)) = reader.next_record(&mut tapes[0]) {
+        if starts {
+            let counts = (1..=helpers).map(|index| runs[index].len() as u64).collect::<Vec<u64>>();
+            if counts.iter().zip(&targets).all(|(count, target)| count >= target) {
+                targets = next_level(&targets);
+            }
+
+            current = 1 + (0..helpers)
+                .min_by_key(|&index| (counts[index] as i64 - targets[index] as i64, index))
+                .unwrap_or(0);
+            runs[current].push_back(0);
+        }
+
+        tapes[current].write_next_record(&record);
+        if let Some(length) = runs[current].back_mut() {
+            *length += 1;
+        }
+    }
+
+    let mut dummies = 0;
+    for index in 1..=helpers {
+        let tape = &mut tapes[index];
+        finish_output(tape);
+
+        while (runs[index].len() as u64) < targets[index - 1] {
+            runs[index].push_front(0);
+            dummies += 1;
+        }
+
+        println!("{}", format!("{:-^58}", format!(" TAPE {} ", index)).blue());
+        tape.print();
+    }
+    let series = runs.iter().map(|runs| runs.len()).sum::<usize>() - dummies;
+    println!(
+        "{}",
+        format!(">{:->57}", format!(" SERIES {}, DUMMIES {} ", series, dummies)).bright_blue()
+    );
+
+    runs
+}
+
+/*
+ * Polyphase merge of the first tape with the others as helpers. Every
+ * phase merges runs from all tapes but one into that one until some input
+ * tape runs out, which then takes the output of the next phase. Every
+ * phase merges as many runs at a time as there are helpers.
+ */
+fn polyphase<T: Record>(tapes: &mut [Tape<T>]) -> SortReport {
+    let mut runs = distribute(tapes);
+    let records = runs.iter().flatten().sum();
+    let initial = runs.iter().flatten().filter(|length| **length > 0).count() as u64;
+    let ways = tapes.len() as u64 - 1;
+    if single_run(tapes, &runs) {
+        return SortReport::new(&tapes[0], records, initial, 0, ways);
+    }
+
+    let order = tapes[0].order().clone();
+    let compare = |first: &T, second: &T| first.compare(second, &order);
+    let mut phase = 0;
+    let mut output = 0;
+    while runs.iter().map(|runs| runs.len()).sum::<usize>() > 1 {
+        phase += 1;
+        print_run(phase, reads(tapes), writes(tapes));
+        println!("{}", format!("---->{: <53}", format!(" MERGE INTO {} ", output)).green());
+
+        let inputs = (0..tapes.len()).filter(|index| *index != output).collect::<Vec<usize>>();
+        let count = inputs.iter().map(|index| runs[*index].len()).min().unwrap_or(0);
+
+        tapes[output].set_head(0, 0);
+        for _ in 0..count {
+            let lengths = inputs
+                .iter()
+                .map(|index| (*index, runs[*index].pop_front().unwrap_or(0)))
+                .collect::<Vec<(usize, u64)>>();
+            let length = merge_run(tapes, &lengths, output, &compare);
+            runs[output].push_back(length);
+        }
+
+        finish_output(&mut tapes[output]);
+        tapes[output].print();
+        print_series(runs.iter().map(|runs| runs.len()).sum());
+
+        output = inputs
+            .into_iter()
+            .find(|index| runs[*index].is_empty())
+            .unwrap_or(output);
+    }
+
+    /*
+     * Last phase may end on a helper, the result is copied back
+     */
+    let last = (0..tapes.len())
+        .find(|index| !runs[*index].is_empty())
+        .unwrap_or(0);
+    if last != 0 {
+        copy_back(tapes, last, runs[last][0]);
+    }
+
+    SortReport::new(&tapes[0], records, initial, phase, ways)
+}
+
+/*
+ * Balanced merge of the first tape with the others as helpers, split into
+ * two groups of the same size, with an odd number of helpers the last one
+ * is left unused so that every pass merges as many ways. Runs of the first tape are dealt in
+ * turn onto the first group, then every pass merges runs from all tapes of
+ * one group, one run from each, into the tapes of the other group in turn.
+ * Each pass cuts the number of runs by the size of its input group.
+ */
+fn balanced<T: Record>(tapes: &mut [Tape<T>]) -> SortReport {
+    println!("{}", format!("---->{: <57}", " DISTRIBUTE ").green());
+
+    let half = (tapes.len() - 1) / 2;
+    let mut groups = [(1..=half).collect::<Vec<usize>>(), (half + 1..=2 * half).collect()];
+    let mut runs = vec![VecDeque::<u64>::new(); tapes.len()];
+    let mut reader = RunReader::new(tapes[0].formation(), tapes[0].order().clone());
+    let mut current = 0;
+
+    for tape in tapes.iter_mut() {
+        tape.set_head(0, 0);
+    }
+
+    while let Some((record, starts)) = reader.next_record(&mut tapes[0]) {
+        if starts {
+            current = groups[0][runs.iter().map(|runs| runs.len()).sum::<usize>() % half];
+            runs[current].push_back(0);
+        }
+
+        tapes[current].write_next_record(&record);
+        if let Some(length) = runs[current].back_mut() {
+            *length += 1;
+        }
+    }
+
+    for (position, index) in groups[0].iter().enumerate() {
+        finish_output(&mut tapes[*index]);
+        println!("{}", format!("{:-^58}", format!(" TAPE {} ", position + 1)).blue());
+        tapes[*index].print();
+    }
+    let records = runs.iter().flatten().sum();
+    let initial = runs.iter().map(|runs| runs.len() as u64).sum();
+    let ways = half as u64;
+    print_series(initial as usize);
+    if single_run(tapes, &runs) {
+        return SortReport::new(&tapes[0], records, initial, 0, ways);
+    }
+
+    let order = tapes[0].order().clone();
+    let compare = |first: &T, second: &T| first.compare(second, &order);
+    let mut pass = 0;
+    while runs.iter().map(|runs| runs.len()).sum::<usize>() > 1 {
+        pass += 1;
+        print_run(pass, reads(tapes), writes(tapes));
+        println!("{}", format!("---->{: <53}", format!(" MERGE {}-WAY ", groups[0].len())).green());
+
+        let [inputs, outputs] = &groups;
+        for index in outputs {
+            tapes[*index].set_head(0, 0);
+        }
+
+        let mut turn = 0;
+        while inputs.iter().any(|index| !runs[*index].is_empty()) {
+            let lengths = inputs
+                .iter()
+                .filter_map(|index| runs[*index].pop_front().map(|length| (*index, length)))
+                .collect::<Vec<(usize, u64)>>();
+            let output = outputs[turn % outputs.len()];
+            let length = merge_run(tapes, &lengths, output, &compare);
+            runs[output].push_back(length);
+            turn += 1;
+        }
+
+        for index in outputs {
+            finish_output(&mut tapes[*index]);
+        }
+        tapes[outputs[0]].print();
+        print_series(runs.iter().map(|runs| runs.len()).sum());
+
+        groups.swap(0, 1);
+    }
+
+    /*
+     * Merges always end on a helper, the result is copied back
+     */
+    let last = groups[0][0];
+    copy_back(tapes, last, runs[last][0]);
+
+    SortReport::new(&tapes[0], records, initial, pass, ways)
+}
+
+impl<T: Record> Tape<'_, T> {
+    /*
+     * Polyphase merge with `helpers` helper tapes. Runs are read off this
+     * tape once, in generalized Fibonacci counts, and never split again.
+     */
+    pub fn sort_polyphase(&mut self, helpers: u64) {
+        if helpers < 2 {
+            panic!("Polyphase merge needs at least two helper tapes");
+        }
+
+        self.sort_with(helpers, polyphase);
+    }
+
+    /*
+     * Balanced merge with `helpers` helper tapes, half of them read and half
+     * written in every pass, an odd one out stays unused. More tapes cost one block buffer each, but merge
+     * more runs at once and so need fewer passes.
+     */
+    pub fn sort_balanced(&mut self, helpers: u64) {
+        if helpers < 4 {
+            panic!("Balanced merge needs at least four helper tapes");
+        }
+
+        self.sort_with(helpers, balanced);
+    }
+
+    /*
+     * Runs `merge` over this tape followed by `helpers` new helper tapes,
+     * `merge` returns the report printed in the summary
+     */
+    fn sort_with(&mut self, helpers: u64, merge: fn(&mut [Tape<T>]) -> SortReport) {
+        println!(
+            "{}",
+            format!("-------______{:_^32}______-------", " TAPE ").blue()
+        );
+        self.print();
+
+        let mut devices = helper_devices(self.path(), helpers, self.block_size());
+        let mut tapes = vec![self.share()];
+        tapes.extend(devices.iter_mut().map(Tape::new));
+
+        let report = merge(&mut tapes);
+        let (reads, writes) = (reads(&tapes), writes(&tapes));
+        drop(tapes);
+        self.reload();
+
+        println!(
+            "{}",
+            format!(">------======{:=^32}======------<", " DONE ")
+                .cyan()
+                .bold()
+        );
+        self.print();
+        print_summary(&report, reads, writes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::{IntRecord, RecordOrder};
+
+    use super::*;
+
+    /*
+     * Tape of `count` random records, returns them as bytes
+     */
+    fn random_tape(path: &str, count: u64, block_size: u64) -> Vec<Vec<u8>> {
+        let mut device = BlockDevice::new(path.to_string(), block_size, true).unwrap();
+        let mut tape = Tape::<IntRecord>::new(&mut device);
+        let mut record = IntRecord::new();
+        let mut written = vec![];
+
+        for _ in 0..count {
+            record.from_random().unwrap();
+            tape.write_next_record(&record);
+            written.push(record.get_bytes());
+        }
+        tape.write_next_record(&IntRecord::new());
+        tape.flush();
+
+        written
+    }
+
+    fn read_tape(path: &str, block_size: u64) -> Vec<IntRecord> {
+        let mut device = BlockDevice::new(path.to_string(), block_size, false).unwrap();
+        let mut tape = Tape::<IntRecord>::new(&mut device);
+        let mut records = vec![];
+
+        while let Some(record) = tape.read_next_record() {
+            records.push(record);
+        }
+        records
+    }
+
+    fn assert_sorted(records: &[IntRecord], mut written: Vec<Vec<u8>>, order: &RecordOrder) {
+        assert!(records.windows(2).all(|pair| pair[0].compare(&pair[1], order).is_le()));
+
+        let mut read = records.iter().map(|record| record.get_bytes()).collect::<Vec<_>>();
+        read.sort();
+        written.sort();
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn test_next_level() {
+        assert_eq!(next_level(&[1, 1]), vec![2, 1]);
+        assert_eq!(next_level(&[5, 3]), vec![8, 5]);
+        assert_eq!(next_level(&[1, 1, 1]), vec![2, 2, 1]);
+        assert_eq!(next_level(&[4, 3, 2]), vec![7, 6, 4]);
+    }
+
+    #[test]
+    fn test_helper_path() {
+        assert_eq!(helper_path("tape.txt", 1), "tape_helper1.txt");
+        assert_eq!(helper_path("dir/tape", 3), "dir/tape_helper3");
+    }
+
+    #[test]
+    fn test_sort_polyphase() {
+        for (helpers, count) in [(2, 0), (2, 1), (2, 200), (3, 333), (5, 100)] {
+            let path = format!("test_polyphase_{}_{}.txt", helpers, count);
+            let written = random_tape(&path, count, 230);
+
+            let mut device = BlockDevice::new(path.clone(), 230, false).unwrap();
+            let mut tape = Tape::<IntRecord>::new(&mut device);
+            tape.sort_polyphase(helpers);
+            tape.flush();
+            drop(tape);
+
+            assert_sorted(&read_tape(&path, 230), written, &RecordOrder::default());
+        }
+    }
+
+    #[test]
+    fn test_sort_formed_runs() {
+        for (name, formation) in [
+            ("replacement", RunFormation::Replacement(3)),
+            ("memory", RunFormation::Memory(2)),
+        ] {
+            for (count, balanced) in [(0, false), (5, false), (300, false), (5, true), (300, true)] {
+                let path = format!("test_formed_{}_{}_{}.txt", name, count, balanced);
+                let written = random_tape(&path, count, 230);
+
+                let mut device = BlockDevice::new(path.clone(), 230, false).unwrap();
+                let mut tape = Tape::<IntRecord>::new(&mut device);
+                tape.set_run_formation(formation);
+                match balanced {
+                    true => tape.sort_balanced(4),
+                    false => tape.sort_polyphase(4),
+                }
+                tape.flush();
+                drop(tape);
+
+                assert_sorted(&read_tape(&path, 230), written, &RecordOrder::default());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let order = RecordOrder::parse("sum:desc,field3").unwrap();
+        let written = random_tape("test_order.txt", 300, 230);
+
+        let mut device = BlockDevice::new("test_order.txt".to_string(), 230, false).unwrap();
+        let mut tape = Tape::<IntRecord>::new(&mut device);
+        tape.set_run_formation(RunFormation::Replacement(2));
+        tape.set_order(order.clone());
+        tape.sort_balanced(4);
+        tape.flush();
+        drop(tape);
+
+        let records = read_tape("test_order.txt", 230);
+        assert_sorted(&records, written, &order);
+    }
+
+    #[test]
+    fn test_sort_balanced() {
+        for (helpers, count) in [(4, 0), (4, 1), (4, 200), (5, 333), (8, 100)] {
+            let path = format!("test_balanced_{}_{}.txt", helpers, count);
+            let written = random_tape(&path, count, 230);
+
+            let mut device = BlockDevice::new(path.clone(), 230, false).unwrap();
+            let mut tape = Tape::<IntRecord>::new(&mut device);
+            tape.sort_balanced(helpers);
+            tape.flush();
+            drop(tape);
+
+            assert_sorted(&read_tape(&path, 230), written, &RecordOrder::default());
+        }
+    }
+
+    #[test]
+    fn test_balanced_odd_helpers() {
+        let path = "test_balanced_odd.txt";
+        let written = random_tape(path, 300, 230);
+
+        let mut device = BlockDevice::new(path.to_string(), 230, false).unwrap();
+        let mut tape = Tape::<IntRecord>::new(&mut device);
+        let mut devices = helper_devices(path, 5, 230);
+        let mut tapes = vec![tape.share()];
+        tapes.extend(devices.iter_mut().map(Tape::new));
+
+        let report = balanced(&mut tapes);
+        assert_eq!(report.ways, 2);
+        assert_eq!(tapes[5].writes(), 0);
+        drop(tapes);
+        tape.reload();
+        tape.flush();
+        drop(tape);
+
+        assert_sorted(&read_tape(path, 230), written, &RecordOrder::default());
+    }
+}
@@ -4,7 +4,14 @@ use byteorder::{ByteOrder, LittleEndian};
 use primes::is_prime;
 use rand::Rng;
 
-pub trait Record: Ord + Copy {
+/*
+ * Records are compared through `compare` under an ordering chosen at runtime,
+ * `Order::default()` is the one used unless a sort is told otherwise
+ */
+#[allow(clippy::wrong_self_convention)]
+pub trait Record: Copy + PartialEq {
+    type Order: Clone + Default;
+
     fn new() -> Self;
     fn get_size(&self) -> u64;
     fn get_bytes(&self) -> Vec<u8>;
@@ -12,6 +19,80 @@ pub trait Record: Ord + Copy {
     fn from_string(&mut self, string: String) -> Result<(), std::io::Error>;
     fn from_random(&mut self) -> Result<(), std::io::Error>;
     fn print(&self);
+    fn compare(&self, other: &Self, order: &Self::Order) -> Ordering;
+}
+
+/*
+ * Value of an `IntRecord` compared by an ordering
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SortKey {
+    Primes,
+    Sum,
+    Lexicographic,
+    Field(usize),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+/*
+ * Keys compared one after another, each one breaks the ties of those before
+ * it. Records equal on all of them are ordered by their numbers, so only
+ * identical records are equal.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordOrder {
+    pub keys: Vec<(SortKey, Direction)>,
+}
+
+impl Default for RecordOrder {
+    fn default() -> Self {
+        RecordOrder {
+            keys: vec![(SortKey::Primes, Direction::Ascending)],
+        }
+    }
+}
+
+impl RecordOrder {
+    /*
+     * Parses keys separated by commas, each of `primes`, `sum`, `lex` or
+     * `field<index>` optionally followed by `:asc` or `:desc`, for example
+     * `primes:desc,field0,sum`
+     */
+    pub fn parse(string: &str) -> Result<RecordOrder, std::io::Error> {
+        let invalid = |part: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Could not parse `{}` as a sort key", part),
+            )
+        };
+
+        let mut keys = vec![];
+        for part in string.split(',') {
+            let (name, direction) = match part.trim().split_once(':') {
+                Some((name, "asc")) => (name, Direction::Ascending),
+                Some((name, "desc")) => (name, Direction::Descending),
+                Some(_) => return Err(invalid(part)),
+                None => (part.trim(), Direction::Ascending),
+            };
+            let key = match name {
+                "primes" => SortKey::Primes,
+                "sum" => SortKey::Sum,
+                "lex" => SortKey::Lexicographic,
+                _ => match name.strip_prefix("field").map(|index| index.parse::<usize>()) {
+                    Some(Ok(index)) if index < 15 => SortKey::Field(index),
+                    _ => return Err(invalid(part)),
+                },
+            };
+            keys.push((key, direction));
+        }
+
+        Ok(RecordOrder { keys })
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -20,6 +101,19 @@ pub struct IntRecord {
 }
 
 impl IntRecord {
+    fn get_sum(&self) -> u64 {
+        self.numbers.iter().map(|num| *num as u64).sum()
+    }
+
+    fn compare_by(&self, other: &Self, key: SortKey) -> Ordering {
+        match key {
+            SortKey::Primes => self.get_primes().cmp(&other.get_primes()),
+            SortKey::Sum => self.get_sum().cmp(&other.get_sum()),
+            SortKey::Lexicographic => self.numbers.cmp(&other.numbers),
+            SortKey::Field(index) => self.numbers[index].cmp(&other.numbers[index]),
+        }
+    }
+
     fn get_primes(&self) -> u32 {
         let mut primes: u32 = 0;
 
@@ -33,12 +127,14 @@ impl IntRecord {
 }
 
 impl Record for IntRecord {
+    type Order = RecordOrder;
+
     fn new() -> Self {
         IntRecord { numbers: [0; 15] }
     }
 
     fn get_size(&self) -> u64 {
-        return std::mem::size_of_val(&self.numbers) as u64;
+        std::mem::size_of_val(&self.numbers) as u64
     }
 
     fn get_bytes(&self) -> Vec<u8> {
@@ -91,20 +187,17 @@ impl Record for IntRecord {
     fn print(&self) {
         println!("{:?} <=> {}", self.numbers, self.get_primes());
     }
-}
 
-impl Ord for IntRecord {
-    fn cmp(&self, other: &Self) -> Ordering {
-        let primes: u32 = self.get_primes();
-        let other_primes: u32 = other.get_primes();
-
-        primes.cmp(&other_primes)
-    }
-}
-
-impl PartialOrd for IntRecord {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    fn compare(&self, other: &Self, order: &RecordOrder) -> Ordering {
+        order
+            .keys
+            .iter()
+            .map(|(key, direction)| match direction {
+                Direction::Ascending => self.compare_by(other, *key),
+                Direction::Descending => other.compare_by(self, *key),
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| self.numbers.cmp(&other.numbers))
     }
 }
 
@@ -112,20 +205,7 @@ impl Eq for IntRecord {}
 
 impl PartialEq for IntRecord {
     fn eq(&self, other: &Self) -> bool {
-        let mut primes: u32 = 0;
-        let mut other_primes: u32 = 0;
-
-        for num in self.numbers {
-            if is_prime(num as u64) {
-                primes += 1;
-            }
-        }
-        for num in other.numbers {
-            if is_prime(num as u64) {
-                other_primes += 1;
-            }
-        }
-        primes == other_primes
+        self.numbers == other.numbers
     }
 }
 
@@ -149,7 +229,7 @@ mod tests {
 
     #[test]
     fn test_from_bytes() -> Result<(), std::io::Error> {
-        let mut bytes: Vec<u8> = vec![0u8; 16 * size_of::<u32>()];
+        let mut bytes: Vec<u8> = vec![0u8; 15 * size_of::<u32>()];
         bytes[0] = 1;
         bytes[1] = 0;
         bytes[2] = 0;
@@ -168,4 +248,37 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_order() -> Result<(), std::io::Error> {
+        let parse = |string: &str| {
+            let mut record = IntRecord::new();
+            record.from_string(string.to_string()).map(|_| record)
+        };
+        let less = |first: &IntRecord, second: &IntRecord, order: &RecordOrder| {
+            first.compare(second, order).is_lt()
+        };
+        let (a, b, c) = (parse("2 3 9")?, parse("5 1 8")?, parse("4 4 4")?);
+
+        // Primes first, the rest only breaks ties, so records differ
+        let order = RecordOrder::default();
+        assert!(less(&b, &a, &order) && less(&c, &b, &order));
+        assert!(a != parse("3 2 9")? && less(&a, &parse("3 2 9")?, &order));
+
+        let order = RecordOrder::parse("sum:desc")?;
+        assert!(less(&a, &b, &order) && less(&b, &c, &order));
+        let order = RecordOrder::parse("field1,lex:desc")?;
+        assert!(less(&b, &a, &order) && less(&a, &c, &order));
+        let order = RecordOrder::parse("primes:desc, field2")?;
+        assert!(less(&a, &b, &order) && less(&b, &c, &order));
+        assert!(less(&parse("3 2 1")?, &a, &order));
+        let order = RecordOrder::parse("lex")?;
+        assert!(less(&a, &c, &order) && less(&c, &b, &order));
+
+        assert!(RecordOrder::parse("field15").is_err());
+        assert!(RecordOrder::parse("sum:up").is_err());
+        assert!(RecordOrder::parse("").is_err());
+
+        Ok(())
+    }
 }
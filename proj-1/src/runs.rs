@@ -0,0 +1,302 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    rc::Rc,
+};
+
+use crate::{record::Record, tape::Tape};
+
+/*
+ * How the runs merged by a sort are cut out of the input tape
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RunFormation {
+    // Runs already present in the input, ended by every descent
+    Natural,
+    // Replacement selection through a heap of the given number of blocks
+    Replacement(u64),
+    // Given number of blocks read at a time and sorted in memory
+    Memory(u64),
+}
+
+/*
+ * Outcome of a sort, printed in its summary
+ */
+pub struct SortReport {
+    pub records: u64,
+    pub initial: u64,
+    pub passes: u64,
+    // Runs merged at a time
+    pub ways: u64,
+    // Passes in theory, known only for runs sorted in memory
+    pub expected: Option<u64>,
+}
+
+impl SortReport {
+    pub fn new<T: Record>(tape: &Tape<T>, records: u64, initial: u64, passes: u64, ways: u64) -> SortReport {
+        let expected = match tape.formation() {
+            RunFormation::Memory(blocks) => {
+                let per_block = RunReader::capacity(tape, 1) as u64;
+                Some(expected_passes(records.div_ceil(per_block), blocks, ways))
+            }
+            _ => None,
+        };
+
+        SortReport {
+            records,
+            initial,
+            passes,
+            ways,
+            expected,
+        }
+    }
+}
+
+/*
+ * `ceil(log_k(N / M))`, passes of a `ways`-way merge over `blocks` blocks
+ * cut into runs of `memory` blocks
+ */
+pub fn expected_passes(blocks: u64, memory: u64, ways: u64) -> u64 {
+    let runs = blocks.div_ceil(memory);
+    let mut passes = 0;
+    let mut merged = 1;
+    while merged < runs && ways > 1 {
+        merged *= ways;
+        passes += 1;
+    }
+    passes
+}
+
+/*
+ * Record waiting in the heap of replacement selection, ordered by its run
+ * first. `BinaryHeap` compares through `Ord` only, so the entry carries the
+ * order of the records.
+ */
+struct HeapEntry<T: Record> {
+    run: u64,
+    record: T,
+    order: Rc<T::Order>,
+}
+
+impl<T: Record> Ord for HeapEntry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.run
+            .cmp(&other.run)
+            .then_with(|| self.record.compare(&other.record, &self.order))
+    }
+}
+
+impl<T: Record> PartialOrd for HeapEntry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Record> Eq for HeapEntry<T> {}
+
+impl<T: Record> PartialEq for HeapEntry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+/*
+ * Reads records of a tape one by one together with a flag telling whether
+ * the record starts a new run under `order`. The tape is passed to every
+ * read, so that the caller can write to other tapes of the same list in
+ * between.
+ */
+pub struct RunReader<T: Record> {
+    formation: RunFormation,
+    order: Rc<T::Order>,
+    heap: BinaryHeap<Reverse<HeapEntry<T>>>,
+    // Sorted block of memory, from the largest record
+    sorted: Vec<T>,
+    filled: bool,
+    records: u64,
+    run: Option<u64>,
+    previous: Option<T>,
+}
+
+impl<T: Record> RunReader<T> {
+    pub fn new(formation: RunFormation, order: T::Order) -> RunReader<T> {
+        RunReader {
+            formation,
+            order: Rc::new(order),
+            heap: BinaryHeap::new(),
+            sorted: vec![],
+            filled: false,
+            records: 0,
+            run: None,
+            previous: None,
+        }
+    }
+
+    /*
+     * Number of records that fit into `blocks` blocks of the tape
+     */
+    pub fn capacity(tape: &Tape<T>, blocks: u64) -> usize {
+        (blocks * (tape.block_size() / T::new().get_size())).max(1) as usize
+    }
+
+    /*
+     * Number of records returned so far
+     */
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn next_record(&mut self, tape: &mut Tape<T>) -> Option<(T, bool)> {
+        let next = self.next(tape);
+        if next.is_some() {
+            self.records += 1;
+        }
+        next
+    }
+
+    fn next(&mut self, tape: &mut Tape<T>) -> Option<(T, bool)> {
+        match self.formation {
+            RunFormation::Natural => {
+                let record = tape.read_next_record()?;
+                let starts = self
+                    .previous
+                    .is_none_or(|previous| record.compare(&previous, &self.order).is_lt());
+                self.previous = Some(record);
+                Some((record, starts))
+            }
+            RunFormation::Replacement(blocks) => {
+                if !self.filled {
+                    let capacity = Self::capacity(tape, blocks);
+                    while self.heap.len() < capacity {
+                        match tape.read_next_record() {
+                            Some(record) => self.heap.push(Reverse(self.entry(0, record))),
+                            None => break,
+                        }
+                    }
+                    self.filled = true;
+                }
+
+                /*
+                 * The smallest record of the current run leaves the heap,
+                 * the one read in its place joins the run only if it does
+                 * not go below it, otherwise it waits for the next run
+                 */
+                let Reverse(HeapEntry { run, record, .. }) = self.heap.pop()?;
+                if let Some(next) = tape.read_next_record() {
+                    let next_run = match next.compare(&record, &self.order) {
+                        Ordering::Less => run + 1,
+                        _ => run,
+                    };
+                    self.heap.push(Reverse(self.entry(next_run, next)));
+                }
+
+                let starts = self.run != Some(run);
+                self.run = Some(run);
+                Some((record, starts))
+            }
+            RunFormation::Memory(blocks) => {
+                let starts = self.sorted.is_empty();
+                if starts {
+                    let capacity = Self::capacity(tape, blocks);
+                    while self.sorted.len() < capacity {
+                        match tape.read_next_record() {
+                            Some(record) => self.sorted.push(record),
+                            None => break,
+                        }
+                    }
+                    self.sorted.sort_by(|first, second| second.compare(first, &self.order));
+                }
+
+                self.sorted.pop().map(|record| (record, starts))
+            }
+        }
+    }
+
+    fn entry(&self, run: u64, record: T) -> HeapEntry<T> {
+        HeapEntry {
+            run,
+            record,
+            order: Rc::clone(&self.order),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        device::BlockDevice,
+        record::{IntRecord, RecordOrder},
+    };
+
+    use super::*;
+
+    /*
+     * Lengths of the runs read off a tape of `count` random records, checks
+     * that every run is sorted and no record is lost
+     */
+    fn run_lengths(path: &str, count: u64, formation: RunFormation) -> Vec<u64> {
+        let mut device = BlockDevice::new(path.to_string(), 230, true).unwrap();
+        let mut tape = Tape::<IntRecord>::new(&mut device);
+        let mut record = IntRecord::new();
+        for _ in 0..count {
+            record.from_random().unwrap();
+            tape.write_next_record(&record);
+        }
+        tape.write_next_record(&IntRecord::new());
+        tape.set_head(0, 0);
+
+        let order = RecordOrder::default();
+        let mut reader = RunReader::new(formation, order.clone());
+        let mut lengths = vec![];
+        let mut previous = None;
+        while let Some((record, starts)) = reader.next_record(&mut tape) {
+            if starts {
+                lengths.push(0);
+            } else {
+                assert!(previous.is_some_and(|previous| record.compare(&previous, &order).is_ge()));
+            }
+            *lengths.last_mut().unwrap() += 1;
+            previous = Some(record);
+        }
+
+        assert_eq!(lengths.iter().sum::<u64>(), count);
+        lengths
+    }
+
+    #[test]
+    fn test_run_reader() {
+        assert_eq!(run_lengths("test_runs_empty.txt", 0, RunFormation::Replacement(2)), vec![]);
+        assert_eq!(run_lengths("test_runs_single.txt", 1, RunFormation::Natural), vec![1]);
+
+        /*
+         * Replacement selection runs are about twice as long as the heap,
+         * 4 blocks of 230 bytes hold 12 records. Twice the heap is only the
+         * limit for long inputs, the first run is shorter (about 1.7 times
+         * the heap) and the last one partial, so on 1000 distinct records
+         * the mean stays just under 24. 20 leaves room for the randomness
+         * and is still far above the heap.
+         */
+        let natural = run_lengths("test_runs_natural.txt", 1000, RunFormation::Natural);
+        let replacement = run_lengths("test_runs_replacement.txt", 1000, RunFormation::Replacement(4));
+        assert!(replacement.len() * 4 < natural.len());
+        assert!(replacement.iter().take(replacement.len() - 1).all(|length| *length >= 12));
+        assert!(1000 / replacement.len() >= 20);
+
+        /*
+         * Memory runs fill the whole memory, but the last one
+         */
+        let memory = run_lengths("test_runs_memory.txt", 1000, RunFormation::Memory(4));
+        assert_eq!(memory.len(), 84);
+        assert!(memory.iter().take(83).all(|length| *length == 12));
+    }
+
+    #[test]
+    fn test_expected_passes() {
+        assert_eq!(expected_passes(0, 4, 2), 0);
+        assert_eq!(expected_passes(4, 4, 2), 0);
+        assert_eq!(expected_passes(5, 4, 2), 1);
+        assert_eq!(expected_passes(100, 4, 2), 5);
+        assert_eq!(expected_passes(100, 4, 5), 2);
+        assert_eq!(expected_passes(101, 4, 5), 3);
+    }
+}
@@ -1,6 +1,50 @@
 use colored::Colorize;
 
-use crate::{device::BlockDevice, record::Record};
+use crate::{
+    device::BlockDevice,
+    merge::helper_devices,
+    record::Record,
+    runs::{RunFormation, RunReader, SortReport},
+};
+
+/*
+ * Reads and writes of all tapes so far, printed before every run
+ */
+pub fn print_run(run: u64, reads: u64, writes: u64) {
+    println!(
+        "{}",
+        format!(
+            ">{: <57}",
+            format!(" RUN -> {}, READS -> {}, WRITES -> {} ", run, reads, writes)
+        )
+        .red()
+        .bold()
+    );
+}
+
+pub fn print_summary(report: &SortReport, reads: u64, writes: u64) {
+    println!(
+        "{}",
+        format!(">------======{:=^32}======------<", " SUMMARY ")
+            .cyan()
+            .bold()
+    );
+
+    let mut lines = vec![
+        format!(" RECORDS -> {} ", report.records),
+        format!(" INITIAL RUNS -> {} ", report.initial),
+        format!(" PASSES -> {} ({}-WAY) ", report.passes, report.ways),
+    ];
+    if let Some(expected) = report.expected {
+        lines.push(format!(" EXPECTED PASSES -> {} ", expected));
+    }
+    lines.push(format!(" READS -> {} ", reads));
+    lines.push(format!(" WRITES -> {} ", writes));
+
+    for line in lines {
+        println!("{}", format!(">{:->57}", line).red().bold());
+    }
+}
 
 pub struct Tape<'a, T: Record> {
     device: &'a mut BlockDevice,
@@ -10,21 +54,86 @@ pub struct Tape<'a, T: Record> {
     outdated: bool,
     dirty: bool,
     record: T,
+    formation: RunFormation,
+    order: T::Order,
 }
 
-impl<'a, T: Record> Tape<'_, T> {
-    pub fn new(device: &'a mut BlockDevice) -> Tape<T> {
+impl<'a, T: Record> Tape<'a, T> {
+    pub fn new(device: &'a mut BlockDevice) -> Tape<'a, T> {
         let mut tape: Tape<T> = Tape::<T> {
-            device: device,
+            device,
             offset: 0,
             lba: 0,
             buf: Vec::<u8>::new(),
             outdated: true,
             dirty: false,
             record: T::new(),
+            formation: RunFormation::Natural,
+            order: T::Order::default(),
         };
         tape.buf.resize(tape.device.block_size as usize, 0);
-        return tape;
+        tape
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.device.reads
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.device.writes
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.device.block_size
+    }
+
+    pub fn path(&self) -> &str {
+        &self.device.path
+    }
+
+    pub fn formation(&self) -> RunFormation {
+        self.formation
+    }
+
+    /*
+     * Sets how the first pass of a sort cuts this tape into runs
+     */
+    pub fn set_run_formation(&mut self, formation: RunFormation) {
+        self.formation = formation;
+    }
+
+    pub fn order(&self) -> &T::Order {
+        &self.order
+    }
+
+    /*
+     * Sets the ordering the records of this tape are sorted by
+     */
+    pub fn set_order(&mut self, order: T::Order) {
+        self.order = order;
+    }
+
+    /*
+     * Second tape over the same device, so that sorts can keep this one in a
+     * single list with their helpers. `reload` has to follow once it is gone.
+     */
+    pub fn share(&mut self) -> Tape<'_, T> {
+        self.flush();
+        let formation = self.formation;
+        let order = self.order.clone();
+        let mut tape = Tape::new(self.device);
+        tape.formation = formation;
+        tape.order = order;
+        tape
+    }
+
+    /*
+     * Rewinds the tape and drops its buffer, the next read goes to the device
+     */
+    pub fn reload(&mut self) {
+        self.set_head(0, 0);
+        self.flush();
+        self.outdated = true;
     }
 
     pub fn flush(&mut self) {
@@ -82,7 +191,7 @@ impl<'a, T: Record> Tape<'_, T> {
         }
 
         self.move_head_to_next();
-        return Some(self.record);
+        Some(self.record)
     }
 
     pub fn write_next_record(&mut self, record: &T) {
@@ -99,27 +208,38 @@ impl<'a, T: Record> Tape<'_, T> {
     }
 
     pub fn split(&mut self, helper: &mut Tape<T>, other_helper: &mut Tape<T>) -> u64 {
+        let mut runs = RunReader::new(RunFormation::Natural, self.order.clone());
+        self.split_runs(&mut runs, helper, other_helper)
+    }
+
+    /*
+     * Splits runs given by `runs` between the helpers in turn
+     */
+    fn split_runs(
+        &mut self,
+        runs: &mut RunReader<T>,
+        helper: &mut Tape<T>,
+        other_helper: &mut Tape<T>,
+    ) -> u64 {
         println!("{}", format!("---->{: <57}", " SPLIT ").green());
 
         self.set_head(0, 0);
         helper.set_head(0, 0);
         other_helper.set_head(0, 0);
-        let mut series: u64 = 1;
-        let mut previous_record = None;
+        let mut series: u64 = 0;
 
-        while let Some(record) = self.read_next_record() {
-            if Some(record) < previous_record {
+        while let Some((record, starts)) = runs.next_record(self) {
+            if starts {
                 series += 1;
             }
 
-            if series % 2 == 0 {
+            if series.is_multiple_of(2) {
                 helper.write_next_record(&record);
             } else {
                 other_helper.write_next_record(&record);
             }
-
-            previous_record = Some(record);
         }
+        let series = series.max(1);
 
         let empty_record = T::new();
         helper.write_next_record(&empty_record);
@@ -137,9 +257,16 @@ impl<'a, T: Record> Tape<'_, T> {
         series
     }
 
+    /*
+     * Merges the runs of the helpers back onto this tape, records are
+     * ordered by the order of this tape
+     */
     pub fn join(&mut self, helper: &mut Tape<T>, other_helper: &mut Tape<T>) -> u64 {
         println!("{}", format!("---->{: <53}", " JOIN ").green());
 
+        let order = self.order.clone();
+        let compare = |first: &T, second: &T| first.compare(second, &order);
+
         let mut series: u64 = 1;
 
         self.set_head(0, 0);
@@ -152,29 +279,29 @@ impl<'a, T: Record> Tape<'_, T> {
         let mut second = other_helper.read_next_record();
 
         loop {
-            let heads = vec![first, second];
+            let heads = [first, second];
             let min_option = heads
                 .iter()
                 .flatten()
-                .filter(|&x| Some(x) >= previous.as_ref())
-                .min();
-
-            let record;
-
-            if min_option.is_none() {
-                // Look for min without condition on `previous`
-                let other_min_option = heads.iter().flatten().min();
-
-                if let Some(min) = other_min_option {
-                    series += 1;
-                    record = min;
-                } else {
-                    // No records left
-                    break;
+                .filter(|x| previous.as_ref().is_none_or(|previous| compare(x, previous).is_ge()))
+                .min_by(|first, second| compare(first, second));
+
+            let record = match min_option {
+                Some(min) => min,
+                None => {
+                    // Look for min without condition on `previous`
+                    let other_min_option =
+                        heads.iter().flatten().min_by(|first, second| compare(first, second));
+
+                    if let Some(min) = other_min_option {
+                        series += 1;
+                        min
+                    } else {
+                        // No records left
+                        break;
+                    }
                 }
-            } else {
-                record = min_option.unwrap();
-            }
+            };
 
             self.write_next_record(record);
             if Some(record) == first.as_ref() {
@@ -202,35 +329,45 @@ impl<'a, T: Record> Tape<'_, T> {
         self.print();
         self.set_head(0, 0);
 
-        let mut first_device =
-            BlockDevice::new("helper1.txt".to_string(), self.device.block_size, true)
-                .expect("Could not create device!");
-        let mut first_helper = Tape::<T>::new(&mut first_device);
-        let mut second_device =
-            BlockDevice::new("helper2.txt".to_string(), self.device.block_size, true)
-                .expect("Could not create device!");
-        let mut second_helper = Tape::<T>::new(&mut second_device);
+        let mut devices = helper_devices(self.path(), 2, self.device.block_size);
+        let [first_device, second_device] = devices.as_mut_slice() else {
+            unreachable!("Natural merge uses two helper tapes");
+        };
+        let mut first_helper = Tape::<T>::new(first_device);
+        let mut second_helper = Tape::<T>::new(second_device);
 
+        let order = self.order.clone();
         let mut run: u64 = 1;
+        let mut joins = 0;
+        let mut initial = 0;
+        let mut records = 0;
         loop {
-            println!(
-                "{}",
-                format!(
-                    ">{: <57}",
-                    format!(
-                        " RUN -> {}, READS -> {}, WRITES -> {} ",
-                        run, self.device.reads + first_helper.device.reads + second_helper.device.reads, self.device.writes + first_helper.device.writes + second_helper.device.writes
-                    )
-                )
-                .red()
-                .bold()
+            print_run(
+                run,
+                self.device.reads + first_helper.device.reads + second_helper.device.reads,
+                self.device.writes + first_helper.device.writes + second_helper.device.writes,
             );
-            let mut series: u64 = self.split(&mut first_helper, &mut second_helper);
-            if series == 1 {
+            /*
+             * Only the first pass cuts runs its own way, later ones take
+             * the runs left by `join`
+             */
+            let formation = match run {
+                1 => self.formation,
+                _ => RunFormation::Natural,
+            };
+            let mut runs = RunReader::new(formation, order.clone());
+            let mut series: u64 = self.split_runs(&mut runs, &mut first_helper, &mut second_helper);
+            if run == 1 {
+                initial = series;
+                records = runs.records();
+            }
+            // A formed run still has to be joined back onto this tape
+            if series == 1 && formation == RunFormation::Natural {
                 break;
             }
 
             series = self.join(&mut first_helper, &mut second_helper);
+            joins += 1;
             if series == 1 {
                 break;
             }
@@ -246,44 +383,10 @@ impl<'a, T: Record> Tape<'_, T> {
         );
 
         self.print();
-        println!(
-            "{}",
-            format!(">------======{:=^32}======------<", " SUMMARY ")
-                .cyan()
-                .bold()
-        );
-
-        println!(
-            "{}",
-            format!(">{:->57}", format!(" RUNS -> {} ", run))
-                .red()
-                .bold()
-        );
-
-        println!(
-            "{}",
-            format!(
-                ">{:->57}",
-                format!(
-                    " READS -> {} ",
-                    self.device.reads + first_helper.device.reads + second_helper.device.reads
-                )
-            )
-            .red()
-            .bold()
-        );
-
-        println!(
-            "{}",
-            format!(
-                ">{:->57}",
-                format!(
-                    " WRITES -> {} ",
-                    self.device.writes + first_helper.device.writes + second_helper.device.writes
-                )
-            )
-            .red()
-            .bold()
+        print_summary(
+            &SortReport::new(self, records, initial, joins, 2),
+            self.device.reads + first_helper.device.reads + second_helper.device.reads,
+            self.device.writes + first_helper.device.writes + second_helper.device.writes,
         );
     }
 
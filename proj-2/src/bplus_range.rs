@@ -0,0 +1,421 @@
+use std::ops::Bound;
+
+use crate::{
+    bplus_tree::BPlusTree,
+    btree_core::BTreeCore,
+    btree_key::{BTreeKey, CompositeKey, KeyPart},
+    btree_record::BTreeRecord,
+    record::Record,
+};
+
+/*
+ * Page visited by the scan. For non-leaf pages `index` points at the record
+ * whose child is being visited, for leafs at the next key to return.
+ */
+struct Frame<K: BTreeKey> {
+    records: Vec<BTreeRecord<K>>,
+    index: usize,
+    leaf: bool,
+    next_lba: Option<u64>,
+}
+
+/*
+ * Ordered scan over keys within bounds. Front of the range descends once and
+ * then follows the links between leafs, back of the range has no links to
+ * follow and walks pages with an explicit stack. A data record that cannot
+ * be read is returned as an error and ends the scan.
+ */
+pub struct BPlusRange<'a, K: BTreeKey, T: Record> {
+    bplus_tree: &'a BPlusTree<K, T>,
+    lower: Bound<K>,
+    upper: Bound<K>,
+    front: Option<Frame<K>>,
+    back: Option<Vec<Frame<K>>>,
+    front_key: Option<K>,
+    back_key: Option<K>,
+    finished: bool,
+    pub index_reads: u64,
+    pub data_reads: u64,
+}
+
+impl<'a, K: BTreeKey, T: Record> BPlusRange<'a, K, T> {
+    pub fn new(bplus_tree: &'a BPlusTree<K, T>, lower: Bound<K>, upper: Bound<K>) -> Self {
+        BPlusRange {
+            bplus_tree,
+            lower,
+            upper,
+            front: None,
+            back: None,
+            front_key: None,
+            back_key: None,
+            finished: false,
+            index_reads: 0,
+            data_reads: 0,
+        }
+    }
+
+    /*
+     * Trailing record of a leaf is not a key, it is kept as `next_lba`
+     */
+    fn load_frame(&mut self, lba: u64) -> Frame<K> {
+        let page = self.bplus_tree.core.load_page(lba, u64::MAX);
+        self.index_reads += 1;
+
+        let leaf = BTreeCore::<K, T>::is_leaf(&page);
+        let next_lba = match leaf {
+            true => BPlusTree::<K, T>::next_leaf(&page),
+            false => None,
+        };
+
+        Frame {
+            records: page
+                .records
+                .iter()
+                .filter(|record| !leaf || record.key != K::invalid())
+                .map(|record| **record)
+                .collect(),
+            index: 0,
+            leaf,
+            next_lba,
+        }
+    }
+
+    fn below_upper(&self, key: K) -> bool {
+        match self.upper {
+            Bound::Included(upper) => key <= upper,
+            Bound::Excluded(upper) => key < upper,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn above_lower(&self, key: K) -> bool {
+        match self.lower {
+            Bound::Included(lower) => key >= lower,
+            Bound::Excluded(lower) => key > lower,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /*
+     * Leaf holding the first key that is not below the lower bound, or the
+     * leaf before it if that key starts the next leaf
+     */
+    fn first_leaf(&mut self) -> Frame<K> {
+        let mut frame = self.load_frame(self.bplus_tree.core.root_lba);
+
+        while !frame.leaf {
+            let index = frame
+                .records
+                .iter()
+                .position(|record| match self.lower {
+                    Bound::Included(lower) | Bound::Excluded(lower) => {
+                        record.key == K::invalid() || record.key > lower
+                    }
+                    Bound::Unbounded => true,
+                })
+                .expect("Non-leaf page without trailing record");
+            let child_lba = frame.records[index]
+                .child_lba
+                .expect("Non-leaf record without child!");
+            frame = self.load_frame(child_lba);
+        }
+
+        frame.index = frame
+            .records
+            .iter()
+            .position(|record| self.above_lower(record.key))
+            .unwrap_or(frame.records.len());
+        frame
+    }
+
+    fn next_front_record(&mut self) -> Option<BTreeRecord<K>> {
+        let mut frame = match self.front.take() {
+            Some(frame) => frame,
+            None => self.first_leaf(),
+        };
+
+        while frame.index == frame.records.len() {
+            frame = self.load_frame(frame.next_lba?);
+        }
+
+        frame.index += 1;
+        let found = frame.records[frame.index - 1];
+        self.front = Some(frame);
+        Some(found)
+    }
+
+    fn descend_back(&mut self, stack: &mut Vec<Frame<K>>, lba: u64) {
+        let mut lba = Some(lba);
+
+        while let Some(current) = lba {
+            let mut frame = self.load_frame(current);
+            frame.index = frame
+                .records
+                .iter()
+                .position(|record| record.key == K::invalid() || !self.below_upper(record.key))
+                .unwrap_or(frame.records.len());
+
+            lba = match frame.leaf {
+                true => None,
+                false => frame.records[frame.index].child_lba,
+            };
+            stack.push(frame);
+        }
+    }
+
+    fn descend_rightmost(&mut self, stack: &mut Vec<Frame<K>>, lba: u64) {
+        let mut lba = Some(lba);
+
+        while let Some(current) = lba {
+            let mut frame = self.load_frame(current);
+            if frame.leaf {
+                frame.index = frame.records.len();
+                lba = None;
+            } else {
+                frame.index = frame.records.len() - 1;
+                lba = frame.records[frame.index].child_lba;
+            }
+            stack.push(frame);
+        }
+    }
+
+    fn next_back_record(&mut self) -> Option<BTreeRecord<K>> {
+        let mut stack = match self.back.take() {
+            Some(stack) => stack,
+            None => {
+                let mut stack = vec![];
+                self.descend_back(&mut stack, self.bplus_tree.core.root_lba);
+                stack
+            }
+        };
+
+        let mut found = None;
+        while let Some(frame) = stack.last_mut() {
+            if frame.leaf {
+                if frame.index > 0 {
+                    frame.index -= 1;
+                    found = Some(frame.records[frame.index]);
+                    break;
+                }
+                stack.pop();
+                continue;
+            }
+
+            /*
+             * Child under `index` is done, the subtree on its left comes next
+             */
+            if frame.index == 0 {
+                stack.pop();
+                continue;
+            }
+
+            frame.index -= 1;
+            let child_lba = frame.records[frame.index]
+                .child_lba
+                .expect("Non-leaf record without child!");
+            self.descend_rightmost(&mut stack, child_lba);
+        }
+
+        self.back = Some(stack);
+        found
+    }
+
+    fn read_record(&mut self, record: BTreeRecord<K>) -> Result<(K, T), std::io::Error> {
+        self.data_reads += 1;
+        match self.bplus_tree.core.read_data(record.data_lba) {
+            Ok(data) => Ok((record.key, data)),
+            Err(error) => {
+                self.finished = true;
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<K: BTreeKey, T: Record> Iterator for BPlusRange<'_, K, T> {
+    type Item = Result<(K, T), std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.next_front_record() {
+            Some(record)
+                if self.below_upper(record.key)
+                    && self.back_key.is_none_or(|back_key| record.key < back_key) =>
+            {
+                self.front_key = Some(record.key);
+                Some(self.read_record(record))
+            }
+            _ => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+impl<K: BTreeKey, T: Record> DoubleEndedIterator for BPlusRange<'_, K, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.next_back_record() {
+            Some(record)
+                if self.above_lower(record.key)
+                    && self.front_key.is_none_or(|front_key| record.key > front_key) =>
+            {
+                self.back_key = Some(record.key);
+                Some(self.read_record(record))
+            }
+            _ => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+impl<A: KeyPart, B: KeyPart, T: Record> BPlusTree<CompositeKey<A, B>, T> {
+    /*
+     * Keys sharing the leading column, ordered by the rest of them
+     */
+    pub fn range_prefix(&self, first: A) -> BPlusRange<'_, CompositeKey<A, B>, T> {
+        self.range(CompositeKey::prefix(first))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{btree_key::IntKey, heap::HeapPage, record::IntRecord};
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    fn keys(range: impl Iterator<Item = Result<(IntKey, IntRecord), std::io::Error>>) -> Vec<i32> {
+        range
+            .map(|item| {
+                let (key, data) = item.unwrap();
+                assert_eq!(data.get_bytes(), record(key.value).get_bytes());
+                key.value
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bplus_range() -> Result<(), std::io::Error> {
+        let mut bplus_tree = BPlusTree::<IntKey, IntRecord>::create("test_bplus_range.hex", 88, 0)?; // t = 2
+        for value in (0..50).rev() {
+            bplus_tree.insert(IntKey { value: value * 2 }, record(value * 2))?;
+        }
+        let key = |value| IntKey { value };
+
+        assert_eq!(keys(bplus_tree.range(..)), (0..50).map(|x| x * 2).collect::<Vec<i32>>());
+        assert_eq!(keys(bplus_tree.range(key(10)..key(20))), vec![10, 12, 14, 16, 18]);
+        assert_eq!(keys(bplus_tree.range(key(11)..=key(20))), vec![12, 14, 16, 18, 20]);
+        assert_eq!(
+            keys(bplus_tree.range((Bound::Excluded(key(10)), Bound::Excluded(key(16))))),
+            vec![12, 14]
+        );
+        assert_eq!(keys(bplus_tree.range(key(95)..)), vec![96, 98]);
+        assert_eq!(keys(bplus_tree.range(key(200)..)), Vec::<i32>::new());
+
+        assert_eq!(keys(bplus_tree.range(..).rev()), (0..50).rev().map(|x| x * 2).collect::<Vec<i32>>());
+        assert_eq!(keys(bplus_tree.range(key(10)..key(20)).rev()), vec![18, 16, 14, 12, 10]);
+
+        let mut range = bplus_tree.range(key(10)..=key(20));
+        let mut values = vec![];
+        while let Some((front, _)) = range.next().transpose()? {
+            values.push(front.value);
+            if let Some((back, _)) = range.next_back().transpose()? {
+                values.push(back.value);
+            }
+        }
+        assert_eq!(values, vec![10, 20, 12, 18, 14, 16]);
+
+        /*
+         * Every record takes a data block of its own, in the order of
+         * insertion, so the wiped block held the record of key 20
+         */
+        bplus_tree.core.heap.write_page(39, &HeapPage::new(vec![0u8; 88]))?;
+        let items = bplus_tree.range(key(10)..).collect::<Vec<_>>();
+        assert_eq!(items.len(), 6);
+        assert!(items[5].is_err());
+
+        Ok(())
+    }
+
+    /*
+     * Full scan descends the leftmost path once and then reads every leaf
+     * exactly once by following the links
+     */
+    #[test]
+    fn test_bplus_range_reads() -> Result<(), std::io::Error> {
+        let mut bplus_tree =
+            BPlusTree::<IntKey, IntRecord>::create("test_bplus_range_reads.hex", 88, 0)?; // t = 2
+        for value in 0..100 {
+            bplus_tree.insert(IntKey { value }, record(value))?;
+        }
+
+        let mut range = bplus_tree.range(..);
+        assert_eq!(range.by_ref().count(), 100);
+        assert_eq!(range.data_reads, 100);
+
+        let mut path = 0;
+        let mut page = bplus_tree.core.load_page(bplus_tree.core.root_lba, u64::MAX);
+        while let Some(lba) = page.records[0].child_lba {
+            page = bplus_tree.core.load_page(lba, u64::MAX);
+            path += 1;
+        }
+        let mut leafs = 1;
+        while let Some(lba) = BPlusTree::<IntKey, IntRecord>::next_leaf(&page) {
+            page = bplus_tree.core.load_page(lba, u64::MAX);
+            leafs += 1;
+        }
+
+        assert_eq!(range.index_reads, path + leafs);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bplus_range_prefix() -> Result<(), std::io::Error> {
+        let mut tree = BPlusTree::<CompositeKey<u32, i64>, IntRecord>::create("test_bplus_range_prefix.hex", 30 * 4, 0)?; // t = 2
+        for customer in (1..=5u32).rev() {
+            for timestamp in [300i64, -20, 7, 1 << 40] {
+                tree.insert(CompositeKey::new(customer, timestamp), record(customer as i32))?;
+            }
+        }
+
+        let scanned = |range: BPlusRange<'_, CompositeKey<u32, i64>, IntRecord>| {
+            range
+                .map(|item| {
+                    let (key, data) = item.unwrap();
+                    assert_eq!(data.get_bytes(), record(key.first as i32).get_bytes());
+                    (key.first, key.second)
+                })
+                .collect::<Vec<(u32, i64)>>()
+        };
+        assert_eq!(
+            scanned(tree.range_prefix(3)),
+            vec![(3, -20), (3, 7), (3, 300), (3, 1 << 40)]
+        );
+        assert_eq!(
+            tree.range_prefix(5)
+                .rev()
+                .map(|item| item.map(|(key, _)| key.second))
+                .collect::<Result<Vec<i64>, std::io::Error>>()?,
+            vec![1 << 40, 300, 7, -20]
+        );
+        assert_eq!(scanned(tree.range_prefix(0)), vec![]);
+        assert_eq!(scanned(tree.range_prefix(6)), vec![]);
+        assert_eq!(tree.range(..).count(), 20);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,603 @@
+use std::ops::RangeBounds;
+
+use crate::{
+    bplus_range::BPlusRange,
+    btree::DuplicateMode,
+    btree_core::BTreeCore,
+    btree_key::BTreeKey,
+    btree_record::BTreeRecord,
+    bytes::Bytes,
+    device::BlockDevice,
+    page::Page,
+    record::Record,
+    superblock::BPLUS_SUPERBLOCK_MAGIC,
+};
+
+/*
+ * B+ tree over the same pages, heap file and superblock as `BTree`, all of
+ * them kept by `BTreeCore`. Only leafs point at data records, keys of
+ * non-leaf pages merely route the search: child of a record holds keys below
+ * its key and not below the key of the previous record. Every leaf ends with
+ * a record of invalid key whose `data_lba` holds the LBA of the next leaf,
+ * the last leaf has no such record.
+ */
+pub struct BPlusTree<K: BTreeKey, T: Record> {
+    pub core: BTreeCore<K, T>,
+}
+
+impl<K: BTreeKey, T: Record> BPlusTree<K, T> {
+    pub fn new(index_device: BlockDevice, data_device: BlockDevice, capacity: u64) -> Self {
+        let core = BTreeCore::<K, T>::new(
+            BPLUS_SUPERBLOCK_MAGIC,
+            index_device,
+            data_device,
+            DuplicateMode::Unique,
+            capacity,
+        )
+        .expect("Could not create BPlusTree");
+        BPlusTree::<K, T>::from_core(core)
+    }
+
+    pub fn create(path: &str, block_size: u64, capacity: u64) -> Result<Self, std::io::Error> {
+        let (index_device, data_device) = BTreeCore::<K, T>::create_devices(path, block_size)?;
+
+        Ok(BPlusTree::<K, T>::new(index_device, data_device, capacity))
+    }
+
+    pub fn open(path: &str, capacity: u64) -> Result<Self, std::io::Error> {
+        let core = BTreeCore::<K, T>::open(path, BPLUS_SUPERBLOCK_MAGIC, capacity)?;
+        Ok(BPlusTree::<K, T>::from_core(core))
+    }
+
+    fn from_core(core: BTreeCore<K, T>) -> Self {
+        println!(
+            "BPlusTree:\n\t- degree: {}\n\t- index block size: {}\n\t- record size: {}",
+            core.degree,
+            core.index_device.borrow().block_size,
+            BTreeRecord::<K>::get_size()
+        );
+
+        BPlusTree { core }
+    }
+
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.core.flush()
+    }
+
+    pub fn next_leaf(page: &Page<BTreeRecord<K>>) -> Option<u64> {
+        page.records
+            .last()
+            .filter(|record| record.key == K::invalid())
+            .map(|record| record.data_lba)
+    }
+
+    fn set_next_leaf(page: &mut Page<BTreeRecord<K>>, next_lba: Option<u64>) {
+        if BPlusTree::<K, T>::next_leaf(page).is_some() {
+            page.records.pop();
+        }
+        if let Some(lba) = next_lba {
+            page.records.push(Box::new(BTreeRecord::<K> {
+                child_lba: None,
+                key: K::invalid(),
+                data_lba: lba,
+            }));
+        }
+        page.dirty = true;
+    }
+
+    /*
+     * Index of the record whose child covers `key`, keys equal to a
+     * separator live on its right
+     */
+    fn child_index(page: &Page<BTreeRecord<K>>, key: K) -> usize {
+        page.records
+            .iter()
+            .position(|x| x.key == K::invalid() || x.key > key)
+            .expect("Non-leaf page without trailing record")
+    }
+
+    fn find(&self, key: K) -> Option<BTreeRecord<K>> {
+        let mut page = self.core.load_page(self.core.root_lba, u64::MAX);
+
+        while !BTreeCore::<K, T>::is_leaf(&page) {
+            let index = BPlusTree::<K, T>::child_index(&page, key);
+            let lba = page.records[index]
+                .child_lba
+                .expect("Non-leaf record without child!");
+            page = self.core.load_page(lba, page.lba);
+        }
+
+        page.records
+            .iter()
+            .find(|record| record.key == key)
+            .map(|record| **record)
+    }
+
+    pub fn search(&self, key: K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /*
+     * Iterates over records with keys within `range` in key order, forward
+     * scan follows the chain of leafs
+     */
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> BPlusRange<'_, K, T> {
+        BPlusRange::new(self, range.start_bound().cloned(), range.end_bound().cloned())
+    }
+
+    /*
+     * Record of the key, `None` if the key is absent
+     */
+    pub fn get(&self, key: K) -> Result<Option<T>, std::io::Error> {
+        self.core.read_found(self.find(key))
+    }
+
+    pub fn update(&mut self, key: K, record: T) -> Result<(), std::io::Error> {
+        let found = match self.find(key) {
+            Some(found) => found,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Key {} is not present in the index", key),
+                ))
+            }
+        };
+
+        self.core.heap.update(found.data_lba, &record.get_bytes())
+    }
+
+    /*
+     * Full leaf gives its upper half to `new_child`, which is linked right
+     * after it, and a copy of the first key of `new_child` goes up as the
+     * separator. Non-leaf pages split as in `BTree`, centre key moves up.
+     */
+    fn split_child(
+        parent: &mut Page<BTreeRecord<K>>,
+        child: &mut Page<BTreeRecord<K>>,
+        new_child: &mut Page<BTreeRecord<K>>,
+    ) {
+        let separator_key = if BTreeCore::<K, T>::is_leaf(child) {
+            let next_lba = BPlusTree::<K, T>::next_leaf(child);
+            BPlusTree::<K, T>::set_next_leaf(child, None);
+
+            let centre_index = BTreeCore::<K, T>::centre_index(child);
+            new_child.records.extend(child.records.drain(centre_index..));
+
+            BPlusTree::<K, T>::set_next_leaf(new_child, next_lba);
+            BPlusTree::<K, T>::set_next_leaf(child, Some(new_child.lba));
+            new_child.records[0].key
+        } else {
+            let centre_index = BTreeCore::<K, T>::centre_index(child);
+            let centre_record = child.records.remove(centre_index);
+
+            new_child.records.extend(child.records.drain(centre_index..));
+            child.records.push(Box::new(BTreeRecord::<K> {
+                child_lba: centre_record.child_lba,
+                key: K::invalid(),
+                data_lba: 0,
+            }));
+            centre_record.key
+        };
+
+        let child_record_index = parent
+            .records
+            .iter()
+            .position(|x| x.child_lba == Some(child.lba))
+            .expect("Tried to split `Page` that is not a child of `parent`");
+
+        parent.records[child_record_index].child_lba = Some(new_child.lba);
+        parent.records.insert(
+            child_record_index,
+            Box::new(BTreeRecord::<K> {
+                child_lba: Some(child.lba),
+                key: separator_key,
+                data_lba: 0,
+            }),
+        );
+
+        parent.dirty = true;
+        child.dirty = true;
+        new_child.dirty = true;
+    }
+
+    /*
+     * Keys are unique, inserting a key that is already present fails with
+     * `AlreadyExists` and leaves the tree untouched
+     */
+    pub fn insert(&mut self, key: K, record: T) -> Result<(), std::io::Error> {
+        let mut page = self.core.load_page(self.core.root_lba, u64::MAX);
+        if self.core.is_full(&page) {
+            page = self.core.split_root(page, BPlusTree::<K, T>::split_child)?;
+        }
+
+        while !BTreeCore::<K, T>::is_leaf(&page) {
+            let index = BPlusTree::<K, T>::child_index(&page, key);
+            let child_lba = page.records[index]
+                .child_lba
+                .expect("Non-leaf record without child!");
+            let mut child = self.core.load_page(child_lba, page.lba);
+
+            if self.core.is_full(&child) {
+                let lba = self.core.get_next_index_lba()?;
+                let mut new_child =
+                    Page::<BTreeRecord<K>>::empty(&self.core.index_device, lba, page.lba);
+                BPlusTree::<K, T>::split_child(&mut page, &mut child, &mut new_child);
+
+                if key >= page.records[index].key {
+                    child = new_child;
+                }
+            }
+
+            page = child;
+        }
+
+        let index = page
+            .records
+            .iter()
+            .position(|x| x.key == K::invalid() || x.key >= key)
+            .unwrap_or(page.records.len());
+        if index < page.records.len() && page.records[index].key == key {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Key {} is already present in the index", key.to_string().trim()),
+            ));
+        }
+
+        let data_lba = self.core.append_data(&record)?;
+        page.records.insert(
+            index,
+            Box::new(BTreeRecord::<K> {
+                child_lba: None,
+                key,
+                data_lba,
+            }),
+        );
+        page.dirty = true;
+
+        Ok(())
+    }
+
+    /*
+     * Same top-down scheme as `BTree::delete`, keys are removed from leafs
+     * only. Separators that no longer exist as keys still route correctly,
+     * so they stay until a rotation or merge replaces them.
+     */
+    pub fn delete(&mut self, key: K) -> Result<bool, std::io::Error> {
+        let mut page = self.core.load_page(self.core.root_lba, u64::MAX);
+        if self.core.lacks_headroom(&page) {
+            page = self.core.split_root(page, BPlusTree::<K, T>::split_child)?;
+        }
+        let mut is_root = true;
+
+        while !BTreeCore::<K, T>::is_leaf(&page) {
+            let index = BPlusTree::<K, T>::child_index(&page, key);
+            if self.core.split_for_delete(&mut page, index, BPlusTree::<K, T>::split_child)? {
+                continue;
+            }
+            let child = self.fill_child(&mut page, index)?;
+
+            /*
+             * Root lost its last key due to merge of its only two children
+             */
+            if is_root && BTreeCore::<K, T>::keys_count(&page) == 0 {
+                self.core.root_lba = child.lba;
+                self.core.dirty = true;
+                self.core.free_page(page)?;
+            }
+
+            is_root = false;
+            page = child;
+        }
+
+        let index = match page.records.iter().position(|x| x.key == key) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        let removed = page.records.remove(index);
+        page.dirty = true;
+
+        self.core.heap.delete(removed.data_lba)?;
+        Ok(true)
+    }
+
+    /*
+     * Returns child under `index` with spare keys, borrowing from a sibling
+     * or merging with one as in `BTree`
+     */
+    fn fill_child(
+        &mut self,
+        page: &mut Page<BTreeRecord<K>>,
+        index: usize,
+    ) -> Result<Page<BTreeRecord<K>>, std::io::Error> {
+        let child_lba = page.records[index]
+            .child_lba
+            .expect("Non-leaf record without child!");
+        let mut child = self.core.load_page(child_lba, page.lba);
+
+        loop {
+            if self.core.has_spare(&child) {
+                return Ok(child);
+            }
+
+            let mut left = None;
+            if index > 0 {
+                let left_lba = page.records[index - 1]
+                    .child_lba
+                    .expect("Non-leaf record without child!");
+                let mut left_page = self.core.load_page(left_lba, page.lba);
+
+                if self.core.has_spare(&left_page) {
+                    BPlusTree::<K, T>::rotate_right(page, index - 1, &mut left_page, &mut child);
+                    continue;
+                }
+                left = Some(left_page);
+            }
+
+            if index + 1 < page.records.len() {
+                let right_lba = page.records[index + 1]
+                    .child_lba
+                    .expect("Non-leaf record without child!");
+                let mut right = self.core.load_page(right_lba, page.lba);
+
+                if self.core.has_spare(&right) {
+                    BPlusTree::<K, T>::rotate_left(page, index, &mut child, &mut right);
+                    continue;
+                }
+                BPlusTree::<K, T>::merge_children(page, index, &mut child, &mut right);
+                self.core.free_page(right)?;
+                return Ok(child);
+            }
+
+            let mut left = left.expect("Page with a single child is not a root!");
+            BPlusTree::<K, T>::merge_children(page, index - 1, &mut left, &mut child);
+            self.core.free_page(child)?;
+            return Ok(left);
+        }
+    }
+
+    /*
+     * Moves the last key of `left` into `right`. Between leafs the key moves
+     * directly and the separator under `index` becomes a copy of it, between
+     * non-leaf pages it goes through the separator as in `BTree`.
+     */
+    fn rotate_right(
+        parent: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        left: &mut Page<BTreeRecord<K>>,
+        right: &mut Page<BTreeRecord<K>>,
+    ) {
+        if !BTreeCore::<K, T>::is_leaf(left) {
+            return BTreeCore::<K, T>::rotate_right(parent, index, left, right);
+        }
+
+        let left_next = BPlusTree::<K, T>::next_leaf(left);
+        BPlusTree::<K, T>::set_next_leaf(left, None);
+        let moved = left.records.pop().expect("Borrowing from an empty page!");
+        BPlusTree::<K, T>::set_next_leaf(left, left_next);
+
+        right.records.insert(0, moved);
+        parent.records[index].key = right.records[0].key;
+
+        parent.dirty = true;
+        left.dirty = true;
+        right.dirty = true;
+    }
+
+    /*
+     * Moves the first key of `right` into `left`, mirror of `rotate_right`
+     */
+    fn rotate_left(
+        parent: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        left: &mut Page<BTreeRecord<K>>,
+        right: &mut Page<BTreeRecord<K>>,
+    ) {
+        if !BTreeCore::<K, T>::is_leaf(left) {
+            return BTreeCore::<K, T>::rotate_left(parent, index, left, right);
+        }
+
+        let moved = right.records.remove(0);
+        let left_next = BPlusTree::<K, T>::next_leaf(left);
+        BPlusTree::<K, T>::set_next_leaf(left, None);
+        left.records.push(moved);
+        BPlusTree::<K, T>::set_next_leaf(left, left_next);
+        parent.records[index].key = right.records[0].key;
+
+        parent.dirty = true;
+        left.dirty = true;
+        right.dirty = true;
+    }
+
+    /*
+     * Appends all keys of `right` to `left` and drops the separator under
+     * `index`. Merged leafs take over the link of `right`, non-leaf pages
+     * pull the separator down between their halves as in `BTree`.
+     */
+    fn merge_children(
+        parent: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        left: &mut Page<BTreeRecord<K>>,
+        right: &mut Page<BTreeRecord<K>>,
+    ) {
+        if !BTreeCore::<K, T>::is_leaf(left) {
+            return BTreeCore::<K, T>::merge_children(parent, index, left, right);
+        }
+
+        parent.records.remove(index);
+        let right_next = BPlusTree::<K, T>::next_leaf(right);
+        BPlusTree::<K, T>::set_next_leaf(right, None);
+        BPlusTree::<K, T>::set_next_leaf(left, None);
+        left.records.append(&mut right.records);
+        BPlusTree::<K, T>::set_next_leaf(left, right_next);
+        parent.records[index].child_lba = Some(left.lba);
+
+        parent.dirty = true;
+        left.dirty = true;
+        right.dirty = true;
+    }
+}
+
+impl<K: BTreeKey, T: Record> Drop for BPlusTree<K, T> {
+    fn drop(&mut self) {
+        self.flush().expect("Could not write superblock on flush!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{btree::BTree, btree_key::IntKey, record::IntRecord};
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    /*
+     * Checks key order, page occupancy and equal depth of leafs, then walks
+     * the chain of leafs and returns keys in the order they are linked
+     */
+    fn collect_keys(bplus_tree: &BPlusTree<IntKey, IntRecord>) -> Vec<i32> {
+        fn walk(
+            bplus_tree: &BPlusTree<IntKey, IntRecord>,
+            lba: u64,
+            depth: u64,
+            leaf_depth: &mut Option<u64>,
+            leafs: &mut Vec<u64>,
+        ) {
+            let page = bplus_tree.core.load_page(lba, u64::MAX);
+            let keys = page
+                .records
+                .iter()
+                .filter(|record| record.key != IntKey::invalid())
+                .map(|record| record.key.value)
+                .collect::<Vec<i32>>();
+
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!((keys.len() as u64) < 2 * bplus_tree.core.degree);
+            if lba != bplus_tree.core.root_lba {
+                assert!(keys.len() as u64 >= bplus_tree.core.degree - 1);
+            }
+
+            if BTreeCore::<IntKey, IntRecord>::is_leaf(&page) {
+                assert_eq!(*leaf_depth.get_or_insert(depth), depth);
+                leafs.push(lba);
+                return;
+            }
+
+            for record in &page.records {
+                walk(bplus_tree, record.child_lba.unwrap(), depth + 1, leaf_depth, leafs);
+            }
+        }
+
+        let mut leafs = vec![];
+        walk(bplus_tree, bplus_tree.core.root_lba, 0, &mut None, &mut leafs);
+
+        let mut keys = vec![];
+        let mut lba = Some(leafs[0]);
+        let mut linked = vec![];
+        while let Some(current) = lba {
+            let page = bplus_tree.core.load_page(current, u64::MAX);
+            keys.extend(
+                page.records
+                    .iter()
+                    .filter(|record| record.key != IntKey::invalid())
+                    .map(|record| record.key.value),
+            );
+            linked.push(current);
+            lba = BPlusTree::<IntKey, IntRecord>::next_leaf(&page);
+        }
+
+        assert_eq!(linked, leafs);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        keys
+    }
+
+    #[test]
+    fn test_insert_get() -> Result<(), std::io::Error> {
+        let mut bplus_tree = BPlusTree::<IntKey, IntRecord>::create("test_bplus_insert.hex", 88, 0)?; // t = 2
+
+        for value in [50, 10, 70, 30, 90, 20, 80, 40, 60, 0, 100, 55, 65, 75, 85] {
+            bplus_tree.insert(IntKey { value }, record(value))?;
+        }
+        let error = bplus_tree.insert(IntKey { value: 30 }, record(31)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+
+        assert_eq!(
+            collect_keys(&bplus_tree),
+            vec![0, 10, 20, 30, 40, 50, 55, 60, 65, 70, 75, 80, 85, 90, 100]
+        );
+        assert_eq!(bplus_tree.get(IntKey { value: 30 })?.unwrap().get_bytes(), record(30).get_bytes());
+        assert_eq!(bplus_tree.get(IntKey { value: 85 })?.unwrap().get_bytes(), record(85).get_bytes());
+        assert!(bplus_tree.get(IntKey { value: 35 })?.is_none());
+
+        /*
+         * Non-leaf pages route only, their keys do not point at data
+         */
+        let root = bplus_tree.core.load_page(bplus_tree.core.root_lba, u64::MAX);
+        assert!(!BTreeCore::<IntKey, IntRecord>::is_leaf(&root));
+        assert!(root.records.iter().all(|record| record.data_lba == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete() -> Result<(), std::io::Error> {
+        let mut bplus_tree = BPlusTree::<IntKey, IntRecord>::create("test_bplus_delete.hex", 88, 0)?; // t = 2
+
+        for value in 0..60 {
+            bplus_tree.insert(IntKey { value }, record(value))?;
+        }
+
+        for value in (0..60).step_by(3) {
+            assert!(bplus_tree.delete(IntKey { value })?);
+        }
+        assert!(!bplus_tree.delete(IntKey { value: 3 })?);
+        assert_eq!(
+            collect_keys(&bplus_tree),
+            (0..60).filter(|value| value % 3 != 0).collect::<Vec<i32>>()
+        );
+
+        for value in (0..60).filter(|value| value % 3 != 0) {
+            assert!(bplus_tree.delete(IntKey { value })?);
+        }
+
+        let root = bplus_tree.core.load_page(bplus_tree.core.root_lba, u64::MAX);
+        assert!(BTreeCore::<IntKey, IntRecord>::is_leaf(&root));
+        assert!(root.records.is_empty());
+        drop(root);
+
+        let stats = bplus_tree.core.page_stats();
+        assert_eq!(stats.live_pages, 1);
+        assert_eq!(bplus_tree.core.pages_count, stats.live_pages + stats.free_pages + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen() -> Result<(), std::io::Error> {
+        let path = "test_bplus_reopen.hex";
+        {
+            let mut bplus_tree = BPlusTree::<IntKey, IntRecord>::create(path, 21 * 6, 0)?; // t = 3
+            for value in (0..40).rev() {
+                bplus_tree.insert(IntKey { value }, record(value))?;
+            }
+            bplus_tree.update(IntKey { value: 7 }, record(700))?;
+        }
+
+        assert_eq!(
+            BTree::<IntKey, IntRecord>::open(path, 0).err().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        let mut bplus_tree = BPlusTree::<IntKey, IntRecord>::open(path, 0)?;
+        assert_eq!(collect_keys(&bplus_tree), (0..40).collect::<Vec<i32>>());
+        assert_eq!(bplus_tree.get(IntKey { value: 7 })?.unwrap().get_bytes(), record(700).get_bytes());
+
+        bplus_tree.insert(IntKey { value: 40 }, record(40))?;
+        assert!(bplus_tree.delete(IntKey { value: 0 })?);
+        assert_eq!(collect_keys(&bplus_tree), (1..41).collect::<Vec<i32>>());
+
+        Ok(())
+    }
+}
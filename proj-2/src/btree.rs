@@ -1,80 +1,358 @@
-use std::{cell::RefCell, rc::Rc, vec};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    ops::RangeBounds,
+    path::Path,
+    rc::Rc,
+    vec,
+};
 
 use crate::{
-    btree_key::{BTreeKey, IntKey},
+    btree_core::BTreeCore,
+    btree_dot::DotSteps,
+    btree_key::BTreeKey,
+    buffer_pool::BufferPool,
+    btree_range::BTreeRange,
     btree_record::BTreeRecord,
     bytes::Bytes,
     device::BlockDevice,
+    heap::HeapFile,
     page::Page,
     record::Record,
+    superblock::SUPERBLOCK_MAGIC,
+    wal::{LogImage, Wal, WAL_DATA_DEVICE, WAL_INDEX_DEVICE},
 };
 
+/*
+ * Log is checkpointed on flush or once it grows past this size
+ */
+pub const WAL_CHECKPOINT_SIZE: u64 = 1 << 20;
+
+/*
+ * What happens to a full page met on the way down during insertion
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InsertPolicy {
+    Split,
+    Compensation,
+}
+
+/*
+ * What happens to a key that is already in the index, chosen once when the
+ * tree is created. `Unique` rejects it, `Replace` overwrites its record and
+ * `Multimap` keeps records of equal keys next to each other in the order
+ * they were inserted.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DuplicateMode {
+    Unique,
+    Replace,
+    Multimap,
+}
+
+impl DuplicateMode {
+    pub fn from_superblock(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(DuplicateMode::Unique),
+            1 => Some(DuplicateMode::Replace),
+            2 => Some(DuplicateMode::Multimap),
+            _ => None,
+        }
+    }
+
+    pub fn to_superblock(self) -> u32 {
+        match self {
+            DuplicateMode::Unique => 0,
+            DuplicateMode::Replace => 1,
+            DuplicateMode::Multimap => 2,
+        }
+    }
+}
+
 pub struct BTree<K: BTreeKey, T: Record> {
-    pub index_device: Rc<RefCell<BlockDevice>>,
-    pub data_device: Rc<RefCell<BlockDevice>>,
-    pub index_root: Page<BTreeRecord<K>>,
-    pub working_page: Option<Page<BTreeRecord<K>>>,
-    pub helper_page: Option<Page<BTreeRecord<K>>>,
+    pub core: BTreeCore<K, T>,
     pub loaded_data: Vec<T>,
-    pub degree: u64,
-    pub pages_count: u64,
+    pub insert_policy: InsertPolicy,
+    pub splits: u64,
+    pub compensations: u64,
+    pub path: Option<String>,
+    pub reorganize_threshold: Option<f64>,
+    pub wal: Option<Wal>,
+    pub dot_steps: Option<DotSteps>,
 }
 
 impl<K: BTreeKey, T: Record> BTree<K, T> {
     pub fn new(index_device: BlockDevice, data_device: BlockDevice) -> Self {
-        let block_size = index_device.block_size;
-        let record_size = BTreeRecord::<K>::get_size();
-        let child_count: u64 = index_device.block_size / BTreeRecord::<K>::get_size();
-        let degree = child_count / 2;
-        let index_device = Rc::new(RefCell::new(index_device));
-        let data_device = Rc::new(RefCell::new(data_device));
-
-        let btree = BTree {
-            index_device: index_device.clone(),
-            data_device: data_device.clone(),
-            index_root: Page::new(&index_device.clone(), 0, u64::max_value()),
-            working_page: None,
-            helper_page: None,
-            loaded_data: vec![],
-            degree: degree,
-            pages_count: 1,
-        };
+        BTree::<K, T>::with_duplicates(index_device, data_device, DuplicateMode::Unique, 0)
+    }
+
+    /*
+     * `capacity` is the number of index blocks cached by the buffer pool
+     */
+    pub fn with_duplicates(
+        index_device: BlockDevice,
+        data_device: BlockDevice,
+        duplicate_mode: DuplicateMode,
+        capacity: u64,
+    ) -> Self {
+        let core = BTreeCore::<K, T>::new(
+            SUPERBLOCK_MAGIC,
+            index_device,
+            data_device,
+            duplicate_mode,
+            capacity,
+        )
+        .expect("Could not create BTree");
+        BTree::<K, T>::from_core(core)
+    }
+
+    /*
+     * Creates a fresh tree, data is kept in a file next to the index
+     */
+    pub fn create(path: &str, block_size: u64, capacity: u64) -> Result<Self, std::io::Error> {
+        BTree::<K, T>::create_with_duplicates(path, block_size, DuplicateMode::Unique, capacity)
+    }
+
+    pub fn create_with_duplicates(
+        path: &str,
+        block_size: u64,
+        duplicate_mode: DuplicateMode,
+        capacity: u64,
+    ) -> Result<Self, std::io::Error> {
+        let (index_device, data_device) = BTreeCore::<K, T>::create_devices(path, block_size)?;
+
+        let mut btree =
+            BTree::<K, T>::with_duplicates(index_device, data_device, duplicate_mode, capacity);
+        btree.path = Some(path.to_string());
+        Ok(btree)
+    }
 
+    /*
+     * Reopens a tree written by `create` or `new` and resumes where the
+     * previous session stopped
+     */
+    pub fn open(path: &str, capacity: u64) -> Result<Self, std::io::Error> {
+        BTreeCore::<K, T>::check_exists(path)?;
+        BTree::<K, T>::finish_reorganize(path)?;
+        let wal = BTree::<K, T>::replay_wal(path)?;
+
+        let core = BTreeCore::<K, T>::open(path, SUPERBLOCK_MAGIC, capacity)?;
+        let mut btree = BTree::<K, T>::from_core(core);
+        btree.path = Some(path.to_string());
+        if let Some(wal) = wal {
+            btree.core.index_device.borrow_mut().hold_dirty = true;
+            btree.core.heap.defer_writes();
+            btree.wal = Some(wal);
+        }
+        Ok(btree)
+    }
+
+    fn from_core(core: BTreeCore<K, T>) -> Self {
         println!(
             "BTree:\n\t- degree: {}\n\t- index block size: {}\n\t- record size: {}",
-            degree, block_size, record_size
+            core.degree,
+            core.index_device.borrow().block_size,
+            BTreeRecord::<K>::get_size()
         );
 
-        btree
+        BTree {
+            core,
+            loaded_data: vec![],
+            insert_policy: InsertPolicy::Split,
+            splits: 0,
+            compensations: 0,
+            path: None,
+            reorganize_threshold: None,
+            wal: None,
+            dot_steps: None,
+        }
+    }
+
+    /*
+     * With the redo log enabled the last operation is committed and the log
+     * checkpointed, otherwise the superblock and cached pages are written
+     */
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        if self.wal.is_some() {
+            self.commit()?;
+            return self.checkpoint();
+        }
+
+        self.core.flush()
+    }
+
+    pub fn wal_path(path: &str) -> String {
+        format!("{}.wal", path)
+    }
+
+    /*
+     * From now on pages changed by an operation stay in memory until the
+     * operation ends, then they are logged together with the superblock
+     * and only after that written into their devices
+     */
+    pub fn enable_wal(&mut self) -> Result<(), std::io::Error> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Only trees created by `create` or `open` can keep a redo log",
+                ))
+            }
+        };
+
+        self.flush()?;
+        self.wal = Some(Wal::new(&BTree::<K, T>::wal_path(&path), true)?);
+        self.core.index_device.borrow_mut().hold_dirty = true;
+        self.core.heap.defer_writes();
+
+        Ok(())
+    }
+
+    /*
+     * Writes committed images of a log left by a previous session into the
+     * devices, the log is returned so that the tree keeps using it
+     */
+    fn replay_wal(path: &str) -> Result<Option<Wal>, std::io::Error> {
+        let wal_path = BTree::<K, T>::wal_path(path);
+        if !Path::new(&wal_path).exists() {
+            return Ok(None);
+        }
+
+        let mut wal = Wal::new(&wal_path, false)?;
+        let device_paths = [path.to_string(), BTreeCore::<K, T>::data_path(path)];
+        let mut devices = HashMap::<(u8, usize), BlockDevice>::new();
+
+        for image in wal.read()? {
+            let device = match devices.entry((image.device, image.bytes.len())) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let device_path = device_paths.get(image.device as usize).ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, "Unknown device in redo log")
+                    })?;
+                    entry.insert(BlockDevice::new(
+                        device_path.clone(),
+                        image.bytes.len() as u64,
+                        false,
+                    )?)
+                }
+            };
+
+            device.write(image.lba, &image.bytes)?;
+        }
+
+        for device in devices.values_mut() {
+            device.sync()?;
+        }
+        wal.clear()?;
+
+        Ok(Some(wal))
+    }
+
+    /*
+     * Ends an operation: logs every page it changed and writes them into the
+     * devices afterwards. Does nothing without the log, pages are written as
+     * soon as they are released then.
+     */
+    pub fn commit(&mut self) -> Result<(), std::io::Error> {
+        if self.wal.is_none() {
+            return Ok(());
+        }
+
+        let mut images = vec![];
+        if self.core.dirty {
+            images.push(LogImage {
+                device: WAL_INDEX_DEVICE,
+                lba: 0,
+                bytes: self.core.superblock().to_bytes(),
+            });
+        }
+        for (lba, bytes) in self.core.index_device.borrow().dirty_frames() {
+            images.push(LogImage {
+                device: WAL_INDEX_DEVICE,
+                lba,
+                bytes,
+            });
+        }
+        for (lba, bytes) in self.core.heap.take_pending() {
+            images.push(LogImage {
+                device: WAL_DATA_DEVICE,
+                lba,
+                bytes,
+            });
+        }
+
+        if images.is_empty() {
+            return Ok(());
+        }
+
+        let wal = self.wal.as_mut().unwrap();
+        wal.append(&images)?;
+        let wal_size = wal.size;
+
+        for image in images {
+            match image.device {
+                WAL_INDEX_DEVICE => {
+                    self.core.index_device.borrow_mut().write(image.lba, &image.bytes)?
+                }
+                _ => {
+                    self.core.data_device.borrow_mut().write(image.lba, &image.bytes)?;
+                }
+            }
+        }
+        self.core.index_device.borrow_mut().flush_all()?;
+        self.core.dirty = false;
+
+        if wal_size > WAL_CHECKPOINT_SIZE {
+            self.checkpoint()?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Once devices are synced nothing in the log is needed anymore
+     */
+    fn checkpoint(&mut self) -> Result<(), std::io::Error> {
+        self.core.index_device.borrow_mut().device.sync()?;
+        self.core.data_device.borrow_mut().sync()?;
+
+        match self.wal.as_mut() {
+            Some(wal) => wal.clear(),
+            None => Ok(()),
+        }
     }
 
-    fn search(&mut self, key: K) -> bool {
-        let mut page_option = Some(&self.index_root);
+    /*
+     * In multimap mode the first of equal keys may lie deeper on the left,
+     * so the search goes on below every match
+     */
+    fn find(&self, key: K) -> Option<BTreeRecord<K>> {
+        let mut page = self.core.load_page(self.core.root_lba, u64::MAX);
+        let mut first = None;
 
-        while let Some(page) = page_option {
-            let records = &page.records;
-            let found_record = records
+        loop {
+            let found_record = page
+                .records
                 .iter()
-                .find(|record| record.key == K::invalid() || record.key >= key);
+                .find(|record| record.key == K::invalid() || record.key >= key)
+                .map(|record| **record);
 
             if let Some(record) = found_record {
                 /*
                  * We found something interesting
                  */
                 if record.key == key {
-                    return true;
+                    if self.core.duplicate_mode != DuplicateMode::Multimap {
+                        return Some(record);
+                    }
+                    first = Some(record);
                 }
 
                 match record.child_lba {
-                    Some(lba) => {
-                        self.working_page = Some(Page::<BTreeRecord<K>>::new(
-                            &self.index_device.clone(),
-                            lba,
-                            page.lba,
-                        ));
-                        page_option = self.working_page.as_ref();
-                    }
-                    None => page_option = None,
+                    Some(lba) => page = self.core.load_page(lba, page.lba),
+                    None => break,
                 }
             } else {
                 /*
@@ -84,27 +362,251 @@ impl<K: BTreeKey, T: Record> BTree<K, T> {
             }
         }
         /*
-         * We did not find anything
+         * Nothing more below, the first match found on the way if any
+         */
+        first
+    }
+
+    pub fn search(&self, key: K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /*
+     * Iterates over records with keys within `range` in key order, use
+     * `rev` to walk the range from its end
+     */
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> BTreeRange<'_, K, T> {
+        BTreeRange::new(self, range.start_bound().cloned(), range.end_bound().cloned())
+    }
+
+    /*
+     * Record of the key, the first inserted one in multimap mode. `None`
+     * means the key is absent, a record that cannot be read is an error.
+     */
+    pub fn get(&self, key: K) -> Result<Option<T>, std::io::Error> {
+        self.core.read_found(self.find(key))
+    }
+
+    /*
+     * All records of the key in the order they were inserted, at most one
+     * outside of multimap mode
+     */
+    pub fn get_all(&self, key: K) -> Result<Vec<T>, std::io::Error> {
+        match self.core.duplicate_mode {
+            DuplicateMode::Multimap => self
+                .range(key..=key)
+                .map(|item| item.map(|(_, record)| record))
+                .collect(),
+            _ => Ok(self.get(key)?.into_iter().collect()),
+        }
+    }
+
+    /*
+     * Overwrites the record in its data block, the index is left untouched.
+     * In multimap mode only the first record of the key changes.
+     */
+    pub fn update(&mut self, key: K, record: T) -> Result<(), std::io::Error> {
+        let found = match self.find(key) {
+            Some(found) => found,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Key {} is not present in the index", key),
+                ))
+            }
+        };
+
+        self.core.heap.update(found.data_lba, &record.get_bytes())?;
+        self.commit()?;
+        self.write_dot_step(&format!("update {}", key.to_string().trim()))
+    }
+
+    /*
+     * Index `index.hex` is reorganized through `index.hex.tmp`, its data
+     * through `index_data.hex.tmp`
+     */
+    fn reorganize_paths(path: &str) -> (String, String, String) {
+        let data_path = BTreeCore::<K, T>::data_path(path);
+        (format!("{}.tmp", path), format!("{}.tmp", data_path), data_path)
+    }
+
+    /*
+     * Completes or rolls back reorganization interrupted by a crash. Renaming
+     * the index copy is the commit point: while the copy exists the original
+     * files are untouched, once it is gone only the data file is left to be
+     * renamed.
+     */
+    fn finish_reorganize(path: &str) -> Result<(), std::io::Error> {
+        let (index_tmp, data_tmp, data_path) = BTree::<K, T>::reorganize_paths(path);
+
+        if Path::new(&index_tmp).exists() {
+            fs::remove_file(&index_tmp)?;
+            if Path::new(&data_tmp).exists() {
+                fs::remove_file(&data_tmp)?;
+            }
+        } else if Path::new(&data_tmp).exists() {
+            fs::rename(&data_tmp, &data_path)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Space behind tombstones relative to space taken by live records
+     */
+    pub fn dead_space_ratio(&mut self) -> Result<f64, std::io::Error> {
+        let usage = self.core.heap.usage()?;
+        let live_space = usage.live_records * T::get_size();
+
+        Ok(match (live_space, usage.dead_space) {
+            (_, 0) => 0.0,
+            (0, _) => f64::INFINITY,
+            (live_space, dead_space) => dead_space as f64 / live_space as f64,
+        })
+    }
+
+    fn reorganize_if_needed(&mut self) -> Result<(), std::io::Error> {
+        if let Some(threshold) = self.reorganize_threshold {
+            if self.dead_space_ratio()? > threshold {
+                return self.reorganize();
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Writes live records into a fresh data file in key order and points the
+     * index at their new places. Both files are rebuilt as copies next to the
+     * originals and swapped by renaming, see `finish_reorganize`.
+     */
+    pub fn reorganize(&mut self) -> Result<(), std::io::Error> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Only trees created by `create` or `open` can be reorganized",
+                ))
+            }
+        };
+        let (index_tmp, data_tmp, data_path) = BTree::<K, T>::reorganize_paths(&path);
+
+        self.flush()?;
+        fs::copy(&path, &index_tmp)?;
+
+        let block_size = self.core.index_device.borrow().block_size;
+        let data_block_size = self.core.data_device.borrow().block_size;
+        let index_copy = Rc::new(RefCell::new(BufferPool::new(
+            BlockDevice::new(index_tmp.clone(), block_size, false)?,
+            0,
+        )));
+        let data_copy = Rc::new(RefCell::new(BlockDevice::new(
+            data_tmp.clone(),
+            data_block_size,
+            true,
+        )?));
+        let mut heap = HeapFile::new(&data_copy, 0);
+        heap.usage()?;
+
+        self.copy_data(&index_copy, &mut heap, self.core.root_lba)?;
+
+        let mut superblock = self.core.superblock();
+        superblock.data_pages_count = heap.pages_count;
+        {
+            let mut index_copy = index_copy.borrow_mut();
+            index_copy.write(0, &superblock.to_bytes())?;
+            index_copy.flush_all()?;
+            index_copy.device.sync()?;
+        }
+        data_copy.borrow_mut().sync()?;
+
+        fs::rename(&index_tmp, &path)?;
+        fs::rename(&data_tmp, &data_path)?;
+
+        /*
+         * Copies were opened before the renames, so they already are the
+         * files of the tree. They take over counters of the old devices.
          */
-        false
+        {
+            let index_device = self.core.index_device.borrow();
+            let mut copy = index_copy.borrow_mut();
+            copy.device.reads += index_device.device.reads;
+            copy.device.writes += index_device.device.writes;
+            copy.hits += index_device.hits;
+            copy.misses += index_device.misses;
+            copy.set_capacity(index_device.capacity)?;
+
+            let data_device = self.core.data_device.borrow();
+            let mut copy = data_copy.borrow_mut();
+            copy.reads += data_device.reads;
+            copy.writes += data_device.writes;
+        }
+
+        self.core.index_device = index_copy;
+        self.core.data_device = data_copy;
+        self.core.heap = heap;
+        if self.wal.is_some() {
+            self.core.index_device.borrow_mut().hold_dirty = true;
+            self.core.heap.defer_writes();
+        }
+
+        Ok(())
     }
 
-    fn get_next_index_lba(&mut self) -> u64 {
-        let ret = self.pages_count;
-        self.pages_count += 1;
-        ret
+    /*
+     * Walks the subtree in key order, so records land in the new heap file
+     * sorted by their keys
+     */
+    fn copy_data(
+        &self,
+        index_copy: &Rc<RefCell<BufferPool>>,
+        heap: &mut HeapFile,
+        lba: u64,
+    ) -> Result<(), std::io::Error> {
+        let mut page = Page::<BTreeRecord<K>>::new(index_copy, lba, u64::MAX);
+
+        for index in 0..page.records.len() {
+            let record = *page.records[index];
+            if let Some(child_lba) = record.child_lba {
+                self.copy_data(index_copy, heap, child_lba)?;
+            }
+
+            if record.key != K::invalid() {
+                let bytes = self.core.heap.read(record.data_lba)?;
+                page.records[index].data_lba = heap.insert(&bytes)?;
+                page.dirty = true;
+            }
+        }
+
+        Ok(())
     }
 
+    /*
+     * Moves the upper half of a full `child` into `new_child` and lifts the
+     * centre record into `parent`. `child` keeps the lower half, so the record
+     * in `parent` that pointed at `child` is repointed to `new_child`.
+     */
     fn split_child(
-        mut parent: &mut Page<BTreeRecord<K>>,
-        mut child: &mut Page<BTreeRecord<K>>,
-        mut new_child: &mut Page<BTreeRecord<K>>,
+        parent: &mut Page<BTreeRecord<K>>,
+        child: &mut Page<BTreeRecord<K>>,
+        new_child: &mut Page<BTreeRecord<K>>,
     ) {
-        let centre_index = child.records.len() / 2;
+        let centre_index = BTreeCore::<K, T>::centre_index(child);
         let mut centre_record = child.records.remove(centre_index);
 
-        for _ in centre_index..child.records.len() {
-            new_child.records.push(child.records.remove(centre_index));
+        new_child.records.extend(child.records.drain(centre_index..));
+
+        /*
+         * Left half of non-leaf page needs its own trailing record pointing
+         * at the child that was on the left of the centre record
+         */
+        if centre_record.child_lba.is_some() {
+            child.records.push(Box::new(BTreeRecord::<K> {
+                child_lba: centre_record.child_lba,
+                key: K::invalid(),
+                data_lba: 0,
+            }));
         }
 
         let child_record_index_option = parent
@@ -116,173 +618,556 @@ impl<K: BTreeKey, T: Record> BTree<K, T> {
             None => panic!("Tried to split `Page` that is not a child of `parent`"),
         };
 
-        let new_page_last_record = BTreeRecord::<K> {
-            child_lba: centre_record.child_lba,
-            key: K::invalid(),
-            data_lba: 0,
-        };
-        new_child.records.push(Box::new(new_page_last_record));
         centre_record.child_lba = Some(child.lba);
         parent.records[child_record_index].child_lba = Some(new_child.lba);
         parent.records.insert(child_record_index, centre_record);
+
+        parent.dirty = true;
+        child.dirty = true;
+        new_child.dirty = true;
     }
 
-    pub fn insert(&mut self, key: K) -> bool {
-        if self.index_root.records.iter().filter(|x| x.key != K::invalid()).count() == (2 * self.degree - 1) as usize{
-            let lba = self.get_next_index_lba();
-            let mut working_page =
-                Page::<BTreeRecord<K>>::empty(&self.index_device.clone(), lba, 0);
+    fn split_root(
+        &mut self,
+        page: Page<BTreeRecord<K>>,
+    ) -> Result<Page<BTreeRecord<K>>, std::io::Error> {
+        let root = self.core.split_root(page, BTree::<K, T>::split_child)?;
+        self.splits += 1;
+        Ok(root)
+    }
 
-            /*
-             * This record will land into root after swap
-             */
-            working_page.records.push(Box::new(BTreeRecord::<K> {
-                child_lba: Some(lba),
-                key: K::invalid(),
-                data_lba: 0,
-            }));
+    /*
+     * With the redo log enabled each insert, delete and update becomes a
+     * single group of the log
+     */
+    pub fn insert(&mut self, key: K, record: T) -> Result<(), std::io::Error> {
+        let inserted = self.insert_record(key, record);
+        self.commit()?;
+        self.write_dot_step(&format!("insert {}", key.to_string().trim()))?;
+        inserted
+    }
 
-            std::mem::swap(&mut working_page.records, &mut self.index_root.records);
+    /*
+     * Key already in the index lies on the path down to the leaf, every page
+     * of the path is checked before it gets split or compensated. Equal keys
+     * of a multimap go after the ones already present.
+     */
+    fn insert_record(&mut self, key: K, record: T) -> Result<(), std::io::Error> {
+        let mut page = self.core.load_page(self.core.root_lba, u64::MAX);
+        if let Some(found) = self.find_in_page(&page, key) {
+            return self.insert_existing(found, key, record);
+        }
 
-            let mut new_page = Page::<BTreeRecord<K>>::empty(
-                &self.index_device.clone(),
-                self.get_next_index_lba(),
-                0,
-            );
-            BTree::<K, T>::split_child(&mut self.index_root, &mut working_page, &mut new_page);
-            self.working_page = Some(working_page);
+        if self.core.is_full(&page) {
+            page = self.split_root(page)?;
         }
 
-        let mut page = &mut self.index_root;
         loop {
-            if page.records.is_empty() {
-                page.records.push(Box::new(BTreeRecord::<K> {
-                    child_lba: None,
-                    key: key,
-                    data_lba: 0,
-                }));
-                page.dirty = true;
-                break;
-            } else if page.records[0].child_lba == None {
+            if BTreeCore::<K, T>::is_leaf(&page) {
                 let insert_index = page
                     .records
                     .iter()
-                    .position(|x| x.key == K::invalid() || x.key >= key)
+                    .position(|x| x.key == K::invalid() || x.key > key)
                     .unwrap_or(page.records.len());
 
+                let data_lba = self.core.append_data(&record)?;
                 page.records.insert(insert_index, Box::new(BTreeRecord::<K> {
                     child_lba: None,
-                    key: key,
-                    data_lba: 0,
+                    key,
+                    data_lba,
                 }));
                 page.dirty = true;
                 break;
-            } else {
-                /*
-                 * Non-leaf page
-                 */
-                let next_search_index = page
-                    .records
-                    .iter()
-                    .position(|x| x.key == K::invalid() || x.key > key)
-                    .expect("Could not find proper position for further search");
+            }
 
-                let next_lba = page.records[next_search_index].child_lba.expect("Tried to enter leafs child!");
+            /*
+             * Non-leaf page, full children are split or compensated on the way
+             * down so that the leaf always has room for the new record
+             */
+            let next_search_index = page
+                .records
+                .iter()
+                .position(|x| x.key == K::invalid() || x.key > key)
+                .expect("Could not find proper position for further search");
 
-                self.working_page = Some(Page::<BTreeRecord<K>>::new(
-                    &self.index_device.clone(),
-                    next_lba,
-                    0,
-                ));
-                page = self.working_page.as_mut().unwrap();
+            let next_lba = page.records[next_search_index]
+                .child_lba
+                .expect("Tried to enter leafs child!");
+            let mut child = self.core.load_page(next_lba, page.lba);
+            if let Some(found) = self.find_in_page(&child, key) {
+                return self.insert_existing(found, key, record);
             }
-        }
-
-        true
-    }
 
-    pub fn print(&mut self) {
-        let mut tree = Vec::<Vec::<Page::<BTreeRecord<K>>>>::new();
-        let root = Page::<BTreeRecord<K>>::new(&self.index_device, 0, u64::max_value());
+            if self.core.is_full(&child) && self.insert_policy == InsertPolicy::Compensation
+            {
+                if let Some(sibling) = self.compensate(&mut page, next_search_index, &mut child) {
+                    self.compensations += 1;
 
-        tree.push(vec![root]);
-        let mut level = &tree[0];
-        loop {
-            let mut next_level = vec![];
-            for page in level {
-                for record in &page.records {
-                    if let Some(child) = record.child_lba {
-                        let new_page = Page::<BTreeRecord<K>>::new(&self.index_device, child, page.lba);
-                        next_level.push(new_page);
+                    let target_lba = page
+                        .records
+                        .iter()
+                        .find(|x| x.key == K::invalid() || x.key > key)
+                        .and_then(|x| x.child_lba);
+                    if target_lba == Some(sibling.lba) {
+                        child = sibling;
                     }
                 }
             }
 
-            if next_level.is_empty() {
-                break;
-            } else {
-                tree.push(next_level);
-                level = &tree.last().unwrap();
+            if self.core.is_full(&child) {
+                let new_lba = self.core.get_next_index_lba()?;
+                let mut new_child =
+                    Page::<BTreeRecord<K>>::empty(&self.core.index_device, new_lba, page.lba);
+                BTree::<K, T>::split_child(&mut page, &mut child, &mut new_child);
+                self.splits += 1;
+
+                if key >= page.records[next_search_index].key {
+                    child = new_child;
+                }
             }
+
+            page = child;
         }
 
-        for level in tree {
-            for page in level {
-                let mut count = 0;
-                print!("[");
-                for record in &page.records {
-                    print!("{}", record);
-                    count += 1;
+        Ok(())
+    }
+
+    /*
+     * Record of the key in the page unless equal keys are allowed
+     */
+    fn find_in_page(&self, page: &Page<BTreeRecord<K>>, key: K) -> Option<BTreeRecord<K>> {
+        if self.core.duplicate_mode == DuplicateMode::Multimap {
+            return None;
+        }
+
+        page.records
+            .iter()
+            .find(|record| record.key == key)
+            .map(|record| **record)
+    }
+
+    fn insert_existing(
+        &mut self,
+        found: BTreeRecord<K>,
+        key: K,
+        record: T,
+    ) -> Result<(), std::io::Error> {
+        match self.core.duplicate_mode {
+            DuplicateMode::Replace => self.core.heap.update(found.data_lba, &record.get_bytes()),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Key {} is already present in the index", key.to_string().trim()),
+            )),
+        }
+    }
+
+    /*
+     * Evens out the bytes of keys between full child under `index` and one of
+     * its siblings by moving records through `parent`. Sibling qualifies when
+     * both pages end up with room for one more key. Returns the sibling that
+     * took part in compensation.
+     */
+    fn compensate(
+        &mut self,
+        parent: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        child: &mut Page<BTreeRecord<K>>,
+    ) -> Option<Page<BTreeRecord<K>>> {
+        let record_size = BTreeRecord::<K>::get_size();
+        let growth = BTreeCore::<K, T>::key_growth();
+        let max_weight = self.core.page_capacity() - 2 * record_size - growth;
+
+        if index > 0 {
+            let left_lba = parent.records[index - 1]
+                .child_lba
+                .expect("Non-leaf record without child!");
+            let mut left = self.core.load_page(left_lba, parent.lba);
+
+            if BTreeCore::<K, T>::weight(&left) <= max_weight {
+                while BTreeCore::<K, T>::weight(child)
+                    > BTreeCore::<K, T>::weight(&left) + record_size
+                {
+                    BTreeCore::<K, T>::rotate_left(parent, index - 1, &mut left, child);
                 }
-                while count<self.degree*2 {
-                    print!("{}", BTreeRecord::<K>::invalid());
-                    count += 1;
+                return Some(left);
+            }
+        }
+
+        if index + 1 < parent.records.len() {
+            let right_lba = parent.records[index + 1]
+                .child_lba
+                .expect("Non-leaf record without child!");
+            let mut right = self.core.load_page(right_lba, parent.lba);
+
+            if BTreeCore::<K, T>::weight(&right) <= max_weight {
+                while BTreeCore::<K, T>::weight(child)
+                    > BTreeCore::<K, T>::weight(&right) + record_size
+                {
+                    BTreeCore::<K, T>::rotate_right(parent, index, child, &mut right);
                 }
-                print!("]");
+                return Some(right);
             }
-            print!("\n\n");
         }
+
+        None
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::{btree_key::IntKey, record::IntRecord};
+    /*
+     * Deletion works top-down: before descending into a child we make sure it
+     * has spare keys, either by borrowing records from a sibling through the
+     * parent or by merging it with a sibling. Thanks to that no page ever
+     * drops below `page_minimum` after removal. In multimap mode all records
+     * of the key are removed. Returns whether the key was present.
+     */
+    pub fn delete(&mut self, key: K) -> Result<bool, std::io::Error> {
+        let deleted = self.delete_record(key).and_then(|deleted| {
+            if deleted && self.core.duplicate_mode == DuplicateMode::Multimap {
+                while self.delete_record(key)? {}
+            }
+            Ok(deleted)
+        });
+        self.commit()?;
+        self.write_dot_step(&format!("delete {}", key.to_string().trim()))?;
 
-    use super::*;
+        if deleted? {
+            self.reorganize_if_needed()?;
+            return Ok(true);
+        }
 
-    #[test]
-    fn test_search() -> Result<(), std::io::Error> {
-        let block_size = 21 * 4; // t = 2
+        Ok(false)
+    }
 
-        {
-            let device = BlockDevice::new("test_search.hex".to_string(), block_size, true).unwrap();
-            let device = Rc::new(RefCell::new(device));
-            let mut root_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 0, 0);
-            let mut child1_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 1, 0);
-            let mut child2_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 2, 0);
-            //let child3_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 0, 0);
-            //let child4_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 0, 0);
+    fn delete_record(&mut self, key: K) -> Result<bool, std::io::Error> {
+        let mut root = self.core.load_page(self.core.root_lba, u64::MAX);
+        if BTreeCore::<K, T>::key_growth() > 0 && self.core.lacks_headroom(&root) {
+            root = self.split_root(root)?;
+        }
+        let deleted = self.delete_from(&mut root, key)?;
 
-            let record1 = BTreeRecord::<IntKey> {
-                child_lba: Some(1),
-                key: IntKey { value: 10 },
-                data_lba: 0,
-            };
-            let record2 = BTreeRecord::<IntKey> {
-                child_lba: Some(2),
-                key: IntKey::invalid(),
-                data_lba: 0,
-            };
-            root_page.records.push(Box::new(record1));
-            root_page.records.push(Box::new(record2));
+        /*
+         * Root lost its last key due to merge of its only two children
+         */
+        if !BTreeCore::<K, T>::is_leaf(&root) && BTreeCore::<K, T>::keys_count(&root) == 0 {
+            self.core.root_lba = root.records[0]
+                .child_lba
+                .expect("Non-leaf root without child!");
+            self.core.dirty = true;
 
-            let record1 = BTreeRecord::<IntKey> {
-                child_lba: None,
-                key: IntKey { value: 4 },
-                data_lba: 0,
-            };
-            let record2 = BTreeRecord::<IntKey> {
+            self.core.free_page(root)?;
+        }
+
+        match deleted {
+            Some(record) => self.core.heap.delete(record.data_lba).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /*
+     * Returns the record that was removed from the index
+     */
+    fn delete_from(
+        &mut self,
+        page: &mut Page<BTreeRecord<K>>,
+        key: K,
+    ) -> Result<Option<BTreeRecord<K>>, std::io::Error> {
+        let index_option = page
+            .records
+            .iter()
+            .position(|x| x.key == K::invalid() || x.key >= key);
+
+        if BTreeCore::<K, T>::is_leaf(page) {
+            return match index_option {
+                Some(index) if page.records[index].key == key => {
+                    let removed = page.records.remove(index);
+                    page.dirty = true;
+                    Ok(Some(*removed))
+                }
+                _ => Ok(None),
+            };
+        }
+
+        let index = index_option.expect("Non-leaf page without trailing record");
+        if page.records[index].key == key {
+            return self.delete_from_non_leaf(page, index, key);
+        }
+        if self.split_for_delete(page, index)? {
+            return self.delete_from(page, key);
+        }
+
+        let mut child = self.fill_child(page, index)?;
+        self.delete_from(&mut child, key)
+    }
+
+    fn delete_from_non_leaf(
+        &mut self,
+        page: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        key: K,
+    ) -> Result<Option<BTreeRecord<K>>, std::io::Error> {
+        if self.split_for_delete(page, index)? {
+            return self.delete_from(page, key);
+        }
+
+        let removed = *page.records[index];
+        let left_lba = page.records[index]
+            .child_lba
+            .expect("Non-leaf record without child!");
+        let mut left = self.core.load_page(left_lba, page.lba);
+
+        if self.core.has_spare(&left) {
+            let predecessor = self.max_record(&left);
+            page.records[index].key = predecessor.key;
+            page.records[index].data_lba = predecessor.data_lba;
+            page.dirty = true;
+            self.delete_edge(&mut left, true)?;
+            return Ok(Some(removed));
+        }
+
+        let right_lba = page.records[index + 1]
+            .child_lba
+            .expect("Non-leaf record without child!");
+        let mut right = self.core.load_page(right_lba, page.lba);
+
+        if self.core.lacks_headroom(&right) {
+            drop((left, right));
+            self.split_for_delete(page, index + 1)?;
+            return self.delete_from(page, key);
+        }
+
+        if self.core.has_spare(&right) {
+            let successor = self.min_record(&right);
+            page.records[index].key = successor.key;
+            page.records[index].data_lba = successor.data_lba;
+            page.dirty = true;
+            self.delete_edge(&mut right, false)?;
+            return Ok(Some(removed));
+        }
+
+        BTreeCore::<K, T>::merge_children(page, index, &mut left, &mut right);
+        self.core.free_page(right)?;
+        self.delete_from(&mut left, key)
+    }
+
+    /*
+     * Removes the last record of the subtree, or its first one when `last` is
+     * false. Predecessors and successors are removed this way, by key they
+     * could be mistaken for an equal key of a multimap.
+     */
+    fn delete_edge(
+        &mut self,
+        page: &mut Page<BTreeRecord<K>>,
+        last: bool,
+    ) -> Result<BTreeRecord<K>, std::io::Error> {
+        let index = match last {
+            true => page.records.len() - 1,
+            false => 0,
+        };
+
+        if BTreeCore::<K, T>::is_leaf(page) {
+            page.dirty = true;
+            return Ok(*page.records.remove(index));
+        }
+        if self.split_for_delete(page, index)? {
+            return self.delete_edge(page, last);
+        }
+
+        let mut child = self.fill_child(page, index)?;
+        self.delete_edge(&mut child, last)
+    }
+
+    fn max_record(&self, page: &Page<BTreeRecord<K>>) -> BTreeRecord<K> {
+        let mut record = **page.records.last().expect("Empty page in non-empty tree!");
+
+        while let Some(lba) = record.child_lba {
+            let child = self.core.load_page(lba, u64::MAX);
+            record = **child.records.last().expect("Empty page in non-empty tree!");
+        }
+
+        record
+    }
+
+    fn min_record(&self, page: &Page<BTreeRecord<K>>) -> BTreeRecord<K> {
+        let mut record = **page.records.first().expect("Empty page in non-empty tree!");
+
+        while let Some(lba) = record.child_lba {
+            let child = self.core.load_page(lba, u64::MAX);
+            record = **child.records.first().expect("Empty page in non-empty tree!");
+        }
+
+        record
+    }
+
+    fn split_for_delete(
+        &mut self,
+        page: &mut Page<BTreeRecord<K>>,
+        index: usize,
+    ) -> Result<bool, std::io::Error> {
+        let split = self.core.split_for_delete(page, index, BTree::<K, T>::split_child)?;
+        if split {
+            self.splits += 1;
+        }
+        Ok(split)
+    }
+
+    /*
+     * Returns child under `index` with spare keys, so that one of them can be
+     * removed without breaking B-tree properties. A single borrowed record
+     * is enough for keys of fixed size, variable ones may need more.
+     */
+    fn fill_child(
+        &mut self,
+        page: &mut Page<BTreeRecord<K>>,
+        index: usize,
+    ) -> Result<Page<BTreeRecord<K>>, std::io::Error> {
+        let child_lba = page.records[index]
+            .child_lba
+            .expect("Non-leaf record without child!");
+        let mut child = self.core.load_page(child_lba, page.lba);
+
+        loop {
+            if self.core.has_spare(&child) {
+                return Ok(child);
+            }
+
+            let mut left = None;
+            if index > 0 {
+                let left_lba = page.records[index - 1]
+                    .child_lba
+                    .expect("Non-leaf record without child!");
+                let mut left_page = self.core.load_page(left_lba, page.lba);
+
+                if self.core.has_spare(&left_page) {
+                    BTreeCore::<K, T>::rotate_right(page, index - 1, &mut left_page, &mut child);
+                    continue;
+                }
+                left = Some(left_page);
+            }
+
+            if index + 1 < page.records.len() {
+                let right_lba = page.records[index + 1]
+                    .child_lba
+                    .expect("Non-leaf record without child!");
+                let mut right = self.core.load_page(right_lba, page.lba);
+
+                if self.core.has_spare(&right) {
+                    BTreeCore::<K, T>::rotate_left(page, index, &mut child, &mut right);
+                    continue;
+                }
+                BTreeCore::<K, T>::merge_children(page, index, &mut child, &mut right);
+                self.core.free_page(right)?;
+                return Ok(child);
+            }
+
+            let mut left = left.expect("Page with a single child is not a root!");
+            BTreeCore::<K, T>::merge_children(page, index - 1, &mut left, &mut child);
+            self.core.free_page(child)?;
+            return Ok(left);
+        }
+    }
+
+    pub fn print(&mut self) {
+        let mut tree = Vec::<Vec::<Page::<BTreeRecord<K>>>>::new();
+        let root = self.core.load_page(self.core.root_lba, u64::MAX);
+
+        tree.push(vec![root]);
+        let mut level = &tree[0];
+        loop {
+            let mut next_level = vec![];
+            for page in level {
+                for record in &page.records {
+                    if let Some(child) = record.child_lba {
+                        let new_page =
+                            Page::<BTreeRecord<K>>::new(&self.core.index_device, child, page.lba);
+                        next_level.push(new_page);
+                    }
+                }
+            }
+
+            if next_level.is_empty() {
+                break;
+            } else {
+                tree.push(next_level);
+                level = tree.last().unwrap();
+            }
+        }
+
+        for level in tree {
+            for page in level {
+                let mut count = 0;
+                print!("[");
+                for record in &page.records {
+                    print!("{}", record);
+                    count += 1;
+                }
+                while count<self.core.degree*2 {
+                    print!("{}", BTreeRecord::<K>::invalid());
+                    count += 1;
+                }
+                print!("]");
+            }
+            print!("\n\n");
+        }
+    }
+}
+
+impl<K: BTreeKey, T: Record> Drop for BTree<K, T> {
+    fn drop(&mut self) {
+        self.flush().expect("Could not write superblock on flush!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        btree_key::{IntKey, StringKey, STRING_KEY_MAX_LEN},
+        record::IntRecord,
+    };
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    #[test]
+    fn test_search() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+
+        let device = BlockDevice::new("test_search.hex".to_string(), block_size, true).unwrap();
+        let data_device =
+            BlockDevice::new("test_search_data.hex".to_string(), block_size, true).unwrap();
+        let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
+        btree.core.pages_count = 4;
+
+        {
+            let device = btree.core.index_device.clone();
+            let mut root_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 1, 0);
+            let mut child1_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 2, 0);
+            let mut child2_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 3, 0);
+            //let child3_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 0, 0);
+            //let child4_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 0, 0);
+
+            let record1 = BTreeRecord::<IntKey> {
+                child_lba: Some(2),
+                key: IntKey { value: 10 },
+                data_lba: 0,
+            };
+            let record2 = BTreeRecord::<IntKey> {
+                child_lba: Some(3),
+                key: IntKey::invalid(),
+                data_lba: 0,
+            };
+            root_page.records.push(Box::new(record1));
+            root_page.records.push(Box::new(record2));
+
+            let record1 = BTreeRecord::<IntKey> {
+                child_lba: None,
+                key: IntKey { value: 4 },
+                data_lba: 0,
+            };
+            let record2 = BTreeRecord::<IntKey> {
                 child_lba: None,
                 key: IntKey { value: 7 },
                 data_lba: 0,
@@ -310,25 +1195,20 @@ mod tests {
             child2_page.records.push(Box::new(record3));
         }
 
-        let device = BlockDevice::new("test_search.hex".to_string(), block_size, false).unwrap();
-        let mut data_device =
-            BlockDevice::new("test_search_data.hex".to_string(), block_size, false).unwrap();
-        let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
-
-        assert_eq!(btree.search(IntKey { value: 20 }), true);
-        assert_eq!(btree.search(IntKey { value: 10 }), true);
-        assert_eq!(btree.search(IntKey { value: 7 }), true);
-        assert_eq!(btree.search(IntKey { value: 8 }), false);
+        assert!(btree.search(IntKey { value: 20 }));
+        assert!(btree.search(IntKey { value: 10 }));
+        assert!(btree.search(IntKey { value: 7 }));
+        assert!(!btree.search(IntKey { value: 8 }));
 
         Ok(())
     }
 
     #[test]
     fn test_split() -> Result<(), std::io::Error> {
-        let block_size = 21 * 4; // t = 2
+        let block_size = 88; // t = 2
 
         let device = BlockDevice::new("test_split.hex".to_string(), block_size, true).unwrap();
-        let device = Rc::new(RefCell::new(device));
+        let device = Rc::new(RefCell::new(BufferPool::new(device, 0)));
         let mut root_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 0, 0);
         let mut child_page = Page::<BTreeRecord<IntKey>>::new(&device.clone(), 1, 0);
 
@@ -366,7 +1246,7 @@ mod tests {
 
     #[test]
     fn test_insert() -> Result<(), std::io::Error> {
-        let block_size = 21 * 4; // t = 2
+        let block_size = 88; // t = 2
 
         let device = BlockDevice::new("test_insert.hex".to_string(), block_size, true).unwrap();
         let data_device = BlockDevice::new("test_insert_data.hex".to_string(), block_size, true).unwrap();
@@ -374,26 +1254,857 @@ mod tests {
         {
             let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
 
-            btree.insert(IntKey{value: 10});
-            assert_eq!(btree.search(IntKey{value: 10}), true);
-            assert_eq!(btree.search(IntKey{value: 11}), false);
+            btree.insert(IntKey{value: 10}, record(10))?;
+            assert!(btree.search(IntKey{value: 10}));
+            assert!(!btree.search(IntKey{value: 11}));
 
-            btree.insert(IntKey{value: 11});
-            btree.insert(IntKey{value: 12});
-            btree.insert(IntKey{value: 13});
-            // assert_eq!(btree.search(IntKey{value: 10}), true);
-            // assert_eq!(btree.search(IntKey{value: 11}), true);
-            // assert_eq!(btree.search(IntKey{value: 12}), true);
-            // assert_eq!(btree.search(IntKey{value: 13}), true);
-            // assert_eq!(btree.search(IntKey{value: 14}), false);
-            btree.insert(IntKey{value: 14});
+            btree.insert(IntKey{value: 11}, record(11))?;
+            btree.insert(IntKey{value: 12}, record(12))?;
+            btree.insert(IntKey{value: 13}, record(13))?;
+            assert!(btree.search(IntKey{value: 10}));
+            assert!(btree.search(IntKey{value: 11}));
+            assert!(btree.search(IntKey{value: 12}));
+            assert!(btree.search(IntKey{value: 13}));
+            assert!(!btree.search(IntKey{value: 14}));
+            btree.insert(IntKey{value: 14}, record(14))?;
         }
 
-        let device = BlockDevice::new("test_insert.hex".to_string(), block_size, false).unwrap();
-        let data_device = BlockDevice::new("test_insert_data.hex".to_string(), block_size, true).unwrap();
-        let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
+        let mut btree = BTree::<IntKey, IntRecord>::open("test_insert.hex", 0)?;
         btree.print();
 
         Ok(())
     }
+
+    /*
+     * Walks the whole tree and checks B-tree properties, returns keys in order
+     */
+    fn collect_keys(btree: &BTree<IntKey, IntRecord>) -> Vec<i32> {
+        fn walk(
+            btree: &BTree<IntKey, IntRecord>,
+            lba: u64,
+            depth: u64,
+            leaf_depth: &mut Option<u64>,
+            keys: &mut Vec<i32>,
+        ) {
+            let page = btree.core.load_page(lba, u64::MAX);
+            let keys_count = BTreeCore::<IntKey, IntRecord>::keys_count(&page);
+
+            if lba != btree.core.root_lba {
+                assert!(keys_count >= btree.core.degree - 1, "Page {} underflowed", lba);
+            }
+            assert!(keys_count < 2 * btree.core.degree, "Page {} overflowed", lba);
+
+            if BTreeCore::<IntKey, IntRecord>::is_leaf(&page) {
+                assert_eq!(*leaf_depth.get_or_insert(depth), depth, "Leafs on different levels");
+                keys.extend(page.records.iter().map(|record| record.key.value));
+                return;
+            }
+
+            assert_eq!(page.records.last().unwrap().key, IntKey::invalid());
+            for record in &page.records {
+                walk(btree, record.child_lba.unwrap(), depth + 1, leaf_depth, keys);
+                if record.key != IntKey::invalid() {
+                    keys.push(record.key.value);
+                }
+            }
+        }
+
+        let mut keys = vec![];
+        walk(btree, btree.core.root_lba, 0, &mut None, &mut keys);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "Keys out of order");
+        keys
+    }
+
+    #[test]
+    fn test_delete_from_leaf() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+
+        let device = BlockDevice::new("test_delete_from_leaf.hex".to_string(), block_size, true).unwrap();
+        let data_device = BlockDevice::new("test_delete_from_leaf_data.hex".to_string(), block_size, true).unwrap();
+        let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
+
+        btree.insert(IntKey{value: 10}, record(10))?;
+        btree.insert(IntKey{value: 11}, record(11))?;
+
+        assert!(btree.delete(IntKey{value: 10})?);
+        assert!(!btree.delete(IntKey{value: 10})?);
+        assert!(!btree.search(IntKey{value: 10}));
+        assert!(btree.search(IntKey{value: 11}));
+        assert_eq!(collect_keys(&btree), vec![11]);
+
+        assert!(btree.delete(IntKey{value: 11})?);
+        assert_eq!(collect_keys(&btree), Vec::<i32>::new());
+        assert!(!btree.delete(IntKey{value: 11})?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_merge_and_shrink() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+
+        let device = BlockDevice::new("test_delete_merge_and_shrink.hex".to_string(), block_size, true).unwrap();
+        let data_device = BlockDevice::new("test_delete_merge_and_shrink_data.hex".to_string(), block_size, true).unwrap();
+        let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
+
+        for value in 1..=30 {
+            btree.insert(IntKey{value}, record(value))?;
+        }
+        assert_eq!(collect_keys(&btree), (1..=30).collect::<Vec<i32>>());
+
+        /*
+         * Removing from the middle hits non-leaf pages, borrowing and merging
+         */
+        let mut expected = (1..=30).collect::<Vec<i32>>();
+        for value in [16, 8, 24, 4, 12, 20, 28, 1, 30, 15, 17] {
+            assert!(btree.delete(IntKey{value})?);
+            expected.retain(|&x| x != value);
+            assert_eq!(collect_keys(&btree), expected);
+        }
+
+        for value in expected.clone() {
+            assert!(btree.delete(IntKey{value})?);
+            expected.retain(|&x| x != value);
+            assert_eq!(collect_keys(&btree), expected);
+        }
+
+        let root = btree.core.load_page(btree.core.root_lba, u64::MAX);
+        assert!(root.records.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_reopen() -> Result<(), std::io::Error> {
+        use rand::{seq::SliceRandom, SeedableRng};
+
+        let block_size = 21 * 6; // t = 3
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut values = (0..300).collect::<Vec<i32>>();
+        values.shuffle(&mut rng);
+        let (deleted, kept) = values.split_at(150);
+
+        let device = BlockDevice::new("test_delete_reopen.hex".to_string(), block_size, true).unwrap();
+        let data_device = BlockDevice::new("test_delete_reopen_data.hex".to_string(), block_size, true).unwrap();
+        {
+            let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
+
+            for &value in &values {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+            for &value in deleted {
+                assert!(btree.delete(IntKey{value})?);
+            }
+        }
+
+        let btree = BTree::<IntKey, IntRecord>::open("test_delete_reopen.hex", 0)?;
+
+        let mut expected = kept.to_vec();
+        expected.sort();
+        assert_eq!(collect_keys(&btree), expected);
+
+        for &value in deleted {
+            assert!(!btree.search(IntKey{value}));
+        }
+        for &value in kept {
+            assert!(btree.search(IntKey{value}));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+        let data_block_size = 256;
+
+        let device = BlockDevice::new("test_get.hex".to_string(), block_size, true).unwrap();
+        let data_device = BlockDevice::new("test_get_data.hex".to_string(), data_block_size, true).unwrap();
+        {
+            let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
+
+            for value in 0..40 {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+            assert_eq!(btree.core.data_device.borrow().writes, 40);
+
+            /*
+             * Three records share every block of the data device
+             */
+            assert_eq!(btree.core.heap.pages_count, 14);
+
+            /*
+             * Records lifted from leafs during deletion must keep their data
+             */
+            for value in (0..40).step_by(3) {
+                assert!(btree.delete(IntKey{value})?);
+            }
+            assert!(btree.get(IntKey{value: 3})?.is_none());
+            assert!(btree.get(IntKey{value: 40})?.is_none());
+
+            /*
+             * Deleted records leave tombstones in their blocks
+             */
+            let page = btree.core.heap.read_page(0)?;
+            assert_eq!(page.live_count(), 2);
+            assert_eq!(page.dead_space(), IntRecord::get_size());
+        }
+
+        let btree = BTree::<IntKey, IntRecord>::open("test_get.hex", 0)?;
+
+        for value in (0..40).filter(|value| value % 3 != 0) {
+            let found = btree.get(IntKey{value})?.expect("Record lost");
+            assert_eq!(found.get_bytes(), record(value).get_bytes());
+        }
+        assert_eq!(btree.core.data_device.borrow().reads, 26);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_resumes() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+
+        {
+            let mut btree = BTree::<IntKey, IntRecord>::create("test_open_resumes.hex", block_size, 0)?;
+            for value in 0..50 {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+        }
+
+        /*
+         * Pages and data blocks allocated now must not overwrite old ones
+         */
+        let (root_lba, pages_count) = {
+            let mut btree = BTree::<IntKey, IntRecord>::open("test_open_resumes.hex", 0)?;
+            assert_eq!(btree.core.heap.pages_count, 50);
+            for value in 50..100 {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+            (btree.core.root_lba, btree.core.pages_count)
+        };
+
+        let btree = BTree::<IntKey, IntRecord>::open("test_open_resumes.hex", 0)?;
+        assert_eq!(btree.core.root_lba, root_lba);
+        assert_eq!(btree.core.pages_count, pages_count);
+        assert_eq!(btree.core.heap.pages_count, 100);
+        assert_eq!(collect_keys(&btree), (0..100).collect::<Vec<i32>>());
+        for value in 0..100 {
+            assert_eq!(btree.get(IntKey{value})?.unwrap().get_bytes(), record(value).get_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_invalid() -> Result<(), std::io::Error> {
+        let error = BTree::<IntKey, IntRecord>::open("test_open_invalid_missing.hex", 0).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        {
+            let mut device = BlockDevice::new("test_open_invalid.hex".to_string(), 88, true)?;
+            device.write(0, &[0xAAu8; 88])?;
+        }
+        let error = BTree::<IntKey, IntRecord>::open("test_open_invalid.hex", 0).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let error = BTree::<IntKey, IntRecord>::create("test_open_invalid_large.hex", 1 << 17, 0)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        assert_eq!(BTreeCore::<IntKey, IntRecord>::data_path("dir/index.hex"), "dir/index_data.hex");
+        assert_eq!(BTreeCore::<IntKey, IntRecord>::data_path("index"), "index_data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+
+        let device = BlockDevice::new("test_update.hex".to_string(), block_size, true).unwrap();
+        let data_device = BlockDevice::new("test_update_data.hex".to_string(), block_size, true).unwrap();
+        let mut btree = BTree::<IntKey, IntRecord>::new(device, data_device);
+
+        for value in 0..20 {
+            btree.insert(IntKey{value}, record(value))?;
+        }
+
+        let index_reads = btree.core.index_device.borrow().device.reads;
+        let index_writes = btree.core.index_device.borrow().device.writes;
+        let data_reads = btree.core.data_device.borrow().reads;
+        let data_writes = btree.core.data_device.borrow().writes;
+
+        btree.update(IntKey{value: 13}, record(100))?;
+
+        /*
+         * Single descent to the leaf, single read-modify-write of data block
+         */
+        assert!(btree.core.index_device.borrow().device.reads - index_reads <= 3);
+        assert_eq!(btree.core.index_device.borrow().device.writes, index_writes);
+        assert_eq!(btree.core.data_device.borrow().reads, data_reads + 1);
+        assert_eq!(btree.core.data_device.borrow().writes, data_writes + 1);
+
+        assert_eq!(btree.get(IntKey{value: 13})?.unwrap().get_bytes(), record(100).get_bytes());
+        assert_eq!(btree.get(IntKey{value: 12})?.unwrap().get_bytes(), record(12).get_bytes());
+
+        let error = btree.update(IntKey{value: 20}, record(100)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_free_pages_reused() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+
+        let pages_count = {
+            let mut btree = BTree::<IntKey, IntRecord>::create("test_free_pages_reused.hex", block_size, 0)?;
+            for value in 0..200 {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+            assert_eq!(btree.core.page_stats().free_pages, 0);
+
+            for value in 0..150 {
+                assert!(btree.delete(IntKey{value})?);
+            }
+
+            /*
+             * Live pages are exactly those reachable from the root
+             */
+            let stats = btree.core.page_stats();
+            let mut range = btree.range(..);
+            assert_eq!(range.by_ref().count(), 50);
+            assert_eq!(range.index_reads, stats.live_pages);
+            assert!(stats.free_pages > 0);
+            assert_eq!(stats.free_pages + stats.live_pages + 1, btree.core.pages_count);
+
+            btree.core.pages_count
+        };
+
+        let mut btree = BTree::<IntKey, IntRecord>::open("test_free_pages_reused.hex", 0)?;
+        let free_pages = btree.core.page_stats().free_pages;
+        assert!(free_pages > 0);
+
+        for value in 0..100 {
+            btree.insert(IntKey{value}, record(value))?;
+        }
+
+        assert_eq!(btree.core.pages_count, pages_count);
+        assert!(btree.core.page_stats().free_pages < free_pages);
+        assert_eq!(collect_keys(&btree), (0..200).filter(|x| *x < 100 || *x >= 150).collect::<Vec<i32>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_pool() -> Result<(), std::io::Error> {
+        let block_size = 88; // t = 2
+
+        let mut reads = vec![];
+        for (name, capacity) in [("test_buffer_pool_0.hex", 0), ("test_buffer_pool_16.hex", 16)] {
+            {
+                let mut btree = BTree::<IntKey, IntRecord>::create(name, block_size, capacity)?;
+
+                for value in 0..100 {
+                    btree.insert(IntKey{value}, record(value))?;
+                }
+                for value in 0..100 {
+                    assert!(btree.search(IntKey{value}));
+                }
+
+                let pool = btree.core.index_device.borrow();
+                assert_eq!(pool.misses, pool.device.reads);
+                assert!(pool.cached_count() <= capacity);
+                reads.push((pool.hits, pool.device.reads));
+            }
+
+            let btree = BTree::<IntKey, IntRecord>::open(name, capacity)?;
+            assert_eq!(btree.core.index_device.borrow().capacity, capacity);
+            assert_eq!(collect_keys(&btree), (0..100).collect::<Vec<i32>>());
+        }
+
+        /*
+         * Upper levels of the tree stay cached between operations
+         */
+        assert_eq!(reads[0].0, 0);
+        assert!(reads[1].0 > 0);
+        assert!(reads[1].1 < reads[0].1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_compensation() -> Result<(), std::io::Error> {
+        use rand::{seq::SliceRandom, SeedableRng};
+
+        let block_size = 21 * 6; // t = 3
+        let mut rng = rand::rngs::StdRng::seed_from_u64(11);
+        let mut values = (0..500).collect::<Vec<i32>>();
+        values.shuffle(&mut rng);
+
+        let mut results = vec![];
+        for (name, policy) in [
+            ("test_insert_compensation_split.hex", InsertPolicy::Split),
+            ("test_insert_compensation.hex", InsertPolicy::Compensation),
+        ] {
+            let mut btree = BTree::<IntKey, IntRecord>::create(name, block_size, 0)?;
+            btree.insert_policy = policy;
+
+            for &value in &values {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+
+            assert_eq!(collect_keys(&btree), (0..500).collect::<Vec<i32>>());
+            for value in (0..500).step_by(7) {
+                assert_eq!(btree.get(IntKey{value})?.unwrap().get_bytes(), record(value).get_bytes());
+            }
+            results.push((btree.splits, btree.compensations, btree.core.page_stats().live_pages));
+        }
+
+        let (splits, compensations, pages) = results[0];
+        let (compensated_splits, compensated, compensated_pages) = results[1];
+        assert_eq!(compensations, 0);
+        assert!(compensated > 0);
+        assert!(compensated_splits < splits);
+        assert!(compensated_pages < pages);
+
+        Ok(())
+    }
+
+    /*
+     * In-order walk of the index yields ids of data records in key order
+     */
+    fn data_ids(btree: &BTree<IntKey, IntRecord>) -> Vec<u64> {
+        fn walk(btree: &BTree<IntKey, IntRecord>, lba: u64, ids: &mut Vec<u64>) {
+            let page = btree.core.load_page(lba, u64::MAX);
+            for record in &page.records {
+                if let Some(child_lba) = record.child_lba {
+                    walk(btree, child_lba, ids);
+                }
+                if record.key != IntKey::invalid() {
+                    ids.push(record.data_lba);
+                }
+            }
+        }
+
+        let mut ids = vec![];
+        walk(btree, btree.core.root_lba, &mut ids);
+        ids
+    }
+
+    #[test]
+    fn test_reorganize() -> Result<(), std::io::Error> {
+        let path = "test_reorganize.hex";
+        {
+            let mut btree = BTree::<IntKey, IntRecord>::create(path, 256, 0)?;
+            for value in (0..60).rev() {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+            for value in (0..60).filter(|value| value % 4 != 0) {
+                btree.delete(IntKey{value})?;
+            }
+            btree.update(IntKey{value: 8}, record(800))?;
+
+            assert_eq!(btree.core.heap.pages_count, 20);
+            assert!(btree.dead_space_ratio()? > 2.0);
+            let data_writes = btree.core.data_device.borrow().writes;
+
+            btree.reorganize()?;
+
+            assert_eq!(btree.core.heap.pages_count, 5);
+            assert_eq!(btree.dead_space_ratio()?, 0.0);
+            assert_eq!(btree.core.data_device.borrow().writes, data_writes + 15);
+
+            let ids = data_ids(&btree);
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(btree.get(IntKey{value: 8})?.unwrap().get_bytes(), record(800).get_bytes());
+
+            /*
+             * Tree keeps working on the swapped files
+             */
+            btree.insert(IntKey{value: 61}, record(61))?;
+            assert!(btree.delete(IntKey{value: 0})?);
+        }
+
+        assert!(!Path::new("test_reorganize.hex.tmp").exists());
+        assert!(!Path::new("test_reorganize_data.hex.tmp").exists());
+
+        let btree = BTree::<IntKey, IntRecord>::open(path, 0)?;
+        let mut expected = (4..60).step_by(4).collect::<Vec<i32>>();
+        expected.push(61);
+        assert_eq!(collect_keys(&btree), expected);
+        for value in expected {
+            let data = if value == 8 { record(800) } else { record(value) };
+            assert_eq!(btree.get(IntKey{value})?.unwrap().get_bytes(), data.get_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorganize_threshold() -> Result<(), std::io::Error> {
+        let mut btree = BTree::<IntKey, IntRecord>::create("test_reorganize_threshold.hex", 256, 0)?;
+        btree.reorganize_threshold = Some(0.5);
+
+        for value in 0..30 {
+            btree.insert(IntKey{value}, record(value))?;
+        }
+
+        /*
+         * Tenth delete leaves 10 dead records next to 20 live ones
+         */
+        for value in 0..10 {
+            assert_eq!(btree.core.heap.pages_count, 10);
+            btree.delete(IntKey{value: value * 3})?;
+        }
+        assert!(btree.dead_space_ratio()? <= 0.5);
+        btree.delete(IntKey{value: 1})?;
+
+        assert_eq!(btree.dead_space_ratio()?, 0.0);
+        assert_eq!(btree.core.heap.pages_count, 7);
+        assert_eq!(collect_keys(&btree).len(), 19);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorganize_recovery() -> Result<(), std::io::Error> {
+        let path = "test_reorganize_recovery.hex";
+        {
+            let mut btree = BTree::<IntKey, IntRecord>::create(path, 256, 0)?;
+            for value in 0..20 {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+        }
+
+        /*
+         * Crash before the commit point leaves the copies behind
+         */
+        fs::write("test_reorganize_recovery.hex.tmp", [0u8; 256])?;
+        fs::write("test_reorganize_recovery_data.hex.tmp", [0u8; 256])?;
+        {
+            let btree = BTree::<IntKey, IntRecord>::open(path, 0)?;
+            assert_eq!(collect_keys(&btree), (0..20).collect::<Vec<i32>>());
+        }
+        assert!(!Path::new("test_reorganize_recovery.hex.tmp").exists());
+        assert!(!Path::new("test_reorganize_recovery_data.hex.tmp").exists());
+
+        /*
+         * Crash after it leaves only the data file to be renamed
+         */
+        fs::copy("test_reorganize_recovery_data.hex", "test_reorganize_recovery_data.hex.tmp")?;
+        fs::write("test_reorganize_recovery_data.hex", [])?;
+
+        let btree = BTree::<IntKey, IntRecord>::open(path, 0)?;
+        for value in 0..20 {
+            assert_eq!(btree.get(IntKey{value})?.unwrap().get_bytes(), record(value).get_bytes());
+        }
+        assert!(!Path::new("test_reorganize_recovery_data.hex.tmp").exists());
+
+        Ok(())
+    }
+
+    /*
+     * Index device fails after a few more writes, in the middle of writing
+     * pages of some insert. Everything it did is in the log already.
+     */
+    fn crash_during_inserts(path: &str, writes: u64) -> i32 {
+        let mut btree = BTree::<IntKey, IntRecord>::create(path, 88, 0).unwrap(); // t = 2
+        btree.enable_wal().unwrap();
+        for value in 0..30 {
+            btree.insert(IntKey{value}, record(value)).unwrap();
+        }
+
+        let limit = btree.core.index_device.borrow().device.writes + writes;
+        btree.core.index_device.borrow_mut().device.writes_limit = Some(limit);
+
+        let mut inserted = 30;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            for value in 30..100 {
+                btree.insert(IntKey{value}, record(value)).unwrap();
+                inserted += 1;
+            }
+        }));
+        assert!(result.is_err());
+
+        std::mem::forget(btree);
+        inserted
+    }
+
+    #[test]
+    fn test_wal_crash() -> Result<(), std::io::Error> {
+        let mut broken = 0;
+
+        for writes in [1, 4, 9, 15] {
+            let path = format!("test_wal_crash_{}.hex", writes);
+            let raw_path = format!("test_wal_crash_{}_raw.hex", writes);
+            let inserted = crash_during_inserts(&path, writes);
+
+            fs::copy(&path, &raw_path)?;
+            fs::copy(
+                BTreeCore::<IntKey, IntRecord>::data_path(&path),
+                BTreeCore::<IntKey, IntRecord>::data_path(&raw_path),
+            )?;
+            if !BTree::<IntKey, IntRecord>::open(&raw_path, 0)?.check().is_empty() {
+                broken += 1;
+            }
+
+            /*
+             * Insert that crashed was logged completely, so it is replayed too
+             */
+            let btree = BTree::<IntKey, IntRecord>::open(&path, 0)?;
+            assert!(btree.wal.is_some());
+            assert_eq!(fs::metadata(BTree::<IntKey, IntRecord>::wal_path(&path))?.len(), 0);
+            assert_eq!(btree.check(), vec![]);
+            assert_eq!(collect_keys(&btree), (0..inserted + 1).collect::<Vec<i32>>());
+            for value in 0..inserted + 1 {
+                assert_eq!(btree.get(IntKey{value})?.unwrap().get_bytes(), record(value).get_bytes());
+            }
+        }
+
+        /*
+         * Without the log the same files are left inconsistent
+         */
+        assert!(broken > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_torn_log() -> Result<(), std::io::Error> {
+        let path = "test_wal_torn_log.hex";
+        {
+            let mut btree = BTree::<IntKey, IntRecord>::create(path, 88, 0)?; // t = 2
+            btree.enable_wal()?;
+            for value in 0..20 {
+                btree.insert(IntKey{value}, record(value))?;
+            }
+            assert!(btree.delete(IntKey{value: 7})?);
+
+            /*
+             * Crash while the last insert is being logged, nothing of it
+             * reaches the devices
+             */
+            let index_writes = btree.core.index_device.borrow().device.writes;
+            let data_writes = btree.core.data_device.borrow().writes;
+            btree.core.index_device.borrow_mut().device.writes_limit = Some(index_writes);
+            btree.core.data_device.borrow_mut().writes_limit = Some(data_writes);
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                btree.insert(IntKey{value: 20}, record(20)).unwrap();
+            }));
+            assert!(result.is_err());
+            std::mem::forget(btree);
+        }
+
+        let wal_path = "test_wal_torn_log.hex.wal";
+        let file = fs::OpenOptions::new().write(true).open(wal_path)?;
+        file.set_len(fs::metadata(wal_path)?.len() - 5)?;
+
+        let btree = BTree::<IntKey, IntRecord>::open(path, 0)?;
+        let mut expected = (0..20).collect::<Vec<i32>>();
+        expected.remove(7);
+        assert_eq!(btree.check(), vec![]);
+        assert_eq!(collect_keys(&btree), expected);
+
+        Ok(())
+    }
+
+    /*
+     * Random lowercase text of random length, made unique by its index
+     */
+    fn string_key(rng: &mut StdRng, index: usize) -> StringKey {
+        let len = rng.gen_range(0..=STRING_KEY_MAX_LEN - 4);
+        let text = (0..len)
+            .map(|_| rng.gen_range(b'a'..=b'z') as char)
+            .collect::<String>();
+        StringKey::new(&format!("{}{:04}", text, index)).unwrap()
+    }
+
+    #[test]
+    fn test_split_by_bytes() -> Result<(), std::io::Error> {
+        let btree = BTree::<StringKey, IntRecord>::create("test_split_by_bytes.hex", 512, 0)?;
+        let mut parent = Page::<BTreeRecord<StringKey>>::empty(&btree.core.index_device, 2, u64::MAX);
+        let mut child = Page::<BTreeRecord<StringKey>>::empty(&btree.core.index_device, 3, 2);
+        let mut new_child = Page::<BTreeRecord<StringKey>>::empty(&btree.core.index_device, 4, 2);
+
+        parent.records.push(Box::new(BTreeRecord {
+            child_lba: Some(3),
+            key: StringKey::invalid(),
+            data_lba: 0,
+        }));
+        let long = "a".repeat(STRING_KEY_MAX_LEN);
+        for text in [long.as_str(), "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"] {
+            child.records.push(Box::new(BTreeRecord {
+                child_lba: None,
+                key: StringKey::new(text)?,
+                data_lba: 0,
+            }));
+        }
+
+        /*
+         * Long key outweighs four short ones, so the centre moves left of
+         * the middle record
+         */
+        BTree::<StringKey, IntRecord>::split_child(&mut parent, &mut child, &mut new_child);
+        assert_eq!(parent.records[0].key, StringKey::new("e")?);
+        assert_eq!(child.records.len(), 4);
+        assert_eq!(new_child.records.len(), 6);
+        assert_eq!(BTreeCore::<StringKey, IntRecord>::weight(&child), 58 + 3 * 19);
+        assert_eq!(BTreeCore::<StringKey, IntRecord>::weight(&new_child), 6 * 19);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_keys() -> Result<(), std::io::Error> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut btree = BTree::<StringKey, IntRecord>::create("test_string_keys.hex", 512, 0)?;
+        let keys = (0..400).map(|index| string_key(&mut rng, index)).collect::<Vec<_>>();
+
+        for (index, key) in keys.iter().enumerate() {
+            btree.insert(*key, record(index as i32 + 1))?;
+        }
+        assert_eq!(btree.check(), vec![]);
+
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(btree.range(..).map(|item| item.unwrap().0).collect::<Vec<_>>(), sorted);
+
+        for (index, key) in keys.iter().enumerate().step_by(2) {
+            assert!(btree.delete(*key)?, "Could not delete {:?}", key);
+            if index % 50 == 0 {
+                assert_eq!(btree.check(), vec![]);
+            }
+        }
+        assert_eq!(btree.check(), vec![]);
+        btree.flush()?;
+
+        let btree = BTree::<StringKey, IntRecord>::open("test_string_keys.hex", 0)?;
+        for (index, key) in keys.iter().enumerate() {
+            let expected = (index % 2 == 1).then(|| record(index as i32 + 1).get_bytes());
+            assert_eq!(btree.get(*key)?.map(|record| record.get_bytes()), expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_keys_page_count() -> Result<(), std::io::Error> {
+        let mut btree = BTree::<StringKey, IntRecord>::create("test_string_keys_page_count.hex", 512, 0)?;
+        for value in 0..200 {
+            btree.insert(StringKey::new(&format!("k{:03}", value))?, record(value + 1))?;
+        }
+        assert_eq!(btree.check(), vec![]);
+
+        /*
+         * Short keys take a fraction of the largest record, so pages hold
+         * many more of them than the degree allows for the largest ones
+         */
+        let stats = btree.stats()?;
+        let pages = stats.pages_per_level.iter().sum::<u64>();
+        assert!(stats.keys / pages > 2 * btree.core.degree - 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unique_and_replace() -> Result<(), std::io::Error> {
+        let mut unique = BTree::<IntKey, IntRecord>::create("test_unique.hex", 88, 0)?; // t = 2
+        let mut replace = BTree::<IntKey, IntRecord>::create_with_duplicates(
+            "test_replace.hex",
+            88,
+            DuplicateMode::Replace,
+            0,
+        )?;
+        for value in 0..30 {
+            unique.insert(IntKey{value}, record(value))?;
+            replace.insert(IntKey{value}, record(value))?;
+        }
+
+        for value in (0..30).step_by(4) {
+            let error = unique.insert(IntKey{value}, record(100)).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+            replace.insert(IntKey{value}, record(100))?;
+        }
+
+        for value in 0..30 {
+            let replaced = match value % 4 {
+                0 => 100,
+                _ => value,
+            };
+            assert_eq!(unique.get(IntKey{value})?.unwrap().get_bytes(), record(value).get_bytes());
+            assert_eq!(replace.get(IntKey{value})?.unwrap().get_bytes(), record(replaced).get_bytes());
+        }
+        for btree in [&mut unique, &mut replace] {
+            assert_eq!(btree.range(..).count(), 30);
+            assert_eq!(btree.core.heap.usage()?.live_records, 30);
+            assert_eq!(btree.check(), vec![]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multimap() -> Result<(), std::io::Error> {
+        let path = "test_multimap.hex";
+        let mut btree = BTree::<IntKey, IntRecord>::create_with_duplicates(
+            path,
+            88,
+            DuplicateMode::Multimap,
+            0,
+        )?; // t = 2
+        for round in 0..8 {
+            for value in 0..10 {
+                btree.insert(IntKey{value}, record(round * 10 + value))?;
+            }
+        }
+        assert_eq!(btree.check(), vec![]);
+
+        let inserted = |value: i32| {
+            (0..8).map(|round| record(round * 10 + value).get_bytes()).collect::<Vec<_>>()
+        };
+        let get_all = |btree: &BTree<IntKey, IntRecord>, value: i32| {
+            btree.get_all(IntKey{value}).unwrap().iter().map(|record| record.get_bytes()).collect::<Vec<_>>()
+        };
+        for value in 0..10 {
+            assert_eq!(get_all(&btree, value), inserted(value));
+            assert_eq!(btree.get(IntKey{value})?.unwrap().get_bytes(), record(value).get_bytes());
+        }
+
+        let keys = btree.range(..).map(|item| item.unwrap().0.value).collect::<Vec<i32>>();
+        assert_eq!(keys.len(), 80);
+        assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        /*
+         * Both ends of a scan meet between equal keys
+         */
+        let mut range = btree.range(IntKey{value: 3}..=IntKey{value: 3});
+        assert!(range.next().is_some());
+        assert!(range.next_back().is_some());
+        assert_eq!(range.count(), 6);
+
+        /*
+         * Deletion removes every record of the key, the rest keep their order
+         * through merges and replaced separators
+         */
+        assert!(btree.delete(IntKey{value: 3})?);
+        assert!(btree.delete(IntKey{value: 5})?);
+        assert!(!btree.delete(IntKey{value: 5})?);
+        assert!(btree.get_all(IntKey{value: 3})?.is_empty());
+        assert_eq!(btree.core.heap.usage()?.live_records, 64);
+        assert_eq!(btree.check(), vec![]);
+        btree.flush()?;
+        drop(btree);
+
+        let btree = BTree::<IntKey, IntRecord>::open(path, 0)?;
+        assert_eq!(btree.core.duplicate_mode, DuplicateMode::Multimap);
+        for value in [0, 4, 6, 9] {
+            assert_eq!(get_all(&btree, value), inserted(value));
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,370 @@
+use crate::{
+    btree::{BTree, DuplicateMode},
+    btree_core::BTreeCore,
+    btree_key::BTreeKey,
+    btree_record::BTreeRecord,
+    page::Page,
+    record::Record,
+};
+
+/*
+ * Level of the tree under construction. `previous` is the last full page of
+ * the level together with the separator that follows it, it is held back so
+ * that the last page of the level can take keys from it once the input
+ * ends. Records of upper levels point at their left child, a page gets its
+ * trailing record when it is closed.
+ */
+struct Level<K: BTreeKey> {
+    previous: Option<(Vec<BTreeRecord<K>>, BTreeRecord<K>)>,
+    current: Vec<BTreeRecord<K>>,
+}
+
+impl<K: BTreeKey> Default for Level<K> {
+    fn default() -> Self {
+        Level {
+            previous: None,
+            current: vec![],
+        }
+    }
+}
+
+/*
+ * Builds pages level by level as the keys come, keeping two pages per level
+ */
+struct BulkBuilder<'a, K: BTreeKey, T: Record> {
+    core: &'a mut BTreeCore<K, T>,
+    levels: Vec<Level<K>>,
+    leaf_target: usize,
+}
+
+impl<K: BTreeKey, T: Record> BulkBuilder<'_, K, T> {
+    fn capacity(&self) -> usize {
+        2 * self.core.degree as usize - 1
+    }
+
+    fn trailing(child_lba: u64) -> BTreeRecord<K> {
+        BTreeRecord {
+            child_lba: Some(child_lba),
+            key: K::invalid(),
+            data_lba: 0,
+        }
+    }
+
+    fn keys_count(records: &[BTreeRecord<K>]) -> usize {
+        records.iter().filter(|record| record.key != K::invalid()).count()
+    }
+
+    /*
+     * Adds a record at the end of `level`. Once the current page holds its
+     * target the record becomes the separator after it, the page before it
+     * is written and its separator goes one level up.
+     */
+    fn push(&mut self, level: usize, record: BTreeRecord<K>) -> Result<(), std::io::Error> {
+        if level == self.levels.len() {
+            self.levels.push(Level::default());
+        }
+        let target = match level {
+            0 => self.leaf_target,
+            _ => self.capacity(),
+        };
+
+        let current = &mut self.levels[level].current;
+        if current.len() < target {
+            current.push(record);
+            return Ok(());
+        }
+
+        let mut page = std::mem::take(current);
+        if let Some(child_lba) = record.child_lba {
+            page.push(BulkBuilder::<K, T>::trailing(child_lba));
+        }
+        let separator = BTreeRecord {
+            child_lba: None,
+            ..record
+        };
+
+        if let Some((previous, separator)) = self.levels[level].previous.replace((page, separator)) {
+            let lba = self.write(previous, None)?;
+            self.push(level + 1, BTreeRecord {
+                child_lba: Some(lba),
+                ..separator
+            })?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Closes `level` after its last record, `last_child` is the last page of
+     * the level below. Last page short of keys is merged with the held back
+     * one, or shares their keys with it when they do not fit a single page.
+     * Single page of the top level becomes the root.
+     */
+    fn finish(&mut self, level: usize, last_child: Option<u64>) -> Result<(), std::io::Error> {
+        let Level { previous, mut current } = std::mem::take(&mut self.levels[level]);
+        if let Some(child_lba) = last_child {
+            current.push(BulkBuilder::<K, T>::trailing(child_lba));
+        }
+
+        let minimum = self.core.degree as usize - 1;
+        let mut pages = vec![];
+        match previous {
+            None => pages.push(current),
+            Some((previous, separator)) if BulkBuilder::<K, T>::keys_count(&current) >= minimum => {
+                pages.push(previous);
+                pages.push(vec![separator]);
+                pages.push(current);
+            }
+            Some((mut records, separator)) => {
+                let separator = match records.last() {
+                    Some(last) if last.key == K::invalid() => BTreeRecord {
+                        child_lba: records.pop().and_then(|last| last.child_lba),
+                        ..separator
+                    },
+                    _ => separator,
+                };
+                records.push(separator);
+                records.extend(current);
+
+                let keys = BulkBuilder::<K, T>::keys_count(&records);
+                if keys <= self.capacity() {
+                    pages.push(records);
+                } else {
+                    let right = records.split_off(keys / 2 + 1);
+                    let separator = records.pop().expect("Split of an empty page");
+                    if let Some(child_lba) = separator.child_lba {
+                        records.push(BulkBuilder::<K, T>::trailing(child_lba));
+                    }
+                    pages.push(records);
+                    pages.push(vec![BTreeRecord {
+                        child_lba: None,
+                        ..separator
+                    }]);
+                    pages.push(right);
+                }
+            }
+        }
+
+        if pages.len() == 1 && level + 1 == self.levels.len() {
+            let root_lba = self.core.root_lba;
+            self.write(pages.remove(0), Some(root_lba))?;
+            return Ok(());
+        }
+
+        /*
+         * Pages alternate with the separators between them
+         */
+        let last = pages.pop().expect("Level without pages");
+        let mut pages = pages.into_iter();
+        while let (Some(page), Some(separator)) = (pages.next(), pages.next()) {
+            let lba = self.write(page, None)?;
+            self.push(level + 1, BTreeRecord {
+                child_lba: Some(lba),
+                ..separator[0]
+            })?;
+        }
+        let lba = self.write(last, None)?;
+        self.finish(level + 1, Some(lba))
+    }
+
+    fn write(&mut self, records: Vec<BTreeRecord<K>>, lba: Option<u64>) -> Result<u64, std::io::Error> {
+        let lba = match lba {
+            Some(lba) => lba,
+            None => self.core.get_next_index_lba()?,
+        };
+        let mut page = Page::<BTreeRecord<K>>::empty(&self.core.index_device, lba, u64::MAX);
+        page.records = records.into_iter().map(Box::new).collect();
+
+        Ok(lba)
+    }
+}
+
+impl<K: BTreeKey, T: Record> BTree<K, T> {
+    /*
+     * Builds the tree bottom-up from entries sorted by key, without a single
+     * descent from the root. Leaves are filled to `fill_factor` of their
+     * capacity, every upper level is packed full. Entries are consumed as
+     * they come, memory holds two pages per level of the tree and never the
+     * whole input. Works only on an empty tree, returns the number of loaded
+     * entries. Pages are sized by the number of keys, so keys of variable
+     * size are not supported. Equal keys are accepted in multimap mode only
+     * and keep their order. An entry out of order or an error of the input
+     * ends the load with the tree still empty, records and pages written
+     * until then are left behind unreferenced, see `check`.
+     */
+    pub fn bulk_load<I: IntoIterator<Item = Result<(K, T), std::io::Error>>>(
+        &mut self,
+        entries: I,
+        fill_factor: f64,
+    ) -> Result<u64, std::io::Error> {
+        let invalid_input = |message: &str| {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string()))
+        };
+
+        if K::get_size() != K::min_size() {
+            return invalid_input("Bulk load supports keys of fixed size only");
+        }
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return invalid_input("Fill factor has to lie in (0, 1]");
+        }
+        if !self.core.load_page(self.core.root_lba, u64::MAX).records.is_empty() {
+            return invalid_input("Bulk load needs an empty tree");
+        }
+
+        let degree = self.core.degree as usize;
+        let capacity = 2 * degree - 1;
+        let leaf_target = (capacity as f64 * fill_factor).round() as usize;
+        let mut builder = BulkBuilder {
+            core: &mut self.core,
+            levels: vec![Level::default()],
+            leaf_target: leaf_target.clamp(degree - 1, capacity),
+        };
+
+        let mut count = 0;
+        let mut last: Option<K> = None;
+        for entry in entries {
+            let (key, record) = entry?;
+            let out_of_order = last.is_some_and(|last| match builder.core.duplicate_mode {
+                DuplicateMode::Multimap => last > key,
+                _ => last >= key,
+            });
+            if key == K::invalid() || out_of_order {
+                return invalid_input(&format!("Key {} breaks the sorted order", key));
+            }
+            last = Some(key);
+
+            let data_lba = builder.core.heap.insert(&record.get_bytes())?;
+            builder.push(0, BTreeRecord {
+                child_lba: None,
+                key,
+                data_lba,
+            })?;
+            count += 1;
+        }
+        builder.finish(0, None)?;
+
+        self.core.dirty = true;
+        self.commit()?;
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{btree_key::IntKey, record::IntRecord};
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    fn entries(count: i32) -> impl DoubleEndedIterator<Item = (IntKey, IntRecord)> {
+        (0..count).map(|value| (IntKey { value: value * 2 }, record(value)))
+    }
+
+    #[test]
+    fn test_bulk_load_last_pages() -> Result<(), std::io::Error> {
+        /*
+         * Last pages of every level are short for most counts, they take
+         * keys from the pages before them
+         */
+        for fill_factor in [0.1, 0.5, 1.0] {
+            for count in 0..120 {
+                let mut btree = BTree::<IntKey, IntRecord>::create("test_bulk_load_last.hex", 88, 0)?; // t = 2
+                assert_eq!(btree.bulk_load(entries(count).map(Ok), fill_factor)?, count as u64);
+                assert_eq!(btree.check(), vec![], "{} entries at {}", count, fill_factor);
+                assert_eq!(btree.range(..).count(), count as usize);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_load() -> Result<(), std::io::Error> {
+        for (count, fill_factor) in [(0, 1.0), (3, 1.0), (50, 1.0), (200, 0.5), (333, 0.7)] {
+            let path = format!("test_bulk_load_{}.hex", count);
+            let mut btree = BTree::<IntKey, IntRecord>::create(&path, 21 * 6, 0)?; // t = 3
+
+            assert_eq!(btree.bulk_load(entries(count).map(Ok), fill_factor)?, count as u64);
+            assert_eq!(btree.check(), vec![]);
+
+            let loaded = btree
+                .range(..)
+                .map(|item| item.map(|(key, record)| (key.value, record.get_bytes())))
+                .collect::<Result<Vec<_>, std::io::Error>>()?;
+            let expected = entries(count)
+                .map(|(key, record)| (key.value, record.get_bytes()))
+                .collect::<Vec<_>>();
+            assert_eq!(loaded, expected);
+
+            /*
+             * Tree keeps working as usual afterwards
+             */
+            btree.insert(IntKey { value: 1 }, record(1))?;
+            btree.delete(IntKey { value: 0 })?;
+            assert!(btree.search(IntKey { value: 1 }));
+            assert_eq!(btree.check(), vec![]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_load_fill_factor() -> Result<(), std::io::Error> {
+        let mut full = BTree::<IntKey, IntRecord>::create("test_bulk_load_full.hex", 21 * 6, 0)?;
+        full.bulk_load(entries(500).map(Ok), 1.0)?;
+        let mut half = BTree::<IntKey, IntRecord>::create("test_bulk_load_half.hex", 21 * 6, 0)?;
+        half.bulk_load(entries(500).map(Ok), 0.5)?;
+
+        let full_stats = full.stats()?;
+        let half_stats = half.stats()?;
+        assert!(full_stats.avg_occupancy > 0.75);
+        assert!(half_stats.avg_occupancy < 0.6);
+        assert!(full_stats.pages_per_level.iter().sum::<u64>() < half_stats.pages_per_level.iter().sum::<u64>());
+
+        /*
+         * Bulk load writes each page once, inserts descend for every key
+         */
+        let mut inserted = BTree::<IntKey, IntRecord>::create("test_bulk_load_inserted.hex", 21 * 6, 0)?;
+        for (key, record) in entries(500) {
+            inserted.insert(key, record)?;
+        }
+        full.flush()?;
+        inserted.flush()?;
+        assert!(full.core.index_device.borrow().device.writes < inserted.core.index_device.borrow().device.writes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_load_errors() -> Result<(), std::io::Error> {
+        let mut btree = BTree::<IntKey, IntRecord>::create("test_bulk_load_errors.hex", 21 * 6, 0)?;
+
+        assert!(btree.bulk_load(entries(10).map(Ok), 0.0).is_err());
+        assert!(btree.bulk_load(entries(10).rev().map(Ok), 1.0).is_err());
+
+        let mut btree = BTree::<IntKey, IntRecord>::create("test_bulk_load_errors.hex", 21 * 6, 0)?;
+        btree.insert(IntKey { value: 1 }, record(1))?;
+        assert!(btree.bulk_load(entries(10).map(Ok), 1.0).is_err());
+
+        /*
+         * Equal keys are loaded only into a multimap
+         */
+        let equal = || entries(10).map(|(key, record)| Ok((IntKey { value: key.value / 4 }, record)));
+        let mut btree = BTree::<IntKey, IntRecord>::create("test_bulk_load_errors.hex", 21 * 6, 0)?;
+        assert!(btree.bulk_load(equal(), 1.0).is_err());
+        let mut btree = BTree::<IntKey, IntRecord>::create_with_duplicates(
+            "test_bulk_load_errors.hex",
+            21 * 6,
+            DuplicateMode::Multimap,
+            0,
+        )?;
+        assert_eq!(btree.bulk_load(equal(), 1.0)?, 10);
+        assert_eq!(btree.get_all(IntKey { value: 2 })?.len(), 2);
+        assert_eq!(btree.check(), vec![]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,425 @@
+use std::{collections::HashSet, fmt::Display};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    btree::{BTree, DuplicateMode},
+    btree_key::BTreeKey,
+    btree_record::BTreeRecord,
+    bytes::Bytes,
+    heap::HeapFile,
+    page::Page,
+    record::Record,
+};
+
+/*
+ * Broken property of the tree. `lba` is the index page at fault, problems of
+ * the tree as a whole are reported against the superblock at 0.
+ */
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Violation {
+    pub lba: u64,
+    pub message: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "page {}: {}", self.lba, self.message)
+    }
+}
+
+#[derive(Default)]
+struct CheckState {
+    pages: HashSet<u64>,
+    data: HashSet<u64>,
+    leaf_depth: Option<u64>,
+    violations: Vec<Violation>,
+}
+
+impl CheckState {
+    fn report(&mut self, lba: u64, message: String) {
+        self.violations.push(Violation { lba, message });
+    }
+}
+
+impl<K: BTreeKey, T: Record> BTree<K, T> {
+    /*
+     * Walks every page reachable from the root, then the free list and the
+     * data file, and reports everything that breaks B-tree properties.
+     * Nothing is written, pages are read as raw blocks, so an unreadable
+     * one is reported instead of being replaced with an empty page. Pages
+     * pointed at from outside of the index are never read.
+     */
+    pub fn check(&self) -> Vec<Violation> {
+        let mut state = CheckState::default();
+
+        if self.core.root_lba == 0 || self.core.root_lba >= self.core.pages_count {
+            state.report(0, format!("root {} lies outside of the index", self.core.root_lba));
+            return state.violations;
+        }
+
+        state.pages.insert(self.core.root_lba);
+        self.check_page(&mut state, self.core.root_lba, 0, None, None);
+        self.check_free_list(&mut state);
+        self.check_data(&mut state);
+
+        state.violations
+    }
+
+    fn check_page(
+        &self,
+        state: &mut CheckState,
+        lba: u64,
+        depth: u64,
+        lower: Option<K>,
+        upper: Option<K>,
+    ) {
+        let records = match self.core.index_device.borrow_mut().read(lba) {
+            Ok(bytes) => Page::<BTreeRecord<K>>::records_from_bytes(&bytes)
+                .into_iter()
+                .map(|record| *record)
+                .collect::<Vec<_>>(),
+            Err(error) => {
+                state.report(lba, format!("page can not be read: {}", error));
+                return;
+            }
+        };
+        let leaf = records.first().is_none_or(|record| record.child_lba.is_none());
+
+        let keys = records.iter().filter(|record| record.key != K::invalid());
+        let keys_count = keys.clone().count() as u64;
+        let weight = keys.map(|record| record.size()).sum::<u64>();
+        if weight > self.core.page_capacity()
+            || (lba != self.core.root_lba && weight < self.core.page_minimum())
+        {
+            let message = match K::get_size() == K::min_size() {
+                true => format!(
+                    "{} keys outside of [{}, {}]",
+                    keys_count,
+                    self.core.degree - 1,
+                    2 * self.core.degree - 1
+                ),
+                false => format!(
+                    "{} bytes of keys outside of [{}, {}]",
+                    weight,
+                    self.core.page_minimum(),
+                    self.core.page_capacity()
+                ),
+            };
+            state.report(lba, message);
+        }
+
+        if leaf {
+            match state.leaf_depth {
+                None => state.leaf_depth = Some(depth),
+                Some(leaf_depth) if leaf_depth != depth => state.report(
+                    lba,
+                    format!("leaf at depth {}, other leafs are at depth {}", depth, leaf_depth),
+                ),
+                _ => (),
+            }
+        } else if records.last().is_none_or(|record| record.key != K::invalid()) {
+            state.report(lba, "non-leaf page does not end with trailing record".to_string());
+        }
+
+        let mut previous = lower;
+        for (index, record) in records.iter().enumerate() {
+            let trailing = !leaf && index == records.len() - 1;
+
+            if record.key == K::invalid() && !trailing {
+                state.report(lba, format!("record {} has invalid key", index));
+                continue;
+            }
+            if leaf && record.child_lba.is_some() {
+                state.report(lba, format!("leaf record {} points at a child", index));
+            }
+            if !leaf && record.child_lba.is_none() {
+                state.report(lba, format!("record {} has no child", index));
+            }
+
+            let child_upper = match record.key == K::invalid() {
+                true => upper,
+                false => Some(record.key),
+            };
+
+            if let Some(child_lba) = record.child_lba {
+                if child_lba == 0 || child_lba >= self.core.pages_count {
+                    state.report(lba, format!("child {} lies outside of the index", child_lba));
+                } else if !state.pages.insert(child_lba) {
+                    state.report(lba, format!("child {} is referenced more than once", child_lba));
+                } else {
+                    self.check_page(state, child_lba, depth + 1, previous, child_upper);
+                }
+            }
+
+            if record.key == K::invalid() {
+                continue;
+            }
+
+            if previous.is_some_and(|previous| record.key < previous)
+                || upper.is_some_and(|upper| record.key > upper)
+            {
+                state.report(lba, format!("key {} is out of order", record.key));
+            } else if self.core.duplicate_mode != DuplicateMode::Multimap
+                && (previous == Some(record.key) || upper == Some(record.key))
+            {
+                state.report(lba, format!("key {} is duplicated", record.key));
+            }
+            previous = Some(record.key);
+
+            let (data_page, _) = HeapFile::split_record_id(record.data_lba);
+            if data_page >= self.core.heap.pages_count {
+                state.report(
+                    lba,
+                    format!("data record {:#x} lies outside of the data file", record.data_lba),
+                );
+            } else if !state.data.insert(record.data_lba) {
+                state.report(
+                    lba,
+                    format!("data record {:#x} is referenced more than once", record.data_lba),
+                );
+            } else if self.core.heap.read(record.data_lba).is_err() {
+                state.report(lba, format!("data record {:#x} is missing", record.data_lba));
+            }
+        }
+    }
+
+    fn check_free_list(&self, state: &mut CheckState) {
+        let mut free = HashSet::new();
+        let mut lba = self.core.free_list_lba;
+        let mut previous = 0;
+
+        while lba != 0 {
+            if lba >= self.core.pages_count {
+                state.report(previous, format!("free page {} lies outside of the index", lba));
+                break;
+            }
+            if state.pages.contains(&lba) || !free.insert(lba) {
+                state.report(previous, format!("free page {} is already in use", lba));
+                break;
+            }
+
+            let bytes = match self.core.index_device.borrow_mut().read(lba) {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    state.report(lba, "free page can not be read".to_string());
+                    break;
+                }
+            };
+            previous = lba;
+            lba = LittleEndian::read_u64(&bytes[0..8]);
+        }
+
+        if free.len() as u64 != self.core.free_pages_count {
+            state.report(
+                0,
+                format!(
+                    "free list holds {} pages, superblock counts {}",
+                    free.len(),
+                    self.core.free_pages_count
+                ),
+            );
+        }
+
+        for lba in 1..self.core.pages_count {
+            if !state.pages.contains(&lba) && !free.contains(&lba) {
+                state.report(lba, "page is neither in the tree nor in the free list".to_string());
+            }
+        }
+    }
+
+    fn check_data(&self, state: &mut CheckState) {
+        for lba in 0..self.core.heap.pages_count {
+            let page = match self.core.heap.read_page(lba) {
+                Ok(page) => page,
+                Err(_) => {
+                    state.report(0, format!("data page {} can not be read", lba));
+                    continue;
+                }
+            };
+
+            for slot in 0..page.slots_count() {
+                let id = HeapFile::record_id(lba, slot);
+                if page.get(slot).is_some() && !state.data.contains(&id) {
+                    state.report(0, format!("data record {:#x} is not referenced", id));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{btree_key::IntKey, record::IntRecord};
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    fn build(name: &str) -> BTree<IntKey, IntRecord> {
+        let mut btree = BTree::<IntKey, IntRecord>::create(name, 88, 0).unwrap(); // t = 2
+        for value in 0..40 {
+            btree.insert(IntKey { value }, record(value)).unwrap();
+        }
+        btree
+    }
+
+    fn first_leaf(btree: &BTree<IntKey, IntRecord>) -> (u64, u64) {
+        let mut parent = 0;
+        let mut lba = btree.core.root_lba;
+        loop {
+            let page = btree.core.load_page(lba, u64::MAX);
+            match page.records[0].child_lba {
+                Some(child) => {
+                    parent = lba;
+                    lba = child;
+                }
+                None => return (parent, lba),
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_valid() -> Result<(), std::io::Error> {
+        let mut btree = build("test_check_valid.hex");
+        assert_eq!(btree.check(), vec![]);
+
+        for value in (0..40).step_by(3) {
+            btree.delete(IntKey { value })?;
+        }
+        assert!(btree.core.free_pages_count > 0);
+        assert_eq!(btree.check(), vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_keys() -> Result<(), std::io::Error> {
+        let btree = build("test_check_keys.hex");
+        let (_, leaf_lba) = first_leaf(&btree);
+
+        {
+            let mut leaf = btree.core.load_page(leaf_lba, u64::MAX);
+            leaf.records[0].key = IntKey { value: 1000 };
+            leaf.dirty = true;
+        }
+
+        assert_eq!(
+            btree.check(),
+            vec![Violation {
+                lba: leaf_lba,
+                message: "key 1000 is out of order".to_string(),
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_duplicates() -> Result<(), std::io::Error> {
+        let mut btree = build("test_check_duplicates.hex");
+        let (parent_lba, leaf_lba) = first_leaf(&btree);
+
+        let separator = btree.core.load_page(parent_lba, u64::MAX).records[0].key;
+        {
+            let mut leaf = btree.core.load_page(leaf_lba, u64::MAX);
+            let last = leaf.records.len() - 1;
+            leaf.records[last].key = separator;
+            leaf.dirty = true;
+        }
+
+        assert_eq!(
+            btree.check(),
+            vec![Violation {
+                lba: leaf_lba,
+                message: format!("key {} is duplicated", separator),
+            }]
+        );
+
+        btree.core.duplicate_mode = DuplicateMode::Multimap;
+        assert_eq!(btree.check(), vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_references() -> Result<(), std::io::Error> {
+        let mut btree = build("test_check_references.hex");
+        let (parent_lba, leaf_lba) = first_leaf(&btree);
+
+        let data_id = {
+            let mut parent = btree.core.load_page(parent_lba, u64::MAX);
+            parent.records[1].child_lba = Some(btree.core.pages_count + 3);
+            parent.dirty = true;
+            parent.records[0].data_lba
+        };
+        btree.core.heap.delete(data_id)?;
+
+        let violations = btree.check();
+        assert!(violations.contains(&Violation {
+            lba: parent_lba,
+            message: format!("child {} lies outside of the index", btree.core.pages_count + 3),
+        }));
+        assert!(violations.contains(&Violation {
+            lba: parent_lba,
+            message: format!("data record {:#x} is missing", data_id),
+        }));
+        assert!(violations
+            .iter()
+            .any(|violation| violation.message.contains("neither in the tree")));
+
+        {
+            let mut parent = btree.core.load_page(parent_lba, u64::MAX);
+            parent.records[1].child_lba = Some(leaf_lba);
+            parent.dirty = true;
+        }
+        let violations = btree.check();
+        assert!(violations.contains(&Violation {
+            lba: parent_lba,
+            message: format!("child {} is referenced more than once", leaf_lba),
+        }));
+
+        {
+            let mut leaf = btree.core.load_page(leaf_lba, u64::MAX);
+            leaf.records.insert(0, Box::new(BTreeRecord::<IntKey> {
+                data_lba: 1,
+                ..BTreeRecord::invalid()
+            }));
+            leaf.dirty = true;
+        }
+        assert!(btree
+            .check()
+            .iter()
+            .any(|violation| violation.lba == leaf_lba && violation.message.contains("invalid key")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_unreadable() -> Result<(), std::io::Error> {
+        let mut btree = build("test_check_unreadable.hex");
+        let (parent_lba, _) = first_leaf(&btree);
+
+        /*
+         * Page past the end of the file is counted in the index, but was
+         * never written
+         */
+        let missing = btree.core.pages_count;
+        btree.core.pages_count += 1;
+        {
+            let mut parent = btree.core.load_page(parent_lba, u64::MAX);
+            parent.records[1].child_lba = Some(missing);
+            parent.dirty = true;
+        }
+
+        let violations = btree.check();
+        assert!(violations
+            .iter()
+            .any(|violation| violation.lba == missing && violation.message.contains("can not be read")));
+        assert!(btree.core.index_device.borrow_mut().read(missing).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,578 @@
+use std::{cell::RefCell, marker::PhantomData, path::Path, rc::Rc};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    btree::DuplicateMode,
+    btree_key::BTreeKey,
+    btree_record::BTreeRecord,
+    buffer_pool::BufferPool,
+    bytes::Bytes,
+    device::BlockDevice,
+    heap::HeapFile,
+    page::Page,
+    record::Record,
+    superblock::{Superblock, SUPERBLOCK_SIZE},
+};
+
+/*
+ * Divides a full child of the first page with the empty third one
+ */
+pub type SplitChild<K> =
+    fn(&mut Page<BTreeRecord<K>>, &mut Page<BTreeRecord<K>>, &mut Page<BTreeRecord<K>>);
+
+pub struct PageStats {
+    pub live_pages: u64,
+    pub free_pages: u64,
+}
+
+/*
+ * Files of a tree together with the state kept in its superblock, shared by
+ * `BTree` and `BPlusTree`. Index pages go through the buffer pool and are
+ * reused through the list of free pages, records live in the heap file of
+ * the data device. Page fullness and the moves of records between sibling
+ * pages are the same for both trees, only the routing differs.
+ */
+pub struct BTreeCore<K: BTreeKey, T: Record> {
+    pub index_device: Rc<RefCell<BufferPool>>,
+    pub data_device: Rc<RefCell<BlockDevice>>,
+    pub heap: HeapFile,
+    pub magic: [u8; 8],
+    pub root_lba: u64,
+    pub degree: u64,
+    pub pages_count: u64,
+    pub free_list_lba: u64,
+    pub free_pages_count: u64,
+    pub duplicate_mode: DuplicateMode,
+    pub dirty: bool,
+    key: PhantomData<K>,
+    data: PhantomData<T>,
+}
+
+impl<K: BTreeKey, T: Record> BTreeCore<K, T> {
+    /*
+     * Empty tree with a single leaf as its root, `capacity` is the number of
+     * index blocks cached by the buffer pool
+     */
+    pub fn new(
+        magic: [u8; 8],
+        index_device: BlockDevice,
+        data_device: BlockDevice,
+        duplicate_mode: DuplicateMode,
+        capacity: u64,
+    ) -> Result<Self, std::io::Error> {
+        let child_count: u64 = index_device.block_size / BTreeRecord::<K>::get_size();
+        let superblock = Superblock {
+            magic,
+            block_size: index_device.block_size,
+            data_block_size: data_device.block_size,
+            key_size: K::get_size(),
+            degree: child_count / 2,
+            root_lba: 1,
+            pages_count: 2,
+            data_pages_count: 0,
+            free_list_lba: 0,
+            free_pages_count: 0,
+            duplicate_mode: duplicate_mode.to_superblock(),
+        };
+
+        let mut core =
+            BTreeCore::<K, T>::from_superblock(index_device, data_device, &superblock, capacity)?;
+        core.dirty = true;
+        Ok(core)
+    }
+
+    /*
+     * Fresh devices of a tree, data is kept in a file next to the index
+     */
+    pub fn create_devices(
+        path: &str,
+        block_size: u64,
+    ) -> Result<(BlockDevice, BlockDevice), std::io::Error> {
+        HeapFile::check_block_size(block_size)?;
+        let index_device = BlockDevice::new(path.to_string(), block_size, true)?;
+        let data_device = BlockDevice::new(BTreeCore::<K, T>::data_path(path), block_size, true)?;
+
+        Ok((index_device, data_device))
+    }
+
+    pub fn check_exists(path: &str) -> Result<(), std::io::Error> {
+        if !Path::new(path).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Index file {} does not exist", path),
+            ));
+        }
+        Ok(())
+    }
+
+    /*
+     * Reads the superblock of an existing tree and opens its devices with
+     * the block sizes it describes
+     */
+    pub fn open(path: &str, magic: [u8; 8], capacity: u64) -> Result<Self, std::io::Error> {
+        BTreeCore::<K, T>::check_exists(path)?;
+
+        let mut probe = BlockDevice::new(path.to_string(), SUPERBLOCK_SIZE, false)?;
+        let superblock = Superblock::from_bytes(&probe.read_internal(0)?)?;
+        if superblock.magic != magic {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Index was created for a different kind of tree",
+            ));
+        }
+
+        let index_device = BlockDevice::new(path.to_string(), superblock.block_size, false)?;
+        let data_device = BlockDevice::new(
+            BTreeCore::<K, T>::data_path(path),
+            superblock.data_block_size,
+            false,
+        )?;
+
+        BTreeCore::<K, T>::from_superblock(index_device, data_device, &superblock, capacity)
+    }
+
+    /*
+     * `index.hex` keeps its records in `index_data.hex`
+     */
+    pub fn data_path(path: &str) -> String {
+        let path = Path::new(path);
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        let file_name = match path.extension() {
+            Some(extension) => format!("{}_data.{}", stem, extension.to_string_lossy()),
+            None => format!("{}_data", stem),
+        };
+
+        path.with_file_name(file_name).to_string_lossy().to_string()
+    }
+
+    fn from_superblock(
+        index_device: BlockDevice,
+        data_device: BlockDevice,
+        superblock: &Superblock,
+        capacity: u64,
+    ) -> Result<Self, std::io::Error> {
+        let record_size = BTreeRecord::<K>::get_size();
+        let invalid_data = |message: &str| {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string()))
+        };
+
+        if superblock.key_size != K::get_size() {
+            return invalid_data("Index was created for a different key type");
+        }
+        let duplicate_mode = match DuplicateMode::from_superblock(superblock.duplicate_mode) {
+            Some(duplicate_mode) => duplicate_mode,
+            None => return invalid_data("Unknown duplicate mode of the index"),
+        };
+        if superblock.block_size < SUPERBLOCK_SIZE
+            || superblock.degree < 2
+            || superblock.degree != superblock.block_size / record_size / 2
+        {
+            return invalid_data("Index block size does not match the degree of the tree");
+        }
+        /*
+         * See `page_minimum`, variable keys need room for a few of the largest
+         * records on top of what their separators may grow by
+         */
+        let growth = BTreeCore::<K, T>::key_growth();
+        if growth > 0
+            && superblock.block_size - BTreeRecord::<K>::min_size() < 5 * record_size + 4 * growth
+        {
+            return invalid_data("Index blocks are too small for keys of variable size");
+        }
+        HeapFile::check_block_size(superblock.data_block_size)?;
+        if HeapFile::capacity(superblock.data_block_size) < T::get_size() {
+            return invalid_data("Data device blocks are too small to hold a record");
+        }
+        if superblock.root_lba == 0 || superblock.root_lba >= superblock.pages_count {
+            return invalid_data("Root of the tree lies outside of the index");
+        }
+        if superblock.free_list_lba >= superblock.pages_count
+            || superblock.free_pages_count >= superblock.pages_count
+        {
+            return invalid_data("Free pages list lies outside of the index");
+        }
+
+        let data_device = Rc::new(RefCell::new(data_device));
+        Ok(BTreeCore {
+            index_device: Rc::new(RefCell::new(BufferPool::new(index_device, capacity))),
+            heap: HeapFile::new(&data_device, superblock.data_pages_count),
+            data_device,
+            magic: superblock.magic,
+            root_lba: superblock.root_lba,
+            degree: superblock.degree,
+            pages_count: superblock.pages_count,
+            free_list_lba: superblock.free_list_lba,
+            free_pages_count: superblock.free_pages_count,
+            duplicate_mode,
+            dirty: false,
+            key: PhantomData,
+            data: PhantomData,
+        })
+    }
+
+    pub fn superblock(&self) -> Superblock {
+        Superblock {
+            magic: self.magic,
+            block_size: self.index_device.borrow().block_size,
+            data_block_size: self.data_device.borrow().block_size,
+            key_size: K::get_size(),
+            degree: self.degree,
+            root_lba: self.root_lba,
+            pages_count: self.pages_count,
+            data_pages_count: self.heap.pages_count,
+            free_list_lba: self.free_list_lba,
+            free_pages_count: self.free_pages_count,
+            duplicate_mode: self.duplicate_mode.to_superblock(),
+        }
+    }
+
+    /*
+     * Writes the superblock if the shape of the files changed since last
+     * flush, then writes back all pages cached in the buffer pool
+     */
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        if self.dirty {
+            let bytes = self.superblock().to_bytes();
+            self.index_device.borrow_mut().write(0, &bytes)?;
+            self.dirty = false;
+        }
+        self.index_device.borrow_mut().flush_all()
+    }
+
+    pub fn load_page(&self, lba: u64, parent_lba: u64) -> Page<BTreeRecord<K>> {
+        Page::<BTreeRecord<K>>::new(&self.index_device, lba, parent_lba)
+    }
+
+    pub fn is_leaf(page: &Page<BTreeRecord<K>>) -> bool {
+        page.records
+            .first()
+            .is_none_or(|record| record.child_lba.is_none())
+    }
+
+    pub fn keys_count(page: &Page<BTreeRecord<K>>) -> u64 {
+        page.records.iter().filter(|x| x.key != K::invalid()).count() as u64
+    }
+
+    /*
+     * Bytes taken by the keys of a page, the trailing record is left out
+     */
+    pub fn weight(page: &Page<BTreeRecord<K>>) -> u64 {
+        page.records
+            .iter()
+            .filter(|x| x.key != K::invalid())
+            .map(|x| x.size())
+            .sum()
+    }
+
+    /*
+     * Most a page can grow by when one of its keys is replaced with another,
+     * zero for keys of fixed size
+     */
+    pub fn key_growth() -> u64 {
+        K::get_size() - K::min_size()
+    }
+
+    /*
+     * Bytes the keys of a single page may take. Keys of fixed size are
+     * limited to `2 * degree - 1` of them, variable ones fill the whole block
+     * but the trailing record.
+     */
+    pub fn page_capacity(&self) -> u64 {
+        match BTreeCore::<K, T>::key_growth() {
+            0 => (2 * self.degree - 1) * BTreeRecord::<K>::get_size(),
+            _ => self.index_device.borrow().block_size - BTreeRecord::<K>::min_size(),
+        }
+    }
+
+    /*
+     * Least bytes a page but the root holds. For variable keys it leaves
+     * room for separators growing during deletion, see `split_for_delete`.
+     */
+    pub fn page_minimum(&self) -> u64 {
+        let record_size = BTreeRecord::<K>::get_size();
+        match BTreeCore::<K, T>::key_growth() {
+            0 => (self.degree - 1) * record_size,
+            growth => (self.page_capacity() - 5 * record_size - 2 * growth) / 2,
+        }
+    }
+
+    /*
+     * Full page might not take another record together with a grown separator
+     */
+    pub fn is_full(&self, page: &Page<BTreeRecord<K>>) -> bool {
+        let growth = BTreeCore::<K, T>::key_growth();
+        let record_size = BTreeRecord::<K>::get_size();
+        BTreeCore::<K, T>::weight(page) + record_size + growth > self.page_capacity()
+    }
+
+    /*
+     * Page with spare keys stays above the minimum after losing any of them
+     */
+    pub fn has_spare(&self, page: &Page<BTreeRecord<K>>) -> bool {
+        BTreeCore::<K, T>::weight(page) >= self.page_minimum() + BTreeRecord::<K>::get_size()
+    }
+
+    /*
+     * Page lacks headroom when replacing its separators during deletion could
+     * overflow it. Only keys of variable size ever grow.
+     */
+    pub fn lacks_headroom(&self, page: &Page<BTreeRecord<K>>) -> bool {
+        let growth = BTreeCore::<K, T>::key_growth();
+        growth > 0
+            && BTreeCore::<K, T>::weight(page) + BTreeRecord::<K>::get_size() + 2 * growth
+                > self.page_capacity()
+    }
+
+    /*
+     * Index of the record covering the middle byte of the keys, so both
+     * halves of a split end up holding about the same number of bytes
+     */
+    pub fn centre_index(page: &Page<BTreeRecord<K>>) -> usize {
+        let weight = BTreeCore::<K, T>::weight(page);
+        let mut prefix = 0;
+        page.records
+            .iter()
+            .position(|record| {
+                prefix += record.size();
+                2 * prefix >= weight
+            })
+            .expect("Tried to split an empty page")
+    }
+
+    /*
+     * Tree grows at the top, new root starts with a single trailing record
+     * pointing at the old one, which `split` divides with a new page
+     */
+    pub fn split_root(
+        &mut self,
+        mut page: Page<BTreeRecord<K>>,
+        split: SplitChild<K>,
+    ) -> Result<Page<BTreeRecord<K>>, std::io::Error> {
+        let lba = self.get_next_index_lba()?;
+        let mut root = Page::<BTreeRecord<K>>::empty(&self.index_device, lba, u64::MAX);
+        root.records.push(Box::new(BTreeRecord::<K> {
+            child_lba: Some(page.lba),
+            key: K::invalid(),
+            data_lba: 0,
+        }));
+
+        let new_lba = self.get_next_index_lba()?;
+        let mut new_page = Page::<BTreeRecord<K>>::empty(&self.index_device, new_lba, root.lba);
+        split(&mut root, &mut page, &mut new_page);
+
+        self.root_lba = root.lba;
+        Ok(root)
+    }
+
+    /*
+     * Splits the child under `index` before deletion enters it if it lacks
+     * headroom. Both halves have spare keys, `page` takes one record more,
+     * which its own headroom leaves room for.
+     */
+    pub fn split_for_delete(
+        &mut self,
+        page: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        split: SplitChild<K>,
+    ) -> Result<bool, std::io::Error> {
+        if BTreeCore::<K, T>::key_growth() == 0 {
+            return Ok(false);
+        }
+
+        let child_lba = page.records[index]
+            .child_lba
+            .expect("Non-leaf record without child!");
+        let mut child = self.load_page(child_lba, page.lba);
+        if !self.lacks_headroom(&child) {
+            return Ok(false);
+        }
+
+        let new_lba = self.get_next_index_lba()?;
+        let mut new_child = Page::<BTreeRecord<K>>::empty(&self.index_device, new_lba, page.lba);
+        split(page, &mut child, &mut new_child);
+        Ok(true)
+    }
+
+    pub fn read_data(&self, id: u64) -> Result<T, std::io::Error> {
+        T::from_bytes(self.heap.read(id)?)
+    }
+
+    /*
+     * Record of an index entry found by a lookup, `None` if there was none
+     */
+    pub fn read_found(
+        &self,
+        found: Option<BTreeRecord<K>>,
+    ) -> Result<Option<T>, std::io::Error> {
+        found.map(|record| self.read_data(record.data_lba)).transpose()
+    }
+
+    /*
+     * Records share blocks of the data device, `data_lba` of the index
+     * record holds the id of the record in the heap file
+     */
+    pub fn append_data(&mut self, record: &T) -> Result<u64, std::io::Error> {
+        let id = self.heap.insert(&record.get_bytes())?;
+        self.dirty = true;
+
+        Ok(id)
+    }
+
+    /*
+     * Freed pages are reused before the index file is extended
+     */
+    pub fn get_next_index_lba(&mut self) -> Result<u64, std::io::Error> {
+        if self.free_list_lba != 0 {
+            let ret = self.free_list_lba;
+            let bytes = self.index_device.borrow_mut().read(ret)?;
+
+            self.free_list_lba = LittleEndian::read_u64(&bytes[0..8]);
+            self.free_pages_count -= 1;
+            self.dirty = true;
+            return Ok(ret);
+        }
+
+        let ret = self.pages_count;
+        self.pages_count += 1;
+        self.dirty = true;
+        Ok(ret)
+    }
+
+    /*
+     * Freed pages form a list, each of them holds the LBA of the next one
+     * in its first bytes. Zero ends the list as it is taken by superblock.
+     */
+    pub fn free_page(&mut self, mut page: Page<BTreeRecord<K>>) -> Result<(), std::io::Error> {
+        page.records.clear();
+        page.dirty = false;
+
+        let mut index_device = self.index_device.borrow_mut();
+        let mut bytes = vec![0u8; index_device.block_size as usize];
+        LittleEndian::write_u64(&mut bytes[0..8], self.free_list_lba);
+        index_device.write(page.lba, &bytes)?;
+
+        self.free_list_lba = page.lba;
+        self.free_pages_count += 1;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn page_stats(&self) -> PageStats {
+        PageStats {
+            live_pages: self.pages_count - 1 - self.free_pages_count,
+            free_pages: self.free_pages_count,
+        }
+    }
+
+    /*
+     * Moves the last record of `left` through the separator under `index`
+     * in `parent` into the beginning of `right`
+     */
+    pub fn rotate_right(
+        parent: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        left: &mut Page<BTreeRecord<K>>,
+        right: &mut Page<BTreeRecord<K>>,
+    ) {
+        let separator = *parent.records[index];
+
+        let moved = if BTreeCore::<K, T>::is_leaf(left) {
+            let moved = left.records.pop().expect("Borrowing from an empty page!");
+            right.records.insert(0, Box::new(BTreeRecord::<K> {
+                child_lba: None,
+                ..separator
+            }));
+            moved
+        } else {
+            let moved = left.records.remove(left.records.len() - 2);
+            let trailing = left.records.last_mut().expect("Borrowing from an empty page!");
+            let moved_child = trailing.child_lba;
+            trailing.child_lba = moved.child_lba;
+            right.records.insert(0, Box::new(BTreeRecord::<K> {
+                child_lba: moved_child,
+                ..separator
+            }));
+            moved
+        };
+
+        parent.records[index].key = moved.key;
+        parent.records[index].data_lba = moved.data_lba;
+
+        parent.dirty = true;
+        left.dirty = true;
+        right.dirty = true;
+    }
+
+    /*
+     * Moves the first record of `right` through the separator under `index`
+     * in `parent` into the end of `left`
+     */
+    pub fn rotate_left(
+        parent: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        left: &mut Page<BTreeRecord<K>>,
+        right: &mut Page<BTreeRecord<K>>,
+    ) {
+        let separator = *parent.records[index];
+        let moved = right.records.remove(0);
+
+        if BTreeCore::<K, T>::is_leaf(left) {
+            left.records.push(Box::new(BTreeRecord::<K> {
+                child_lba: None,
+                ..separator
+            }));
+        } else {
+            let trailing = left.records.last_mut().expect("Non-leaf page without trailing record");
+            trailing.key = separator.key;
+            trailing.data_lba = separator.data_lba;
+            left.records.push(Box::new(BTreeRecord::<K> {
+                child_lba: moved.child_lba,
+                key: K::invalid(),
+                data_lba: 0,
+            }));
+        }
+
+        parent.records[index].key = moved.key;
+        parent.records[index].data_lba = moved.data_lba;
+
+        parent.dirty = true;
+        left.dirty = true;
+        right.dirty = true;
+    }
+
+    /*
+     * Appends separator under `index` and all records of `right` to `left`.
+     * `right` is left empty and is no longer referenced from `parent`.
+     */
+    pub fn merge_children(
+        parent: &mut Page<BTreeRecord<K>>,
+        index: usize,
+        left: &mut Page<BTreeRecord<K>>,
+        right: &mut Page<BTreeRecord<K>>,
+    ) {
+        let separator = parent.records.remove(index);
+
+        if BTreeCore::<K, T>::is_leaf(left) {
+            left.records.push(Box::new(BTreeRecord::<K> {
+                child_lba: None,
+                ..*separator
+            }));
+        } else {
+            let trailing = left.records.last_mut().expect("Non-leaf page without trailing record");
+            trailing.key = separator.key;
+            trailing.data_lba = separator.data_lba;
+        }
+
+        left.records.append(&mut right.records);
+        parent.records[index].child_lba = Some(left.lba);
+
+        parent.dirty = true;
+        left.dirty = true;
+        right.dirty = true;
+    }
+}
+
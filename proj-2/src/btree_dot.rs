@@ -0,0 +1,162 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use crate::{btree::BTree, btree_key::BTreeKey, record::Record};
+
+/*
+ * Step mode of the Graphviz export. Every operation writes the whole tree
+ * into `<prefix>_<step>.dot`, steps are counted from 1.
+ */
+pub struct DotSteps {
+    pub prefix: String,
+    pub step: u64,
+}
+
+impl DotSteps {
+    pub fn new(prefix: &str) -> Self {
+        DotSteps {
+            prefix: prefix.to_string(),
+            step: 0,
+        }
+    }
+}
+
+/*
+ * Characters with a meaning inside of a record label
+ */
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "{}|<>\"\\ ".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl<K: BTreeKey, T: Record> BTree<K, T> {
+    /*
+     * Every page becomes a record node: its LBA on top, keys below with a
+     * port before each key that has a child, edges go from that port to the
+     * child page.
+     */
+    pub fn to_dot<W: Write>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        self.write_dot(writer, None)
+    }
+
+    fn write_dot<W: Write>(&self, writer: &mut W, title: Option<&str>) -> Result<(), std::io::Error> {
+        writeln!(writer, "digraph btree {{")?;
+        writeln!(writer, "    node [shape=record, fontname=monospace];")?;
+        if let Some(title) = title {
+            writeln!(writer, "    label=\"{}\";", title.replace('"', "\\\""))?;
+            writeln!(writer, "    labelloc=t;")?;
+        }
+
+        let mut pages = vec![self.core.root_lba];
+        while let Some(lba) = pages.pop() {
+            let page = self.core.load_page(lba, u64::MAX);
+            let mut fields = vec![];
+            let mut edges = vec![];
+            let mut children = vec![];
+
+            for (index, record) in page.records.iter().enumerate() {
+                if let Some(child_lba) = record.child_lba {
+                    fields.push(format!("<c{}>", index));
+                    edges.push(format!("    page{}:c{} -> page{};", lba, index, child_lba));
+                    children.push(child_lba);
+                }
+                if record.key != K::invalid() {
+                    fields.push(escape(record.key.to_string().trim()));
+                }
+            }
+            drop(page);
+            pages.extend(children.iter().rev());
+
+            writeln!(writer, "    page{} [label=\"{{{}|{{{}}}}}\"];", lba, lba, fields.join("|"))?;
+            for edge in edges {
+                writeln!(writer, "{}", edge)?;
+            }
+        }
+
+        writeln!(writer, "}}")
+    }
+
+    /*
+     * Called after every operation, does nothing unless step mode is on
+     */
+    pub fn write_dot_step(&mut self, operation: &str) -> Result<(), std::io::Error> {
+        let (path, title) = match self.dot_steps.as_mut() {
+            Some(steps) => {
+                steps.step += 1;
+                (
+                    format!("{}_{:04}.dot", steps.prefix, steps.step),
+                    format!("step {}: {}", steps.step, operation),
+                )
+            }
+            None => return Ok(()),
+        };
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_dot(&mut writer, Some(&title))?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{btree_key::IntKey, record::IntRecord};
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    #[test]
+    fn test_to_dot() -> Result<(), std::io::Error> {
+        let mut btree = BTree::<IntKey, IntRecord>::create("test_to_dot.hex", 88, 0)?; // t = 2
+        for value in 0..5 {
+            btree.insert(IntKey { value }, record(value))?;
+        }
+
+        let mut output = vec![];
+        btree.to_dot(&mut output)?;
+        let dot = String::from_utf8(output).unwrap();
+        let lines = dot.lines().collect::<Vec<&str>>();
+
+        let root = btree.core.root_lba;
+        let root_line = format!("    page{} [label=\"{{{}|{{<c0>|1|<c1>}}}}\"];", root, root);
+        assert_eq!(lines.first(), Some(&"digraph btree {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        assert!(lines.contains(&root_line.as_str()));
+        assert_eq!(lines.iter().filter(|line| line.contains("[label=")).count(), 3);
+        assert_eq!(lines.iter().filter(|line| line.contains("->")).count(), 2);
+        assert!(lines[5].ends_with("|{0}}\"];"));
+        assert!(lines.iter().any(|line| line.ends_with("|{2|3|4}}\"];")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dot_steps() -> Result<(), std::io::Error> {
+        let prefix = "test_dot_steps";
+        let mut btree = BTree::<IntKey, IntRecord>::create("test_dot_steps.hex", 88, 0)?;
+
+        btree.insert(IntKey { value: 1 }, record(1))?;
+        btree.dot_steps = Some(DotSteps::new(prefix));
+        btree.insert(IntKey { value: 2 }, record(2))?;
+        btree.update(IntKey { value: 2 }, record(3))?;
+        btree.delete(IntKey { value: 1 })?;
+
+        assert_eq!(btree.dot_steps.as_ref().map(|steps| steps.step), Some(3));
+        let last = std::fs::read_to_string(format!("{}_0003.dot", prefix))?;
+        assert!(last.contains("label=\"step 3: delete 1\";"));
+        assert!(last.contains("|{2}}\"];"));
+        assert!(std::fs::read_to_string(format!("{}_0001.dot", prefix))?.contains("|{1|2}}"));
+
+        Ok(())
+    }
+}
@@ -1,5 +1,9 @@
-use byteorder::{LittleEndian, ByteOrder};
-use std::fmt::{Debug, Display};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::{
+    cmp::Ordering,
+    fmt::{Debug, Display},
+    ops::RangeInclusive,
+};
 
 pub trait BTreeKey: Ord + Copy + Debug + Display {
     fn is_valid(&self) -> bool;
@@ -9,6 +13,18 @@ pub trait BTreeKey: Ord + Copy + Debug + Display {
     fn invalid() -> Self;
     fn from_bytes(bytes: &[u8]) -> Self;
     fn get_size() -> u64;
+
+    /*
+     * Keys of variable length report their own encoded size, `get_size`
+     * bounds all of them
+     */
+    fn size(&self) -> u64 {
+        Self::get_size()
+    }
+
+    fn min_size() -> u64 {
+        Self::get_size()
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -18,11 +34,11 @@ pub struct IntKey {
 
 impl BTreeKey for IntKey {
     fn is_valid(&self) -> bool {
-        return self.value == i32::min_value()
+        self.value == i32::MIN
     }
 
     fn invalidate(&mut self) {
-        self.value = i32::min_value();
+        self.value = i32::MIN;
     }
 
     fn to_bytes(&self) -> Vec<u8> {
@@ -32,7 +48,7 @@ impl BTreeKey for IntKey {
     }
 
     fn invalid() -> Self{
-        IntKey {value: i32::min_value()}
+        IntKey {value: i32::MIN}
     }
 
     fn from_bytes(bytes: &[u8]) -> Self {
@@ -48,13 +64,297 @@ impl BTreeKey for IntKey {
 
 impl Display for IntKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.value != i32::min_value() {
+        if self.value != i32::MIN {
             write!(f, "{:>4}", self.value)
         } else {
             write!(f, "{:>4}", "*")
         }
     }
 }
+
+pub const STRING_KEY_MAX_LEN: usize = 40;
+const STRING_KEY_INVALID_LEN: u8 = u8::MAX;
+
+/*
+ * UTF-8 text of up to `STRING_KEY_MAX_LEN` bytes, stored in index pages as
+ * its length followed by the bytes themselves. Keys are ordered by their
+ * bytes, the invalid key goes before all of them.
+ */
+#[derive(Copy, Clone)]
+pub struct StringKey {
+    len: u8,
+    bytes: [u8; STRING_KEY_MAX_LEN],
+}
+
+impl StringKey {
+    pub fn new(text: &str) -> Result<Self, std::io::Error> {
+        if text.len() > STRING_KEY_MAX_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Key `{}` is longer than {} bytes", text, STRING_KEY_MAX_LEN),
+            ));
+        }
+
+        let mut key = StringKey {
+            len: text.len() as u8,
+            bytes: [0u8; STRING_KEY_MAX_LEN],
+        };
+        key.bytes[..text.len()].copy_from_slice(text.as_bytes());
+        Ok(key)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self.len {
+            STRING_KEY_INVALID_LEN => "",
+            len => std::str::from_utf8(&self.bytes[..len as usize]).unwrap_or_default(),
+        }
+    }
+}
+
+impl BTreeKey for StringKey {
+    fn is_valid(&self) -> bool {
+        self.len != STRING_KEY_INVALID_LEN
+    }
+
+    fn invalidate(&mut self) {
+        *self = StringKey::invalid();
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![self.len];
+        if self.is_valid() {
+            buf.extend_from_slice(&self.bytes[..self.len as usize]);
+        }
+        buf
+    }
+
+    fn invalid() -> Self {
+        StringKey {
+            len: STRING_KEY_INVALID_LEN,
+            bytes: [0u8; STRING_KEY_MAX_LEN],
+        }
+    }
+
+    /*
+     * Length that does not fit is treated as the invalid key, so garbage
+     * ends the page instead of being read past its end
+     */
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let len = bytes[0] as usize;
+        if len > STRING_KEY_MAX_LEN || len + 1 > bytes.len() {
+            return StringKey::invalid();
+        }
+
+        let mut key = StringKey {
+            len: len as u8,
+            bytes: [0u8; STRING_KEY_MAX_LEN],
+        };
+        key.bytes[..len].copy_from_slice(&bytes[1..1 + len]);
+        key
+    }
+
+    fn get_size() -> u64 {
+        1 + STRING_KEY_MAX_LEN as u64
+    }
+
+    fn size(&self) -> u64 {
+        match self.is_valid() {
+            true => 1 + self.len as u64,
+            false => 1,
+        }
+    }
+
+    fn min_size() -> u64 {
+        1
+    }
+}
+
+impl Ord for StringKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.is_valid(), other.is_valid()) {
+            (false, false) => Ordering::Equal,
+            (false, true) => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (true, true) => self.bytes[..self.len as usize].cmp(&other.bytes[..other.len as usize]),
+        }
+    }
+}
+
+impl PartialOrd for StringKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for StringKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for StringKey {}
+
+impl Debug for StringKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.is_valid() {
+            true => write!(f, "StringKey({:?})", self.as_str()),
+            false => write!(f, "StringKey(*)"),
+        }
+    }
+}
+
+impl Display for StringKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.is_valid() {
+            true => write!(f, "{:>4}", self.as_str()),
+            false => write!(f, "{:>4}", "*"),
+        }
+    }
+}
+
+/*
+ * Fixed-size column of a composite key. Encoded bytes compare the same way
+ * as the values, so signed integers are stored big-endian with their sign
+ * bit flipped.
+ */
+pub trait KeyPart: Ord + Copy + Debug + Display {
+    const SIZE: usize;
+
+    fn encode(&self, bytes: &mut [u8]);
+    fn decode(bytes: &[u8]) -> Self;
+    fn lowest() -> Self;
+    fn highest() -> Self;
+}
+
+macro_rules! key_part {
+    ($type:ty, $unsigned:ty, $write:ident, $read:ident, $flip:expr) => {
+        impl KeyPart for $type {
+            const SIZE: usize = std::mem::size_of::<$type>();
+
+            fn encode(&self, bytes: &mut [u8]) {
+                BigEndian::$write(bytes, (*self as $unsigned) ^ $flip);
+            }
+
+            fn decode(bytes: &[u8]) -> Self {
+                (BigEndian::$read(bytes) ^ $flip) as $type
+            }
+
+            fn lowest() -> Self {
+                <$type>::MIN
+            }
+
+            fn highest() -> Self {
+                <$type>::MAX
+            }
+        }
+    };
+}
+
+key_part!(u32, u32, write_u32, read_u32, 0);
+key_part!(i32, u32, write_u32, read_u32, 1 << 31);
+key_part!(u64, u64, write_u64, read_u64, 0);
+key_part!(i64, u64, write_u64, read_u64, 1 << 63);
+
+/*
+ * Pair of columns ordered lexicographically, more columns are built by
+ * nesting keys in the second one. Stored as a flag byte followed by the
+ * encoded columns, the invalid key has the flag cleared and goes before
+ * all of them.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct CompositeKey<A: KeyPart, B: KeyPart> {
+    valid: bool,
+    pub first: A,
+    pub second: B,
+}
+
+impl<A: KeyPart, B: KeyPart> CompositeKey<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        CompositeKey {
+            valid: true,
+            first,
+            second,
+        }
+    }
+
+    /*
+     * All keys starting with `first`, for range scans over the leading
+     * column
+     */
+    pub fn prefix(first: A) -> RangeInclusive<Self> {
+        CompositeKey::new(first, B::lowest())..=CompositeKey::new(first, B::highest())
+    }
+}
+
+impl<A: KeyPart, B: KeyPart> KeyPart for CompositeKey<A, B> {
+    const SIZE: usize = A::SIZE + B::SIZE;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        self.first.encode(&mut bytes[..A::SIZE]);
+        self.second.encode(&mut bytes[A::SIZE..Self::SIZE]);
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        CompositeKey::new(A::decode(&bytes[..A::SIZE]), B::decode(&bytes[A::SIZE..Self::SIZE]))
+    }
+
+    fn lowest() -> Self {
+        CompositeKey::new(A::lowest(), B::lowest())
+    }
+
+    fn highest() -> Self {
+        CompositeKey::new(A::highest(), B::highest())
+    }
+}
+
+impl<A: KeyPart, B: KeyPart> BTreeKey for CompositeKey<A, B> {
+    fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    fn invalidate(&mut self) {
+        *self = CompositeKey::invalid();
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; Self::get_size() as usize];
+        buf[0] = self.valid as u8;
+        self.encode(&mut buf[1..]);
+        buf
+    }
+
+    fn invalid() -> Self {
+        CompositeKey {
+            valid: false,
+            first: A::lowest(),
+            second: B::lowest(),
+        }
+    }
+
+    /*
+     * Any flag other than 1 reads as the invalid key, zeroed bytes included
+     */
+    fn from_bytes(bytes: &[u8]) -> Self {
+        match bytes[0] {
+            1 => CompositeKey::decode(&bytes[1..]),
+            _ => CompositeKey::invalid(),
+        }
+    }
+
+    fn get_size() -> u64 {
+        1 + Self::SIZE as u64
+    }
+}
+
+impl<A: KeyPart, B: KeyPart> Display for CompositeKey<A, B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.valid {
+            true => write!(f, "({}, {})", self.first, self.second),
+            false => write!(f, "{:>4}", "*"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,4 +382,83 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_string_key_bytes() -> Result<(), std::io::Error> {
+        let key = StringKey::new("abc")?;
+
+        assert_eq!(key.to_bytes(), [3u8, b'a', b'b', b'c']);
+        assert_eq!(key.size(), 4);
+        assert_eq!(StringKey::from_bytes(&[3u8, b'a', b'b', b'c', 7, 7]), key);
+        assert_eq!(StringKey::new("")?.to_bytes(), [0u8]);
+
+        assert_eq!(StringKey::invalid().to_bytes(), [u8::MAX]);
+        assert!(!StringKey::from_bytes(&[u8::MAX, 0]).is_valid());
+        assert!(!StringKey::from_bytes(&[5u8, b'a']).is_valid());
+        assert!(StringKey::new(&"x".repeat(STRING_KEY_MAX_LEN + 1)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_key_order() -> Result<(), std::io::Error> {
+        let mut keys = ["b", "ab", "", "abc", "a"]
+            .iter()
+            .map(|text| StringKey::new(text))
+            .collect::<Result<Vec<StringKey>, std::io::Error>>()?;
+        keys.push(StringKey::invalid());
+        keys.sort();
+
+        let texts = keys.iter().map(|key| key.to_string()).collect::<Vec<String>>();
+        assert_eq!(texts, ["   *", "    ", "   a", "  ab", " abc", "   b"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_composite_key_bytes() -> Result<(), std::io::Error> {
+        let key = CompositeKey::new(7u32, -2i64);
+
+        assert_eq!(key.to_bytes(), [1u8, 0, 0, 0, 7, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(CompositeKey::<u32, i64>::get_size(), 13);
+        assert_eq!(CompositeKey::from_bytes(&key.to_bytes()), key);
+
+        assert_eq!(CompositeKey::<u32, i64>::invalid().to_bytes()[0], 0);
+        assert_eq!(CompositeKey::<u32, i64>::from_bytes(&[0u8; 13]), CompositeKey::invalid());
+
+        let nested = CompositeKey::new(-1i32, CompositeKey::new(2u64, 3u32));
+        assert_eq!(CompositeKey::from_bytes(&nested.to_bytes()), nested);
+        assert_eq!(nested.to_string(), "(-1, (2, 3))");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_composite_key_order() -> Result<(), std::io::Error> {
+        let values = [i32::MIN, -300, -1, 0, 1, 255, 256, i32::MAX];
+        let mut keys = vec![CompositeKey::invalid()];
+        for first in values {
+            for second in values {
+                keys.push(CompositeKey::new(first, second as i64 * 3));
+            }
+        }
+
+        /*
+         * Bytes sort the same way as the keys themselves
+         */
+        let mut by_key = keys.clone();
+        by_key.sort();
+        let mut by_bytes = keys.clone();
+        by_bytes.sort_by_key(|key| key.to_bytes());
+        assert_eq!(by_key, by_bytes);
+        assert_eq!(by_key[0], CompositeKey::invalid());
+        assert_eq!(by_key[1], CompositeKey::new(i32::MIN, i32::MIN as i64 * 3));
+        assert!(CompositeKey::new(-1, i64::MAX) < CompositeKey::new(0, i64::MIN));
+
+        let prefix = CompositeKey::<i32, i64>::prefix(-1);
+        assert!(by_key.iter().filter(|key| prefix.contains(key)).all(|key| key.first == -1));
+        assert_eq!(by_key.iter().filter(|key| prefix.contains(key)).count(), values.len());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,472 @@
+use std::ops::Bound;
+
+use crate::{
+    btree::BTree,
+    btree_core::BTreeCore,
+    btree_key::{BTreeKey, CompositeKey, KeyPart},
+    btree_record::BTreeRecord,
+    record::Record,
+};
+
+/*
+ * Page visited by the scan together with the position of the scan in it.
+ * For non-leaf pages `index` points at the record whose child is being
+ * visited, for leafs at the next record to return.
+ */
+struct Frame<K: BTreeKey> {
+    records: Vec<BTreeRecord<K>>,
+    index: usize,
+    leaf: bool,
+}
+
+/*
+ * Ordered scan over keys within bounds, walks pages with two explicit stacks
+ * of visited pages - one for each end of the range. Both ends remember the
+ * data record they returned last, the scan is over once one of them meets
+ * the record of the other, as equal keys of a multimap cannot tell that.
+ * A data record that cannot be read is returned as an error and ends the
+ * scan.
+ */
+pub struct BTreeRange<'a, K: BTreeKey, T: Record> {
+    btree: &'a BTree<K, T>,
+    lower: Bound<K>,
+    upper: Bound<K>,
+    front: Option<Vec<Frame<K>>>,
+    back: Option<Vec<Frame<K>>>,
+    front_id: Option<u64>,
+    back_id: Option<u64>,
+    finished: bool,
+    pub index_reads: u64,
+    pub data_reads: u64,
+}
+
+impl<'a, K: BTreeKey, T: Record> BTreeRange<'a, K, T> {
+    pub fn new(btree: &'a BTree<K, T>, lower: Bound<K>, upper: Bound<K>) -> Self {
+        BTreeRange {
+            btree,
+            lower,
+            upper,
+            front: None,
+            back: None,
+            front_id: None,
+            back_id: None,
+            finished: false,
+            index_reads: 0,
+            data_reads: 0,
+        }
+    }
+
+    fn load_frame(&mut self, lba: u64) -> Frame<K> {
+        let page = self.btree.core.load_page(lba, u64::MAX);
+        self.index_reads += 1;
+
+        Frame {
+            records: page.records.iter().map(|record| **record).collect(),
+            index: 0,
+            leaf: BTreeCore::<K, T>::is_leaf(&page),
+        }
+    }
+
+    fn below_upper(&self, key: K) -> bool {
+        match self.upper {
+            Bound::Included(upper) => key <= upper,
+            Bound::Excluded(upper) => key < upper,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn above_lower(&self, key: K) -> bool {
+        match self.lower {
+            Bound::Included(lower) => key >= lower,
+            Bound::Excluded(lower) => key > lower,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /*
+     * Descends from `lba` to the leaf holding the first key of the subtree
+     * that is not below the lower bound
+     */
+    fn descend_front(&mut self, stack: &mut Vec<Frame<K>>, lba: u64) {
+        let mut lba = Some(lba);
+
+        while let Some(current) = lba {
+            let mut frame = self.load_frame(current);
+            frame.index = frame
+                .records
+                .iter()
+                .position(|record| record.key == K::invalid() || self.above_lower(record.key))
+                .unwrap_or(frame.records.len());
+
+            lba = match frame.leaf {
+                true => None,
+                false => frame.records[frame.index].child_lba,
+            };
+            stack.push(frame);
+        }
+    }
+
+    /*
+     * Descends from `lba` to the leaf holding the last key of the subtree
+     * that is not above the upper bound
+     */
+    fn descend_back(&mut self, stack: &mut Vec<Frame<K>>, lba: u64) {
+        let mut lba = Some(lba);
+
+        while let Some(current) = lba {
+            let mut frame = self.load_frame(current);
+            frame.index = frame
+                .records
+                .iter()
+                .position(|record| record.key == K::invalid() || !self.below_upper(record.key))
+                .unwrap_or(frame.records.len());
+
+            lba = match frame.leaf {
+                true => None,
+                false => frame.records[frame.index].child_lba,
+            };
+            stack.push(frame);
+        }
+    }
+
+    fn next_front_record(&mut self) -> Option<BTreeRecord<K>> {
+        let mut stack = match self.front.take() {
+            Some(stack) => stack,
+            None => {
+                let mut stack = vec![];
+                self.descend_front(&mut stack, self.btree.core.root_lba);
+                stack
+            }
+        };
+
+        let mut found = None;
+        while let Some(frame) = stack.last_mut() {
+            if frame.leaf {
+                if frame.index < frame.records.len() {
+                    frame.index += 1;
+                    found = Some(frame.records[frame.index - 1]);
+                    break;
+                }
+                stack.pop();
+                continue;
+            }
+
+            /*
+             * Child under `index` is done, the record itself comes next
+             * followed by the subtree on its right
+             */
+            let record = frame.records[frame.index];
+            if record.key == K::invalid() {
+                stack.pop();
+                continue;
+            }
+
+            frame.index += 1;
+            let child_lba = frame.records[frame.index]
+                .child_lba
+                .expect("Non-leaf record without child!");
+            self.descend_leftmost(&mut stack, child_lba);
+            found = Some(record);
+            break;
+        }
+
+        self.front = Some(stack);
+        found
+    }
+
+    fn next_back_record(&mut self) -> Option<BTreeRecord<K>> {
+        let mut stack = match self.back.take() {
+            Some(stack) => stack,
+            None => {
+                let mut stack = vec![];
+                self.descend_back(&mut stack, self.btree.core.root_lba);
+                stack
+            }
+        };
+
+        let mut found = None;
+        while let Some(frame) = stack.last_mut() {
+            if frame.leaf {
+                if frame.index > 0 {
+                    frame.index -= 1;
+                    found = Some(frame.records[frame.index]);
+                    break;
+                }
+                stack.pop();
+                continue;
+            }
+
+            /*
+             * Child under `index` is done, the record on its left comes next
+             * followed by the subtree on the left of that record
+             */
+            if frame.index == 0 {
+                stack.pop();
+                continue;
+            }
+
+            frame.index -= 1;
+            let record = frame.records[frame.index];
+            let child_lba = record.child_lba.expect("Non-leaf record without child!");
+            self.descend_rightmost(&mut stack, child_lba);
+            found = Some(record);
+            break;
+        }
+
+        self.back = Some(stack);
+        found
+    }
+
+    fn descend_leftmost(&mut self, stack: &mut Vec<Frame<K>>, lba: u64) {
+        let mut lba = Some(lba);
+
+        while let Some(current) = lba {
+            let frame = self.load_frame(current);
+            lba = match frame.leaf {
+                true => None,
+                false => frame.records[0].child_lba,
+            };
+            stack.push(frame);
+        }
+    }
+
+    fn descend_rightmost(&mut self, stack: &mut Vec<Frame<K>>, lba: u64) {
+        let mut lba = Some(lba);
+
+        while let Some(current) = lba {
+            let mut frame = self.load_frame(current);
+            if frame.leaf {
+                frame.index = frame.records.len();
+                lba = None;
+            } else {
+                frame.index = frame.records.len() - 1;
+                lba = frame.records[frame.index].child_lba;
+            }
+            stack.push(frame);
+        }
+    }
+
+    fn read_record(&mut self, record: BTreeRecord<K>) -> Result<(K, T), std::io::Error> {
+        self.data_reads += 1;
+        match self.btree.core.read_data(record.data_lba) {
+            Ok(data) => Ok((record.key, data)),
+            Err(error) => {
+                self.finished = true;
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<K: BTreeKey, T: Record> Iterator for BTreeRange<'_, K, T> {
+    type Item = Result<(K, T), std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.next_front_record() {
+            Some(record)
+                if self.below_upper(record.key)
+                    && self.back_id != Some(record.data_lba) =>
+            {
+                self.front_id = Some(record.data_lba);
+                Some(self.read_record(record))
+            }
+            _ => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+impl<K: BTreeKey, T: Record> DoubleEndedIterator for BTreeRange<'_, K, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.next_back_record() {
+            Some(record)
+                if self.above_lower(record.key)
+                    && self.front_id != Some(record.data_lba) =>
+            {
+                self.back_id = Some(record.data_lba);
+                Some(self.read_record(record))
+            }
+            _ => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+impl<A: KeyPart, B: KeyPart, T: Record> BTree<CompositeKey<A, B>, T> {
+    /*
+     * Keys sharing the leading column, ordered by the rest of them
+     */
+    pub fn range_prefix(&self, first: A) -> BTreeRange<'_, CompositeKey<A, B>, T> {
+        self.range(CompositeKey::prefix(first))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{btree_key::IntKey, heap::HeapPage, record::IntRecord};
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    fn keys(range: impl Iterator<Item = Result<(IntKey, IntRecord), std::io::Error>>) -> Vec<i32> {
+        range
+            .map(|item| {
+                let (key, data) = item.unwrap();
+                assert_eq!(data.get_bytes(), record(key.value).get_bytes());
+                key.value
+            })
+            .collect()
+    }
+
+    fn build(name: &str, count: i32) -> BTree<IntKey, IntRecord> {
+        let mut btree = BTree::<IntKey, IntRecord>::create(name, 88, 0).unwrap(); // t = 2
+        for value in (0..count).rev() {
+            btree.insert(IntKey { value: value * 2 }, record(value * 2)).unwrap();
+        }
+        btree
+    }
+
+    #[test]
+    fn test_range_bounds() -> Result<(), std::io::Error> {
+        let btree = build("test_range_bounds.hex", 50);
+        let key = |value| IntKey { value };
+
+        assert_eq!(keys(btree.range(..)), (0..50).map(|x| x * 2).collect::<Vec<i32>>());
+        assert_eq!(keys(btree.range(key(10)..key(20))), vec![10, 12, 14, 16, 18]);
+        assert_eq!(keys(btree.range(key(11)..=key(20))), vec![12, 14, 16, 18, 20]);
+        assert_eq!(keys(btree.range(key(95)..)), vec![96, 98]);
+        assert_eq!(keys(btree.range(..key(5))), vec![0, 2, 4]);
+        assert_eq!(
+            keys(btree.range((Bound::Excluded(key(10)), Bound::Excluded(key(16))))),
+            vec![12, 14]
+        );
+        assert_eq!(keys(btree.range(key(200)..)), Vec::<i32>::new());
+        assert_eq!(keys(btree.range(key(20)..key(20))), Vec::<i32>::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_reverse() -> Result<(), std::io::Error> {
+        let btree = build("test_range_reverse.hex", 50);
+        let key = |value| IntKey { value };
+
+        assert_eq!(keys(btree.range(..).rev()), (0..50).rev().map(|x| x * 2).collect::<Vec<i32>>());
+        assert_eq!(keys(btree.range(key(11)..=key(20)).rev()), vec![20, 18, 16, 14, 12]);
+        assert_eq!(keys(btree.range(key(10)..key(20)).rev()), vec![18, 16, 14, 12, 10]);
+        assert_eq!(keys(btree.range(..key(5)).rev()), vec![4, 2, 0]);
+
+        /*
+         * Both ends of the same scan meet in the middle without repeating keys
+         */
+        let mut range = btree.range(key(10)..=key(20));
+        let mut values = vec![];
+        while let Some((front, _)) = range.next().transpose()? {
+            values.push(front.value);
+            if let Some((back, _)) = range.next_back().transpose()? {
+                values.push(back.value);
+            }
+        }
+        assert_eq!(values, vec![10, 20, 12, 18, 14, 16]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_reads() -> Result<(), std::io::Error> {
+        let btree = build("test_range_reads.hex", 50);
+
+        let mut range = btree.range(..);
+        assert_eq!(range.by_ref().count(), 50);
+
+        /*
+         * Every live page is read exactly once during a full scan
+         */
+        let live_pages = btree.core.pages_count - 1;
+        assert_eq!(range.index_reads, live_pages);
+        assert_eq!(range.data_reads, 50);
+
+        let mut range = btree.range(IntKey { value: 40 }..=IntKey { value: 40 });
+        assert_eq!(range.by_ref().count(), 1);
+        assert_eq!(range.data_reads, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_prefix() -> Result<(), std::io::Error> {
+        let mut tree = BTree::<CompositeKey<u32, i64>, IntRecord>::create("test_range_prefix.hex", 30 * 4, 0)?; // t = 2
+        for customer in (1..=5u32).rev() {
+            for timestamp in [300i64, -20, 7, 1 << 40] {
+                tree.insert(CompositeKey::new(customer, timestamp), record(customer as i32))?;
+            }
+        }
+
+        let scanned = |range: BTreeRange<'_, CompositeKey<u32, i64>, IntRecord>| {
+            range
+                .map(|item| {
+                    let (key, data) = item.unwrap();
+                    assert_eq!(data.get_bytes(), record(key.first as i32).get_bytes());
+                    (key.first, key.second)
+                })
+                .collect::<Vec<(u32, i64)>>()
+        };
+        assert_eq!(
+            scanned(tree.range_prefix(3)),
+            vec![(3, -20), (3, 7), (3, 300), (3, 1 << 40)]
+        );
+        assert_eq!(
+            tree.range_prefix(5)
+                .rev()
+                .map(|item| item.map(|(key, _)| key.second))
+                .collect::<Result<Vec<i64>, std::io::Error>>()?,
+            vec![1 << 40, 300, 7, -20]
+        );
+        assert_eq!(scanned(tree.range_prefix(0)), vec![]);
+        assert_eq!(scanned(tree.range_prefix(6)), vec![]);
+        assert_eq!(tree.range(..).count(), 20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_data_error() -> Result<(), std::io::Error> {
+        let mut btree = build("test_range_data_error.hex", 20);
+
+        /*
+         * Every record takes a data block of its own, in the order of
+         * insertion, so the wiped block held the record of key 20
+         */
+        btree.core.heap.write_page(9, &HeapPage::new(vec![0u8; 88]))?;
+        let items = btree.range(..).collect::<Vec<_>>();
+        assert_eq!(items.len(), 11);
+        assert!(items[..10].iter().all(|item| item.is_ok()));
+        assert!(items[10].is_err());
+
+        let mut range = btree.range(..).rev();
+        assert_eq!(range.by_ref().take_while(|item| item.is_ok()).count(), 9);
+        assert!(range.next().is_none());
+
+        // Point lookups tell the unreadable record from a missing key
+        assert!(btree.get(IntKey { value: 20 }).is_err());
+        assert!(btree.get(IntKey { value: 21 })?.is_none());
+        assert!(btree.get(IntKey { value: 18 })?.is_some());
+
+        Ok(())
+    }
+}
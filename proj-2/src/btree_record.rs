@@ -3,6 +3,11 @@ use std::fmt::Display;
 use crate::{btree_key::BTreeKey, bytes::Bytes};
 use byteorder::{ByteOrder, LittleEndian};
 
+/*
+ * Child flag, child LBA and data LBA in front of the key
+ */
+const BTREE_RECORD_HEADER_SIZE: u64 = 1 + 2 * std::mem::size_of::<u64>() as u64;
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct BTreeRecord<K: BTreeKey> {
     pub child_lba: Option<u64>,
@@ -22,7 +27,7 @@ impl<K: BTreeKey> Bytes for BTreeRecord<K> {
     fn from_bytes(bytes: &[u8]) -> Self {
         let mut record = BTreeRecord::<K> {
             child_lba: None,
-            key: K::from_bytes(&bytes[17..].to_vec()),
+            key: K::from_bytes(&bytes[17..]),
             data_lba: LittleEndian::read_u64(&bytes[9..9 + 8]),
         };
 
@@ -34,7 +39,7 @@ impl<K: BTreeKey> Bytes for BTreeRecord<K> {
     }
 
     fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![0u8; Self::get_size() as usize];
+        let mut bytes = vec![0u8; self.size() as usize];
 
         if let Some(child_lba) = self.child_lba {
             bytes[0] = 1;
@@ -51,7 +56,15 @@ impl<K: BTreeKey> Bytes for BTreeRecord<K> {
     }
 
     fn get_size() -> u64 {
-        1 + (2 * std::mem::size_of::<u64>()) as u64 + K::get_size()
+        BTREE_RECORD_HEADER_SIZE + K::get_size()
+    }
+
+    fn size(&self) -> u64 {
+        BTREE_RECORD_HEADER_SIZE + self.key.size()
+    }
+
+    fn min_size() -> u64 {
+        BTREE_RECORD_HEADER_SIZE + K::min_size()
     }
 }
 
@@ -80,7 +93,7 @@ mod tests {
         let record = BTreeRecord {
             child_lba: Some(0xDEADBEEFAAAABBBB),
             data_lba: 0xFFEEFFEEFFEEFFEE,
-            key: key,
+            key,
         };
 
         let bytes = record.to_bytes();
@@ -102,7 +115,7 @@ mod tests {
         let record = BTreeRecord {
             child_lba: Some(0xDEADBEEFAAAABBBB),
             data_lba: 0xFFEEFFEEFFEEFFEE,
-            key: key,
+            key,
         };
 
         let bytes = vec![
@@ -123,7 +136,7 @@ mod tests {
         let record = BTreeRecord {
             child_lba: None,
             data_lba: 0xFFEEFFEEFFEEFFEE,
-            key: key,
+            key,
         };
 
         let bytes = vec![
@@ -143,7 +156,7 @@ mod tests {
         let record = BTreeRecord {
             child_lba: Some(0xDEADBEEFAAAABBBB),
             data_lba: 0xFFEEFFEEFFEEFFEE,
-            key: key,
+            key,
         };
 
         let invalid_record = BTreeRecord::<IntKey>::invalid();
@@ -159,7 +172,7 @@ mod tests {
         let record = BTreeRecord {
             child_lba: Some(0xDEADBEEFAAAABBBB),
             data_lba: 0,
-            key: key,
+            key,
         };
 
         let invalid_record = BTreeRecord::<IntKey>::invalid();
@@ -0,0 +1,201 @@
+use std::fmt::Display;
+
+use crate::{
+    btree::{BTree, InsertPolicy},
+    btree_core::BTreeCore,
+    btree_key::BTreeKey,
+    btree_record::BTreeRecord,
+    bytes::Bytes,
+    record::Record,
+};
+
+/*
+ * Shape of the tree and how full its pages are. Occupancy of a page is the
+ * bytes of its keys relative to `2 * degree` records of the largest size,
+ * which for keys of fixed size is their number relative to `2 * degree`.
+ * Sizes are in bytes.
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct TreeStats {
+    pub degree: u64,
+    pub insert_policy: InsertPolicy,
+    pub height: u64,
+    pub pages_per_level: Vec<u64>,
+    pub keys: u64,
+    pub min_occupancy: f64,
+    pub avg_occupancy: f64,
+    pub max_occupancy: f64,
+    pub index_file_size: u64,
+    pub data_file_size: u64,
+    pub live_records: u64,
+}
+
+impl TreeStats {
+    pub fn csv_header() -> &'static str {
+        "degree,insert_policy,height,pages_per_level,keys,min_occupancy,avg_occupancy,max_occupancy,index_file_size,data_file_size,live_records"
+    }
+
+    /*
+     * Pages per level are separated with `;` to keep a single column
+     */
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{:?},{},{},{},{:.4},{:.4},{:.4},{},{},{}",
+            self.degree,
+            self.insert_policy,
+            self.height,
+            self.pages_per_level
+                .iter()
+                .map(|pages| pages.to_string())
+                .collect::<Vec<String>>()
+                .join(";"),
+            self.keys,
+            self.min_occupancy,
+            self.avg_occupancy,
+            self.max_occupancy,
+            self.index_file_size,
+            self.data_file_size,
+            self.live_records,
+        )
+    }
+}
+
+impl Display for TreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "degree {}, {:?} policy", self.degree, self.insert_policy)?;
+        writeln!(
+            f,
+            "height {}, pages per level {:?}, {} keys",
+            self.height, self.pages_per_level, self.keys
+        )?;
+        writeln!(
+            f,
+            "occupancy min {:.2}, avg {:.2}, max {:.2}",
+            self.min_occupancy, self.avg_occupancy, self.max_occupancy
+        )?;
+        write!(
+            f,
+            "index file {} B, data file {} B, {} live records",
+            self.index_file_size, self.data_file_size, self.live_records
+        )
+    }
+}
+
+impl<K: BTreeKey, T: Record> BTree<K, T> {
+    /*
+     * Visits every page of the tree once, level by level
+     */
+    pub fn stats(&mut self) -> Result<TreeStats, std::io::Error> {
+        let capacity = (2 * self.core.degree * BTreeRecord::<K>::get_size()) as f64;
+        let mut pages_per_level = vec![];
+        let mut occupancies = vec![];
+        let mut keys = 0;
+
+        let mut level = vec![self.core.root_lba];
+        while !level.is_empty() {
+            let mut next_level = vec![];
+
+            for lba in &level {
+                let page = self.core.load_page(*lba, u64::MAX);
+                keys += page
+                    .records
+                    .iter()
+                    .filter(|record| record.key != K::invalid())
+                    .count() as u64;
+                occupancies.push(BTreeCore::<K, T>::weight(&page) as f64 / capacity);
+                next_level.extend(page.records.iter().filter_map(|record| record.child_lba));
+            }
+
+            pages_per_level.push(level.len() as u64);
+            level = next_level;
+        }
+
+        let usage = self.core.heap.usage()?;
+
+        Ok(TreeStats {
+            degree: self.core.degree,
+            insert_policy: self.insert_policy,
+            height: pages_per_level.len() as u64,
+            pages_per_level,
+            keys,
+            min_occupancy: occupancies.iter().cloned().fold(f64::INFINITY, f64::min),
+            avg_occupancy: occupancies.iter().sum::<f64>() / occupancies.len() as f64,
+            max_occupancy: occupancies.iter().cloned().fold(0.0, f64::max),
+            index_file_size: self.core.pages_count * self.core.index_device.borrow().block_size,
+            data_file_size: self.core.heap.pages_count * self.core.data_device.borrow().block_size,
+            live_records: usage.live_records,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{btree_key::IntKey, record::IntRecord};
+
+    use super::*;
+
+    fn record(value: i32) -> IntRecord {
+        IntRecord::from_string(format!("1 {}", value)).unwrap()
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), std::io::Error> {
+        let mut btree = BTree::<IntKey, IntRecord>::create("test_stats.hex", 88, 0)?; // t = 2
+
+        let stats = btree.stats()?;
+        assert_eq!(stats.height, 1);
+        assert_eq!(stats.pages_per_level, vec![1]);
+        assert_eq!(stats.keys, 0);
+        assert_eq!(stats.max_occupancy, 0.0);
+
+        for value in 0..10 {
+            btree.insert(IntKey { value }, record(value))?;
+        }
+        btree.delete(IntKey { value: 4 })?;
+
+        let stats = btree.stats()?;
+        assert_eq!(stats.keys, 9);
+        assert_eq!(stats.live_records, 9);
+        assert_eq!(stats.height as usize, stats.pages_per_level.len());
+        assert_eq!(
+            stats.pages_per_level.iter().sum::<u64>(),
+            btree.core.page_stats().live_pages
+        );
+        assert!(stats.min_occupancy >= 0.25 || stats.pages_per_level == vec![1]);
+        assert!(stats.min_occupancy <= stats.avg_occupancy);
+        assert!(stats.avg_occupancy <= stats.max_occupancy);
+        assert!(stats.max_occupancy <= 0.75);
+        assert_eq!(stats.index_file_size, btree.core.pages_count * 88);
+        assert_eq!(stats.data_file_size, btree.core.heap.pages_count * 88);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_csv() -> Result<(), std::io::Error> {
+        let stats = TreeStats {
+            degree: 2,
+            insert_policy: InsertPolicy::Compensation,
+            height: 3,
+            pages_per_level: vec![1, 2, 5],
+            keys: 12,
+            min_occupancy: 0.25,
+            avg_occupancy: 0.375,
+            max_occupancy: 0.75,
+            index_file_size: 756,
+            data_file_size: 1008,
+            live_records: 12,
+        };
+
+        assert_eq!(
+            stats.to_csv(),
+            "2,Compensation,3,1;2;5,12,0.2500,0.3750,0.7500,756,1008,12"
+        );
+        assert_eq!(
+            TreeStats::csv_header().split(',').count(),
+            stats.to_csv().split(',').count()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use crate::device::BlockDevice;
+
+struct Frame {
+    bytes: Vec<u8>,
+    pin_count: u64,
+    dirty: bool,
+    last_used: u64,
+}
+
+/*
+ * Bounded cache of blocks sitting between `Page` and `BlockDevice`. Pinned
+ * frames are never evicted, unpinned ones leave in least recently used
+ * order and are written back only if they are dirty. With capacity 0 every
+ * block leaves the pool as soon as it is unpinned. While `hold_dirty` is set
+ * dirty frames are not evicted either, they stay until `flush_all`.
+ */
+pub struct BufferPool {
+    pub device: BlockDevice,
+    pub block_size: u64,
+    pub capacity: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub hold_dirty: bool,
+    frames: HashMap<u64, Frame>,
+    tick: u64,
+}
+
+impl BufferPool {
+    pub fn new(device: BlockDevice, capacity: u64) -> Self {
+        BufferPool {
+            block_size: device.block_size,
+            device,
+            capacity,
+            hits: 0,
+            misses: 0,
+            hold_dirty: false,
+            frames: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: u64) -> Result<(), std::io::Error> {
+        self.capacity = capacity;
+        self.evict()
+    }
+
+    fn touch(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /*
+     * Brings block into the pool and keeps it there until `unpin`
+     */
+    pub fn pin(&mut self, lba: u64) -> Result<Vec<u8>, std::io::Error> {
+        let tick = self.touch();
+
+        if let Some(frame) = self.frames.get_mut(&lba) {
+            self.hits += 1;
+            frame.pin_count += 1;
+            frame.last_used = tick;
+            return Ok(frame.bytes.clone());
+        }
+
+        self.misses += 1;
+        let bytes = self.device.read(lba)?;
+        self.frames.insert(
+            lba,
+            Frame {
+                bytes: bytes.clone(),
+                pin_count: 1,
+                dirty: false,
+                last_used: tick,
+            },
+        );
+
+        Ok(bytes)
+    }
+
+    /*
+     * Releases block pinned by `pin`, new contents of the block mark it dirty
+     */
+    pub fn unpin(&mut self, lba: u64, bytes: Option<Vec<u8>>) -> Result<(), std::io::Error> {
+        if let Some(frame) = self.frames.get_mut(&lba) {
+            frame.pin_count = frame.pin_count.saturating_sub(1);
+        }
+
+        match bytes {
+            Some(bytes) => self.write(lba, &bytes),
+            None => self.evict(),
+        }
+    }
+
+    pub fn read(&mut self, lba: u64) -> Result<Vec<u8>, std::io::Error> {
+        let bytes = self.pin(lba)?;
+        self.unpin(lba, None)?;
+        Ok(bytes)
+    }
+
+    /*
+     * Block that is not cached yet enters the pool on its first write
+     */
+    pub fn write(&mut self, lba: u64, buf: &[u8]) -> Result<(), std::io::Error> {
+        if buf.len() != self.block_size as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Trying to write a buffer with a size different than devices blocksize",
+            ));
+        }
+
+        let tick = self.touch();
+        let frame = self.frames.entry(lba).or_insert(Frame {
+            bytes: vec![],
+            pin_count: 0,
+            dirty: true,
+            last_used: tick,
+        });
+        frame.bytes = buf.to_vec();
+        frame.dirty = true;
+        frame.last_used = tick;
+
+        self.evict()
+    }
+
+    fn evict(&mut self) -> Result<(), std::io::Error> {
+        while self.frames.len() as u64 > self.capacity {
+            let victim = self
+                .frames
+                .iter()
+                .filter(|(_, frame)| frame.pin_count == 0 && !(self.hold_dirty && frame.dirty))
+                .min_by_key(|(_, frame)| frame.last_used)
+                .map(|(lba, _)| *lba);
+
+            let lba = match victim {
+                Some(lba) => lba,
+                None => break,
+            };
+
+            let frame = self.frames.remove(&lba).unwrap();
+            if frame.dirty {
+                self.device.write(lba, &frame.bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Contents of every dirty block in order of their LBAs
+     */
+    pub fn dirty_frames(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut dirty = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(lba, frame)| (*lba, frame.bytes.clone()))
+            .collect::<Vec<(u64, Vec<u8>)>>();
+        dirty.sort();
+
+        dirty
+    }
+
+    /*
+     * Writes back every dirty block, blocks stay cached as long as the pool
+     * is within its capacity
+     */
+    pub fn flush_all(&mut self) -> Result<(), std::io::Error> {
+        for (lba, bytes) in self.dirty_frames() {
+            self.device.write(lba, &bytes)?;
+            self.frames.get_mut(&lba).unwrap().dirty = false;
+        }
+
+        self.evict()
+    }
+
+    pub fn cached_count(&self) -> u64 {
+        self.frames.len() as u64
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        self.flush_all().expect("Could not write into device on flush!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(name: &str, capacity: u64) -> BufferPool {
+        let device = BlockDevice::new(name.to_string(), 8, true).unwrap();
+        BufferPool::new(device, capacity)
+    }
+
+    #[test]
+    fn test_hits_and_misses() -> Result<(), std::io::Error> {
+        let mut pool = pool("test_hits_and_misses.hex", 2);
+
+        pool.write(0, &[1u8; 8])?;
+        pool.write(1, &[2u8; 8])?;
+        assert_eq!(pool.device.writes, 0);
+
+        assert_eq!(pool.read(0)?, vec![1u8; 8]);
+        assert_eq!(pool.read(1)?, vec![2u8; 8]);
+        assert_eq!((pool.hits, pool.misses), (2, 0));
+
+        pool.flush_all()?;
+        assert_eq!(pool.device.writes, 2);
+        pool.flush_all()?;
+        assert_eq!(pool.device.writes, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lru_eviction() -> Result<(), std::io::Error> {
+        let mut pool = pool("test_lru_eviction.hex", 2);
+
+        pool.write(0, &[1u8; 8])?;
+        pool.write(1, &[2u8; 8])?;
+        pool.read(0)?;
+
+        /*
+         * Block 1 was used least recently
+         */
+        pool.write(2, &[3u8; 8])?;
+        assert_eq!(pool.device.writes, 1);
+        assert_eq!(pool.cached_count(), 2);
+
+        assert_eq!(pool.read(1)?, vec![2u8; 8]);
+        assert_eq!(pool.misses, 1);
+        assert_eq!(pool.device.reads, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_not_evicted() -> Result<(), std::io::Error> {
+        let mut pool = pool("test_pinned_not_evicted.hex", 0);
+
+        pool.write(0, &[1u8; 8])?;
+        assert_eq!(pool.device.writes, 1);
+        assert_eq!(pool.cached_count(), 0);
+
+        pool.pin(0)?;
+        pool.pin(0)?;
+        assert_eq!(pool.cached_count(), 1);
+        assert_eq!((pool.hits, pool.misses), (1, 1));
+
+        pool.unpin(0, Some(vec![2u8; 8]))?;
+        assert_eq!(pool.cached_count(), 1);
+        assert_eq!(pool.device.writes, 1);
+
+        pool.unpin(0, None)?;
+        assert_eq!(pool.cached_count(), 0);
+        assert_eq!(pool.device.writes, 2);
+        assert_eq!(pool.device.read(0)?, vec![2u8; 8]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hold_dirty() -> Result<(), std::io::Error> {
+        let mut pool = pool("test_hold_dirty.hex", 0);
+        pool.hold_dirty = true;
+
+        pool.write(0, &[1u8; 8])?;
+        pool.write(1, &[2u8; 8])?;
+        pool.read(1)?;
+        assert_eq!(pool.device.writes, 0);
+        assert_eq!(pool.dirty_frames(), vec![(0, vec![1u8; 8]), (1, vec![2u8; 8])]);
+
+        pool.flush_all()?;
+        assert_eq!(pool.device.writes, 2);
+        assert_eq!(pool.cached_count(), 0);
+
+        Ok(())
+    }
+}
@@ -3,4 +3,15 @@ pub trait Bytes : PartialEq+Eq+PartialOrd+Ord{
     fn to_bytes(&self) -> Vec<u8>;
     fn get_size() -> u64;
     fn invalid() -> Self;
-}
\ No newline at end of file
+
+    /*
+     * Encoded size of this particular value, `get_size` is the largest one
+     */
+    fn size(&self) -> u64 {
+        Self::get_size()
+    }
+
+    fn min_size() -> u64 {
+        Self::get_size()
+    }
+}
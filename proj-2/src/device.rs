@@ -8,6 +8,10 @@ pub struct BlockDevice {
     pub block_size: u64,
     pub reads: u64,
     pub writes: u64,
+    /*
+     * Writes past the limit fail, used to simulate a crash
+     */
+    pub writes_limit: Option<u64>,
 }
 
 impl BlockDevice {
@@ -23,10 +27,11 @@ impl BlockDevice {
             .create(true)
             .open(filename)?;
         let device = BlockDevice {
-            file: file,
+            file,
             block_size: blocksize,
             reads: 0,
             writes: 0,
+            writes_limit: None,
         };
         Ok(device)
     }
@@ -43,7 +48,7 @@ impl BlockDevice {
         self.read_internal(lba)
     }
 
-    pub fn write_internal(&mut self, lba: u64, buf: &Vec<u8>) -> Result<usize, std::io::Error> {
+    pub fn write_internal(&mut self, lba: u64, buf: &[u8]) -> Result<usize, std::io::Error> {
         if buf.len() != self.block_size as usize {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
@@ -54,8 +59,18 @@ impl BlockDevice {
         self.file.write(buf)
     }
 
-    pub fn write(&mut self, lba: u64, buf: &Vec<u8>) -> Result<usize, std::io::Error> {
+    pub fn write(&mut self, lba: u64, buf: &[u8]) -> Result<usize, std::io::Error> {
+        if self.writes_limit.is_some_and(|limit| self.writes >= limit) {
+            return Err(std::io::Error::other("Device reached its writes limit"));
+        }
         self.writes += 1;
         self.write_internal(lba, buf)
     }
+
+    /*
+     * Makes sure that everything written so far reached the disk
+     */
+    pub fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.file.sync_all()
+    }
 }
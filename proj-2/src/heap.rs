@@ -0,0 +1,578 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::device::BlockDevice;
+
+pub const HEAP_HEADER_SIZE: u64 = 4;
+pub const HEAP_SLOT_SIZE: u64 = 4;
+pub const HEAP_SLOT_BITS: u64 = 16;
+// Offsets within a page are kept in `u16`
+pub const HEAP_MAX_BLOCK_SIZE: u64 = u16::MAX as u64 + 1;
+
+/*
+ * Single block of a heap file. The header holds the number of slots and the
+ * offset where record data starts, slot directory follows the header and
+ * records grow from the end of the block towards it. Slot with offset 0 is a
+ * tombstone, its length stays until the page is compacted so that the dead
+ * space can be accounted for.
+ */
+pub struct HeapPage {
+    pub bytes: Vec<u8>,
+}
+
+impl HeapPage {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        HeapPage { bytes }
+    }
+
+    pub fn slots_count(&self) -> u64 {
+        LittleEndian::read_u16(&self.bytes[0..2]) as u64
+    }
+
+    fn set_slots_count(&mut self, count: u64) {
+        LittleEndian::write_u16(&mut self.bytes[0..2], count as u16);
+    }
+
+    /*
+     * Record data never starts inside the header, so 0 stands for the end
+     * of the block, both in a block that was never written and in one of
+     * `HEAP_MAX_BLOCK_SIZE` bytes
+     */
+    fn free_end(&self) -> u64 {
+        match LittleEndian::read_u16(&self.bytes[2..4]) as u64 {
+            0 => self.bytes.len() as u64,
+            offset => offset,
+        }
+    }
+
+    fn set_free_end(&mut self, offset: u64) {
+        LittleEndian::write_u16(&mut self.bytes[2..4], offset as u16);
+    }
+
+    fn slot(&self, slot: u64) -> (u64, u64) {
+        let off = (HEAP_HEADER_SIZE + slot * HEAP_SLOT_SIZE) as usize;
+        (
+            LittleEndian::read_u16(&self.bytes[off..off + 2]) as u64,
+            LittleEndian::read_u16(&self.bytes[off + 2..off + 4]) as u64,
+        )
+    }
+
+    fn set_slot(&mut self, slot: u64, offset: u64, length: u64) {
+        let off = (HEAP_HEADER_SIZE + slot * HEAP_SLOT_SIZE) as usize;
+        LittleEndian::write_u16(&mut self.bytes[off..off + 2], offset as u16);
+        LittleEndian::write_u16(&mut self.bytes[off + 2..off + 4], length as u16);
+    }
+
+    /*
+     * Contiguous space between slot directory and record data
+     */
+    pub fn free_space(&self) -> u64 {
+        self.free_end() - (HEAP_HEADER_SIZE + self.slots_count() * HEAP_SLOT_SIZE)
+    }
+
+    /*
+     * Space taken by records behind tombstones, reclaimed on compaction
+     */
+    pub fn dead_space(&self) -> u64 {
+        (0..self.slots_count())
+            .map(|slot| self.slot(slot))
+            .filter(|(offset, _)| *offset == 0)
+            .map(|(_, length)| length)
+            .sum()
+    }
+
+    /*
+     * Space a new record can take once the page is compacted
+     */
+    pub fn room(&self) -> u64 {
+        self.free_space() + self.dead_space()
+    }
+
+    pub fn live_count(&self) -> u64 {
+        (0..self.slots_count())
+            .filter(|slot| self.slot(*slot).0 != 0)
+            .count() as u64
+    }
+
+    pub fn get(&self, slot: u64) -> Option<&[u8]> {
+        if slot >= self.slots_count() {
+            return None;
+        }
+
+        match self.slot(slot) {
+            (0, _) => None,
+            (offset, length) => Some(&self.bytes[offset as usize..(offset + length) as usize]),
+        }
+    }
+
+    /*
+     * Returns slot of the inserted record or `None` if it does not fit
+     */
+    pub fn insert(&mut self, record: &[u8]) -> Option<u64> {
+        let length = record.len() as u64;
+        let tombstone = (0..self.slots_count()).find(|slot| self.slot(*slot).0 == 0);
+        let required = match tombstone {
+            Some(_) => length,
+            None => length + HEAP_SLOT_SIZE,
+        };
+
+        if self.free_space() < required {
+            if self.free_space() + self.dead_space() < required {
+                return None;
+            }
+            self.compact();
+        }
+
+        let slot = match tombstone {
+            Some(slot) => slot,
+            None => {
+                let slot = self.slots_count();
+                self.set_slots_count(slot + 1);
+                slot
+            }
+        };
+
+        let offset = self.free_end() - length;
+        self.bytes[offset as usize..(offset + length) as usize].copy_from_slice(record);
+        self.set_free_end(offset);
+        self.set_slot(slot, offset, length);
+
+        Some(slot)
+    }
+
+    /*
+     * Records are fixed in size, so they are always overwritten in place
+     */
+    pub fn update(&mut self, slot: u64, record: &[u8]) -> bool {
+        if slot >= self.slots_count() {
+            return false;
+        }
+
+        match self.slot(slot) {
+            (offset, length) if offset != 0 && length == record.len() as u64 => {
+                self.bytes[offset as usize..(offset + length) as usize].copy_from_slice(record);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn remove(&mut self, slot: u64) -> bool {
+        if slot >= self.slots_count() {
+            return false;
+        }
+
+        match self.slot(slot) {
+            (0, _) => false,
+            (_, length) => {
+                self.set_slot(slot, 0, length);
+                true
+            }
+        }
+    }
+
+    /*
+     * Moves live records to the end of the block, slots keep their numbers
+     */
+    pub fn compact(&mut self) {
+        let mut records = vec![];
+        for slot in 0..self.slots_count() {
+            if let Some(record) = self.get(slot) {
+                records.push((slot, record.to_vec()));
+            } else {
+                self.set_slot(slot, 0, 0);
+            }
+        }
+
+        self.set_free_end(self.bytes.len() as u64);
+        for (slot, record) in records {
+            let offset = self.free_end() - record.len() as u64;
+            self.bytes[offset as usize..offset as usize + record.len()].copy_from_slice(&record);
+            self.set_free_end(offset);
+            self.set_slot(slot, offset, record.len() as u64);
+        }
+    }
+}
+
+/*
+ * Live records and space behind tombstones in the whole heap file
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct HeapUsage {
+    pub live_records: u64,
+    pub dead_space: u64,
+}
+
+/*
+ * Records of the data device kept in slotted pages. A record is identified by
+ * its block and slot packed into a single `u64`, new records go to the first
+ * block with room for them, space freed by deletes included. Deferred writes
+ * stay in memory until they are taken by `take_pending`.
+ */
+pub struct HeapFile {
+    device: Rc<RefCell<BlockDevice>>,
+    pub pages_count: u64,
+    usage: Option<HeapUsage>,
+    // `HeapPage::room` of every page, filled on the first insert
+    rooms: Option<Vec<u64>>,
+    pending: Option<HashMap<u64, Vec<u8>>>,
+}
+
+impl HeapFile {
+    pub fn new(device: &Rc<RefCell<BlockDevice>>, pages_count: u64) -> Self {
+        HeapFile {
+            device: Rc::clone(device),
+            pages_count,
+            usage: None,
+            rooms: None,
+            pending: None,
+        }
+    }
+
+    pub fn defer_writes(&mut self) {
+        self.pending.get_or_insert_with(HashMap::new);
+    }
+
+    /*
+     * Pages written since the last call in order of their LBAs, the caller
+     * is responsible for writing them into the device
+     */
+    pub fn take_pending(&mut self) -> Vec<(u64, Vec<u8>)> {
+        let mut pending = match self.pending.as_mut() {
+            Some(pending) => pending.drain().collect::<Vec<(u64, Vec<u8>)>>(),
+            None => vec![],
+        };
+        pending.sort();
+
+        pending
+    }
+
+    /*
+     * Scans the whole file on first use, afterwards usage is kept up to
+     * date by `insert` and `delete`
+     */
+    pub fn usage(&mut self) -> Result<HeapUsage, std::io::Error> {
+        if let Some(usage) = self.usage {
+            return Ok(usage);
+        }
+
+        let mut usage = HeapUsage::default();
+        for lba in 0..self.pages_count {
+            let page = self.read_page(lba)?;
+            usage.live_records += page.live_count();
+            usage.dead_space += page.dead_space();
+        }
+
+        self.usage = Some(usage);
+        Ok(usage)
+    }
+
+    fn account(&mut self, inserted: bool, dead_before: u64, dead_after: u64) {
+        if let Some(usage) = self.usage.as_mut() {
+            match inserted {
+                true => usage.live_records += 1,
+                false => usage.live_records -= 1,
+            }
+            usage.dead_space = usage.dead_space + dead_after - dead_before;
+        }
+    }
+
+    fn rooms(&mut self) -> Result<&mut Vec<u64>, std::io::Error> {
+        if self.rooms.is_none() {
+            let rooms = (0..self.pages_count)
+                .map(|lba| self.read_page(lba).map(|page| page.room()))
+                .collect::<Result<Vec<u64>, std::io::Error>>()?;
+            self.rooms = Some(rooms);
+        }
+
+        Ok(self.rooms.as_mut().unwrap())
+    }
+
+    fn set_room(&mut self, lba: u64, page: &HeapPage) {
+        if let Some(rooms) = self.rooms.as_mut() {
+            match rooms.get_mut(lba as usize) {
+                Some(room) => *room = page.room(),
+                None => rooms.push(page.room()),
+            }
+        }
+    }
+
+    pub fn record_id(lba: u64, slot: u64) -> u64 {
+        (lba << HEAP_SLOT_BITS) | slot
+    }
+
+    pub fn split_record_id(id: u64) -> (u64, u64) {
+        (id >> HEAP_SLOT_BITS, id & ((1 << HEAP_SLOT_BITS) - 1))
+    }
+
+    /*
+     * Size of the largest record that fits into an empty block
+     */
+    pub fn capacity(block_size: u64) -> u64 {
+        block_size.saturating_sub(HEAP_HEADER_SIZE + HEAP_SLOT_SIZE)
+    }
+
+    pub fn check_block_size(block_size: u64) -> Result<(), std::io::Error> {
+        if block_size > HEAP_MAX_BLOCK_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Data blocks cannot be larger than {} bytes", HEAP_MAX_BLOCK_SIZE),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn read_page(&self, lba: u64) -> Result<HeapPage, std::io::Error> {
+        if let Some(bytes) = self.pending.as_ref().and_then(|pending| pending.get(&lba)) {
+            return Ok(HeapPage::new(bytes.clone()));
+        }
+
+        let bytes = self.device.borrow_mut().read(lba)?;
+        Ok(HeapPage::new(bytes))
+    }
+
+    pub fn write_page(&mut self, lba: u64, page: &HeapPage) -> Result<(), std::io::Error> {
+        if let Some(pending) = self.pending.as_mut() {
+            pending.insert(lba, page.bytes.clone());
+            return Ok(());
+        }
+
+        self.device.borrow_mut().write(lba, &page.bytes)?;
+        Ok(())
+    }
+
+    fn not_found(id: u64) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No record under id {:#x} in heap file", id),
+        )
+    }
+
+    pub fn insert(&mut self, record: &[u8]) -> Result<u64, std::io::Error> {
+        /*
+         * Pages without a tombstone also need room for a new slot, so the
+         * first candidate may still turn the record down
+         */
+        let length = record.len() as u64;
+        let candidates = self
+            .rooms()?
+            .iter()
+            .enumerate()
+            .filter(|(_, room)| **room >= length)
+            .map(|(lba, _)| lba as u64)
+            .collect::<Vec<u64>>();
+        for lba in candidates {
+            let mut page = self.read_page(lba)?;
+            let dead_before = page.dead_space();
+            if let Some(slot) = page.insert(record) {
+                self.write_page(lba, &page)?;
+                self.set_room(lba, &page);
+                self.account(true, dead_before, page.dead_space());
+                return Ok(HeapFile::record_id(lba, slot));
+            }
+        }
+
+        let block_size = self.device.borrow().block_size;
+        let lba = self.pages_count;
+        let mut page = HeapPage::new(vec![0u8; block_size as usize]);
+        let slot = page.insert(record).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Record does not fit into an empty heap page",
+            )
+        })?;
+
+        self.write_page(lba, &page)?;
+        self.set_room(lba, &page);
+        self.pages_count += 1;
+        self.account(true, 0, 0);
+        Ok(HeapFile::record_id(lba, slot))
+    }
+
+    pub fn read(&self, id: u64) -> Result<Vec<u8>, std::io::Error> {
+        let (lba, slot) = HeapFile::split_record_id(id);
+        let page = self.read_page(lba)?;
+
+        match page.get(slot) {
+            Some(record) => Ok(record.to_vec()),
+            None => Err(HeapFile::not_found(id)),
+        }
+    }
+
+    pub fn update(&mut self, id: u64, record: &[u8]) -> Result<(), std::io::Error> {
+        let (lba, slot) = HeapFile::split_record_id(id);
+        let mut page = self.read_page(lba)?;
+
+        if !page.update(slot, record) {
+            return Err(HeapFile::not_found(id));
+        }
+        self.write_page(lba, &page)
+    }
+
+    pub fn delete(&mut self, id: u64) -> Result<(), std::io::Error> {
+        let (lba, slot) = HeapFile::split_record_id(id);
+        let mut page = self.read_page(lba)?;
+        let dead_before = page.dead_space();
+
+        if !page.remove(slot) {
+            return Err(HeapFile::not_found(id));
+        }
+        self.write_page(lba, &page)?;
+        self.set_room(lba, &page);
+        self.account(false, dead_before, page.dead_space());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_insert_get() -> Result<(), std::io::Error> {
+        let mut page = HeapPage::new(vec![0u8; 64]);
+
+        assert_eq!(page.free_space(), 60);
+        assert_eq!(page.insert(&[1u8; 20]), Some(0));
+        assert_eq!(page.insert(&[2u8; 20]), Some(1));
+        assert_eq!(page.free_space(), 12);
+        assert_eq!(page.insert(&[3u8; 20]), None);
+
+        assert_eq!(page.get(0), Some(&[1u8; 20][..]));
+        assert_eq!(page.get(1), Some(&[2u8; 20][..]));
+        assert_eq!(page.get(2), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_tombstone_reuse() -> Result<(), std::io::Error> {
+        let mut page = HeapPage::new(vec![0u8; 64]);
+
+        page.insert(&[1u8; 20]);
+        page.insert(&[2u8; 20]);
+        assert!(page.remove(0));
+        assert!(!page.remove(0));
+        assert_eq!(page.get(0), None);
+        assert_eq!(page.dead_space(), 20);
+        assert_eq!(page.live_count(), 1);
+
+        /*
+         * Needs compaction to fit, surviving record keeps its slot
+         */
+        assert_eq!(page.insert(&[3u8; 20]), Some(0));
+        assert_eq!(page.dead_space(), 0);
+        assert_eq!(page.get(0), Some(&[3u8; 20][..]));
+        assert_eq!(page.get(1), Some(&[2u8; 20][..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_heap_file() -> Result<(), std::io::Error> {
+        let device = BlockDevice::new("test_heap_file.hex".to_string(), 64, true)?;
+        let device = Rc::new(RefCell::new(device));
+        let mut heap = HeapFile::new(&device, 0);
+
+        let ids = (0..5u8)
+            .map(|value| heap.insert(&[value + 1; 20]))
+            .collect::<Result<Vec<u64>, std::io::Error>>()?;
+
+        assert_eq!(heap.pages_count, 3);
+        assert_eq!(HeapFile::split_record_id(ids[3]), (1, 1));
+
+        heap.update(ids[3], &[9u8; 20])?;
+        heap.delete(ids[1])?;
+
+        assert_eq!(heap.read(ids[0])?, vec![1u8; 20]);
+        assert_eq!(heap.read(ids[3])?, vec![9u8; 20]);
+        assert_eq!(heap.read(ids[1]).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(heap.delete(ids[1]).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        let usage = heap.usage()?;
+        assert_eq!(usage, HeapUsage { live_records: 4, dead_space: 20 });
+
+        /*
+         * Kept up to date without another scan, the new record takes the
+         * place of the deleted one
+         */
+        assert_eq!(heap.insert(&[6u8; 20])?, ids[1]);
+        heap.delete(ids[4])?;
+        let reads = device.borrow().reads;
+        assert_eq!(heap.usage()?, HeapUsage { live_records: 4, dead_space: 20 });
+        assert_eq!(device.borrow().reads, reads);
+        assert_eq!(HeapFile::new(&device, heap.pages_count).usage()?, heap.usage()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_heap_space_reuse() -> Result<(), std::io::Error> {
+        let device = BlockDevice::new("test_heap_space_reuse.hex".to_string(), 64, true)?;
+        let device = Rc::new(RefCell::new(device));
+        let mut heap = HeapFile::new(&device, 0);
+
+        let ids = (0..6u8)
+            .map(|value| heap.insert(&[value + 1; 20]))
+            .collect::<Result<Vec<u64>, std::io::Error>>()?;
+        assert_eq!(heap.pages_count, 3);
+
+        /*
+         * Space freed in the first pages is found again after reopening,
+         * a smaller record fits into the tombstone next to the survivor
+         */
+        heap.delete(ids[0])?;
+        heap.delete(ids[3])?;
+        let mut heap = HeapFile::new(&device, heap.pages_count);
+        assert_eq!(heap.insert(&[7u8; 20])?, ids[0]);
+        assert_eq!(heap.insert(&[8u8; 10])?, HeapFile::record_id(1, 1));
+        assert_eq!(heap.insert(&[9u8; 20])?, HeapFile::record_id(3, 0));
+        assert_eq!(heap.pages_count, 4);
+        assert_eq!(heap.read(ids[2])?, vec![3u8; 20]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_largest_block() -> Result<(), std::io::Error> {
+        assert!(HeapFile::check_block_size(HEAP_MAX_BLOCK_SIZE).is_ok());
+        assert_eq!(
+            HeapFile::check_block_size(HEAP_MAX_BLOCK_SIZE + 1).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        let mut page = HeapPage::new(vec![0u8; HEAP_MAX_BLOCK_SIZE as usize]);
+        assert_eq!(page.free_space(), HeapFile::capacity(HEAP_MAX_BLOCK_SIZE) + HEAP_SLOT_SIZE);
+        assert_eq!(page.insert(&[1u8; 100]), Some(0));
+        assert_eq!(page.get(0), Some(&[1u8; 100][..]));
+        assert!(page.remove(0));
+        page.compact();
+        assert_eq!(page.free_space(), HeapFile::capacity(HEAP_MAX_BLOCK_SIZE));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deferred_writes() -> Result<(), std::io::Error> {
+        let device = BlockDevice::new("test_deferred_writes.hex".to_string(), 64, true)?;
+        let device = Rc::new(RefCell::new(device));
+        let mut heap = HeapFile::new(&device, 0);
+        heap.defer_writes();
+
+        let first = heap.insert(&[1u8; 20])?;
+        let second = heap.insert(&[2u8; 20])?;
+        heap.delete(first)?;
+
+        assert_eq!(device.borrow().writes, 0);
+        assert_eq!(heap.read(second)?, vec![2u8; 20]);
+        assert_eq!(device.borrow().reads, 0);
+
+        let pending = heap.take_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(HeapPage::new(pending[0].1.clone()).live_count(), 1);
+        assert!(heap.take_pending().is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufWriter, Write},
+};
+
+use crate::{
+    btree::BTree,
+    btree_stats::TreeStats,
+    btree_key::IntKey,
+    record::{IntRecord, Record},
+    tape::TapeReader,
+};
+
+/*
+ * Block size proj-1 writes its tapes with by default
+ */
+const TAPE_BLOCK_SIZE: u64 = 230;
+
+/*
+ * Reads and writes of both devices, used to report the cost of a single
+ * command
+ */
+#[derive(Copy, Clone, Default)]
+struct Counters {
+    index_reads: u64,
+    index_writes: u64,
+    data_reads: u64,
+    data_writes: u64,
+}
+
+/*
+ * Drives the index with one command per line:
+ *
+ *     insert <key> <numbers...>
+ *     get <key>
+ *     update <key> <numbers...>
+ *     delete <key>
+ *     range [<from> [<to>]]
+ *     print
+ *     dot <path>
+ *     stats [csv <path>]
+ *     reorganize
+ *     check
+ *     load <tape path> [<tape block size> [<fill factor>]]
+ *
+ * `get` prints every record of the key in multimap mode. `load` bulk loads
+ * an empty index from a tape sorted by proj-1. The records do not carry
+ * keys, each one gets its position on the tape as its key, counted from 0,
+ * so the tape order is kept and tapes longer than `i32::MAX + 1` records
+ * are rejected. `stats csv` appends the tree statistics as a row to
+ * the given file, with a header if the file is new. Empty lines and lines
+ * starting with `#` are skipped. Every command is followed by the reads and
+ * writes it caused on the index and data devices.
+ */
+pub struct Interpreter<W: Write> {
+    pub btree: BTree<IntKey, IntRecord>,
+    out: W,
+}
+
+impl<W: Write> Interpreter<W> {
+    pub fn new(btree: BTree<IntKey, IntRecord>, out: W) -> Self {
+        Interpreter { btree, out }
+    }
+
+    fn counters(&self) -> Counters {
+        let index_device = self.btree.core.index_device.borrow();
+        let data_device = self.btree.core.data_device.borrow();
+
+        Counters {
+            index_reads: index_device.device.reads,
+            index_writes: index_device.device.writes,
+            data_reads: data_device.reads,
+            data_writes: data_device.writes,
+        }
+    }
+
+    pub fn run<R: BufRead>(&mut self, input: R) -> Result<(), std::io::Error> {
+        for line in input.lines() {
+            self.execute(&line?)?;
+        }
+        self.out.flush()
+    }
+
+    /*
+     * Malformed commands are reported and skipped, only failures to write
+     * the output are returned
+     */
+    pub fn execute(&mut self, line: &str) -> Result<(), std::io::Error> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let before = self.counters();
+        let result = self.dispatch(line);
+        let after = self.counters();
+
+        match result {
+            Ok(lines) => {
+                for output in lines {
+                    writeln!(self.out, "{}", output)?;
+                }
+            }
+            Err(error) => writeln!(self.out, "error: {}", error)?,
+        }
+
+        writeln!(
+            self.out,
+            "  index: {} reads, {} writes | data: {} reads, {} writes",
+            after.index_reads - before.index_reads,
+            after.index_writes - before.index_writes,
+            after.data_reads - before.data_reads,
+            after.data_writes - before.data_writes,
+        )
+    }
+
+    fn dispatch(&mut self, line: &str) -> Result<Vec<String>, std::io::Error> {
+        let mut words = line.split_ascii_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args = words.collect::<Vec<&str>>();
+
+        match command {
+            "insert" => {
+                let (key, record) = Interpreter::<W>::parse_entry(&args)?;
+                self.btree.insert(key, record)?;
+                Ok(vec![format!("inserted {}", key.value)])
+            }
+            "get" => {
+                let key = Interpreter::<W>::parse_key(&args, 1)?;
+                let records = self.btree.get_all(key)?;
+                if records.is_empty() {
+                    return Ok(vec![format!("{}: not found", key.value)]);
+                }
+                Ok(records
+                    .iter()
+                    .map(|record| format!("{}: {}", key.value, record))
+                    .collect())
+            }
+            "update" => {
+                let (key, record) = Interpreter::<W>::parse_entry(&args)?;
+                self.btree.update(key, record)?;
+                Ok(vec![format!("updated {}", key.value)])
+            }
+            "delete" => {
+                let key = Interpreter::<W>::parse_key(&args, 1)?;
+                Ok(vec![match self.btree.delete(key)? {
+                    true => format!("deleted {}", key.value),
+                    false => format!("{}: not found", key.value),
+                }])
+            }
+            "range" => {
+                if args.len() > 2 {
+                    return Err(Interpreter::<W>::invalid("range takes at most two keys"));
+                }
+                let keys = args
+                    .iter()
+                    .map(|arg| Interpreter::<W>::parse_value(arg))
+                    .collect::<Result<Vec<IntKey>, std::io::Error>>()?;
+
+                let range = match keys[..] {
+                    [] => self.btree.range(..),
+                    [from] => self.btree.range(from..),
+                    [from, to] => self.btree.range(from..=to),
+                    _ => unreachable!(),
+                };
+                let mut lines = range
+                    .map(|item| item.map(|(key, record)| format!("{}: {}", key.value, record)))
+                    .collect::<Result<Vec<String>, std::io::Error>>()?;
+                lines.push(format!("{} records", lines.len()));
+                Ok(lines)
+            }
+            "print" => {
+                self.btree.print();
+                Ok(vec![])
+            }
+            "dot" => {
+                if args.len() != 1 {
+                    return Err(Interpreter::<W>::invalid("expected `dot <path>`"));
+                }
+                let mut writer = BufWriter::new(File::create(args[0])?);
+                self.btree.to_dot(&mut writer)?;
+                writer.flush()?;
+                Ok(vec![format!("written graph to {}", args[0])])
+            }
+            "stats" => {
+                let tree_stats = self.btree.stats()?;
+                match args[..] {
+                    [] => (),
+                    ["csv", path] => {
+                        Interpreter::<W>::append_csv(path, &tree_stats)?;
+                        return Ok(vec![format!("appended stats to {}", path)]);
+                    }
+                    _ => return Err(Interpreter::<W>::invalid("expected `stats [csv <path>]`")),
+                }
+
+                let stats = self.btree.core.page_stats();
+                let index_device = self.btree.core.index_device.borrow();
+                let mut lines = vec![
+                    format!(
+                        "degree {}, root at {}, {} live pages, {} free pages, {} data pages",
+                        self.btree.core.degree,
+                        self.btree.core.root_lba,
+                        stats.live_pages,
+                        stats.free_pages,
+                        self.btree.core.heap.pages_count,
+                    ),
+                    format!(
+                        "{} splits, {} compensations, {} pool hits, {} pool misses",
+                        self.btree.splits,
+                        self.btree.compensations,
+                        index_device.hits,
+                        index_device.misses,
+                    ),
+                ];
+                lines.extend(tree_stats.to_string().lines().map(|line| line.to_string()));
+                Ok(lines)
+            }
+            "reorganize" => {
+                self.btree.reorganize()?;
+                let pages_count = self.btree.core.heap.pages_count;
+                Ok(vec![format!("reorganized into {} data pages", pages_count)])
+            }
+            "check" => {
+                let mut lines = self
+                    .btree
+                    .check()
+                    .iter()
+                    .map(|violation| violation.to_string())
+                    .collect::<Vec<String>>();
+                lines.push(format!("{} violations", lines.len()));
+                Ok(lines)
+            }
+            "load" => {
+                let (path, block_size, fill_factor) = match args[..] {
+                    [path] => (path, Ok(TAPE_BLOCK_SIZE), Ok(1.0)),
+                    [path, block_size] => (path, block_size.parse(), Ok(1.0)),
+                    [path, block_size, fill_factor] => {
+                        (path, block_size.parse(), fill_factor.parse())
+                    }
+                    _ => {
+                        return Err(Interpreter::<W>::invalid(
+                            "expected `load <tape path> [<tape block size> [<fill factor>]]`",
+                        ))
+                    }
+                };
+                let (block_size, fill_factor) = match (block_size, fill_factor) {
+                    (Ok(block_size), Ok(fill_factor)) => (block_size, fill_factor),
+                    _ => return Err(Interpreter::<W>::invalid("invalid block size or fill factor")),
+                };
+
+                let entries = TapeReader::<IntRecord>::new(path, block_size)?
+                    .enumerate()
+                    .map(|(position, record)| match i32::try_from(position) {
+                        Ok(value) => Ok((IntKey { value }, record?)),
+                        Err(_) => Err(Interpreter::<W>::invalid(
+                            "tape holds more records than there are keys",
+                        )),
+                    });
+                let count = self.btree.bulk_load(entries, fill_factor)?;
+                Ok(vec![format!("loaded {} records", count)])
+            }
+            _ => Err(Interpreter::<W>::invalid(&format!("unknown command `{}`", command))),
+        }
+    }
+
+    fn invalid(message: &str) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string())
+    }
+
+    fn append_csv(path: &str, stats: &TreeStats) -> Result<(), std::io::Error> {
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{}", TreeStats::csv_header())?;
+        }
+        writeln!(file, "{}", stats.to_csv())
+    }
+
+    fn parse_value(arg: &str) -> Result<IntKey, std::io::Error> {
+        match arg.parse::<i32>() {
+            Ok(value) if value != i32::MIN => Ok(IntKey { value }),
+            _ => Err(Interpreter::<W>::invalid(&format!("`{}` is not a valid key", arg))),
+        }
+    }
+
+    fn parse_key(args: &[&str], count: usize) -> Result<IntKey, std::io::Error> {
+        if args.len() != count {
+            return Err(Interpreter::<W>::invalid(&format!("expected {} argument(s)", count)));
+        }
+        Interpreter::<W>::parse_value(args[0])
+    }
+
+    /*
+     * Record needs at least one number and the first one cannot be zero,
+     * as such records are treated as empty by the data device
+     */
+    fn parse_entry(args: &[&str]) -> Result<(IntKey, IntRecord), std::io::Error> {
+        let key = match args.first() {
+            Some(arg) => Interpreter::<W>::parse_value(arg)?,
+            None => return Err(Interpreter::<W>::invalid("missing key")),
+        };
+
+        let numbers = &args[1..];
+        let valid_numbers = numbers.iter().all(|number| number.parse::<u32>().is_ok());
+        if numbers.is_empty()
+            || numbers.len() as u64 > IntRecord::get_size() / 4
+            || !valid_numbers
+            || numbers[0].parse::<u32>() == Ok(0)
+        {
+            return Err(Interpreter::<W>::invalid(
+                "record needs 1 to 15 unsigned numbers, the first one non-zero",
+            ));
+        }
+
+        Ok((key, IntRecord::from_string(numbers.join(" "))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(name: &str, capacity: u64, script: &str) -> Vec<String> {
+        let btree = BTree::<IntKey, IntRecord>::create(name, 88, capacity).unwrap(); // t = 2
+        let mut output = vec![];
+        Interpreter::new(btree, &mut output)
+            .run(script.as_bytes())
+            .unwrap();
+
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_commands() -> Result<(), std::io::Error> {
+        let output = run(
+            "test_interpreter_commands.hex",
+            0,
+            "# comment\n\
+             insert 5 1 2 3\n\
+             insert 3 7\n\
+             \n\
+             get 5\n\
+             update 3 9 9\n\
+             get 3\n\
+             range 4\n\
+             delete 5\n\
+             get 5\n\
+             reorganize\n\
+             get 3\n\
+             check\n\
+             dot test_interpreter_commands.dot\n",
+        );
+
+        let results = output
+            .iter()
+            .filter(|line| !line.starts_with("  index:"))
+            .map(|line| line.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            results,
+            vec![
+                "inserted 5",
+                "inserted 3",
+                "5: [1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] <=> 2",
+                "updated 3",
+                "3: [9, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] <=> 0",
+                "5: [1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] <=> 2",
+                "1 records",
+                "deleted 5",
+                "5: not found",
+                "reorganized into 1 data pages",
+                "3: [9, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] <=> 0",
+                "0 violations",
+                "written graph to test_interpreter_commands.dot",
+            ]
+        );
+
+        /*
+         * Record of the first insert lands in a fresh data block
+         */
+        assert_eq!(output[1], "  index: 1 reads, 1 writes | data: 0 reads, 1 writes");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), std::io::Error> {
+        let path = "test_interpreter_stats.csv";
+        let _ = std::fs::remove_file(path);
+
+        let output = run(
+            "test_interpreter_stats.hex",
+            8,
+            &format!(
+                "insert 1 1\ninsert 2 2\nstats\nstats csv {0}\ninsert 3 3\nstats csv {0}\nstats csv\n",
+                path
+            ),
+        );
+
+        assert!(output.contains(&"height 1, pages per level [1], 2 keys".to_string()));
+
+        /*
+         * The root stays in the pool after the first insert
+         */
+        assert_eq!(output[3], "  index: 0 reads, 0 writes | data: 0 reads, 1 writes");
+        assert!(output.contains(&"0 splits, 0 compensations, 2 pool hits, 1 pool misses".to_string()));
+        assert!(output.contains(&format!("appended stats to {}", path)));
+        assert_eq!(output.last().map(|line| line.starts_with("  index:")), Some(true));
+        assert!(output[output.len() - 2].starts_with("error: "));
+
+        let csv = std::fs::read_to_string(path)?;
+        let rows = csv.lines().collect::<Vec<&str>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], TreeStats::csv_header());
+        assert!(rows[1].starts_with("2,Split,1,1,2,"));
+        assert!(rows[2].starts_with("2,Split,1,1,3,"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> Result<(), std::io::Error> {
+        let tape = "test_interpreter_load.txt";
+        let mut device = crate::device::BlockDevice::new(tape.to_string(), 100, true)?;
+        for lba in 0..4 {
+            let mut buf = vec![0u8; 100];
+            let record = IntRecord::from_string(format!("{} 3", lba + 1))?;
+            buf[..IntRecord::get_size() as usize].copy_from_slice(&record.get_bytes());
+            device.write(lba, &buf)?;
+        }
+
+        let output = run(
+            "test_interpreter_load.hex",
+            0,
+            &format!("load {0} 100 0.5\nget 3\nload {0} 100\nload\n", tape),
+        );
+
+        assert_eq!(output[0], "loaded 4 records");
+        assert_eq!(output[2], "3: [4, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] <=> 1");
+        assert_eq!(output[4], "error: Bulk load needs an empty tree");
+        assert!(output[6].starts_with("error: expected `load"));
+
+        // Blocks of the wrong size cut records apart, the tree stays empty
+        let output = run("test_interpreter_load_block.hex", 0, &format!("load {}\nget 1\n", tape));
+        assert_eq!(output[0], "error: Empty record");
+        assert_eq!(output[2], "1: not found");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() -> Result<(), std::io::Error> {
+        let output = run(
+            "test_interpreter_errors.hex",
+            0,
+            "insert x 1\ninsert 1 0 2\nfind 1\nupdate 1 2\ninsert 2 1\ninsert 2 3\n",
+        );
+
+        assert_eq!(output.len(), 12);
+        assert!(output[..8].iter().step_by(2).all(|line| line.starts_with("error: ")));
+        assert_eq!(output[4], "error: unknown command `find`");
+        assert_eq!(output[10], "error: Key 2 is already present in the index");
+
+        Ok(())
+    }
+}
@@ -1,17 +1,172 @@
 pub mod device;
 pub mod record;
+pub mod buffer_pool;
 pub mod bytes;
+pub mod bplus_range;
+pub mod bplus_tree;
 pub mod btree;
+pub mod btree_bulk;
+pub mod btree_check;
+pub mod btree_core;
+pub mod btree_dot;
 pub mod btree_key;
+pub mod btree_range;
 pub mod btree_record;
+pub mod btree_stats;
+pub mod heap;
+pub mod interpreter;
 pub mod page;
+pub mod superblock;
+pub mod tape;
+pub mod wal;
 
-use crate::{device::BlockDevice, btree::{BTree}, record::IntRecord, btree_key::IntKey};
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader},
+};
 
+use crate::{
+    btree::{BTree, DuplicateMode, InsertPolicy},
+    btree_dot::DotSteps,
+    btree_key::IntKey,
+    interpreter::Interpreter,
+    record::IntRecord,
+};
+
+fn help() {
+    println!(
+        "usage:
+    -b <block size>
+    -c <buffer pool frames>
+    -i <index path>
+    -o
+    -f <script path>
+    -r <dead space ratio>
+    -w
+    -p <split|compensation>
+    -d <snapshot prefix>
+    -m <unique|replace|multimap>
+
+commands:
+    insert <key> <numbers...>
+    get <key>
+    update <key> <numbers...>
+    delete <key>
+    range [<from> [<to>]]
+    print
+    dot <path>
+    stats [csv <path>]
+    reorganize
+    check
+    load <tape path> [<tape block size> [<fill factor>]]"
+    );
+}
 
 fn main() {
-    let index_device = BlockDevice::new("index.hex".to_string(), 256, true).expect("Could not create index device");
-    let data_device = BlockDevice::new("index.hex".to_string(), 256, true).expect("Could not create data device");
-    let b_tree = BTree::<IntKey, IntRecord>::new(index_device, data_device);
+    let args: Vec<String> = env::args().collect();
+
+    let mut block_size: u64 = 256;
+    let mut capacity: u64 = 0;
+    let mut index_path = "index.hex".to_string();
+    let mut open = false;
+    let mut script = None;
+    let mut threshold = None;
+    let mut wal = false;
+    let mut policy = InsertPolicy::Split;
+    let mut dot_prefix = None;
+    let mut duplicate_mode = DuplicateMode::Unique;
+
+    let mut index = 1;
+    while index < args.len() {
+        let value = args.get(index + 1);
+        match args[index].as_str() {
+            "-b" => {
+                block_size = match value.map(|value| value.parse()) {
+                    Some(Ok(num)) => num,
+                    _ => panic!("Error when parsing `-b`"),
+                };
+                index += 1;
+            }
+            "-c" => {
+                capacity = match value.map(|value| value.parse()) {
+                    Some(Ok(num)) => num,
+                    _ => panic!("Error when parsing `-c`"),
+                };
+                index += 1;
+            }
+            "-i" => {
+                index_path = value.expect("Missing path after `-i`").to_string();
+                index += 1;
+            }
+            "-o" => open = true,
+            "-w" => wal = true,
+            "-f" => {
+                script = Some(value.expect("Missing path after `-f`").to_string());
+                index += 1;
+            }
+            "-p" => {
+                policy = match value.map(|value| value.as_str()) {
+                    Some("split") => InsertPolicy::Split,
+                    Some("compensation") => InsertPolicy::Compensation,
+                    _ => panic!("Error when parsing `-p`"),
+                };
+                index += 1;
+            }
+            "-m" => {
+                duplicate_mode = match value.map(|value| value.as_str()) {
+                    Some("unique") => DuplicateMode::Unique,
+                    Some("replace") => DuplicateMode::Replace,
+                    Some("multimap") => DuplicateMode::Multimap,
+                    _ => panic!("Error when parsing `-m`"),
+                };
+                index += 1;
+            }
+            "-d" => {
+                dot_prefix = Some(value.expect("Missing prefix after `-d`").to_string());
+                index += 1;
+            }
+            "-r" => {
+                threshold = match value.map(|value| value.parse()) {
+                    Some(Ok(ratio)) => Some(ratio),
+                    _ => panic!("Error when parsing `-r`"),
+                };
+                index += 1;
+            }
+            _ => {
+                help();
+                return;
+            }
+        }
+        index += 1;
+    }
+
+    let mut btree = match open {
+        true => BTree::<IntKey, IntRecord>::open(&index_path, capacity).expect("Could not open index"),
+        false => {
+            BTree::<IntKey, IntRecord>::create_with_duplicates(
+                &index_path,
+                block_size,
+                duplicate_mode,
+                capacity,
+            )
+            .expect("Could not create index")
+        }
+    };
+    btree.reorganize_threshold = threshold;
+    btree.insert_policy = policy;
+    btree.dot_steps = dot_prefix.map(|prefix| DotSteps::new(&prefix));
+    if wal && btree.wal.is_none() {
+        btree.enable_wal().expect("Could not create redo log");
+    }
+    let mut interpreter = Interpreter::new(btree, io::stdout());
 
+    match script {
+        Some(path) => {
+            let file = File::open(path).expect("Could not open script");
+            interpreter.run(BufReader::new(file))
+        }
+        None => interpreter.run(io::stdin().lock()),
+    }
+    .expect("Could not write output");
 }
@@ -1,33 +1,38 @@
 use std::cell::RefCell;
 use std::{rc::Rc};
 
+use crate::buffer_pool::BufferPool;
 use crate::bytes::Bytes;
-use crate::device::BlockDevice;
 
 pub struct Page<K: Bytes> {
-    device: Rc<RefCell<BlockDevice>>,
+    device: Rc<RefCell<BufferPool>>,
     pub records: Vec<Box<K>>,
     pub dirty: bool,
     pub lba: u64,
     pub parent_lba: u64,
+    pinned: bool,
 }
 
 impl<R: Bytes> Page<R> {
-    pub fn new(device: &Rc<RefCell<BlockDevice>>, lba: u64, parent_lba: u64) -> Self {
+    pub fn new(device: &Rc<RefCell<BufferPool>>, lba: u64, parent_lba: u64) -> Self {
         let mut page = Page::<R> {
             device: Rc::clone(device),
             records: Vec::<Box<R>>::new(),
             dirty: false,
-            lba: lba,
-            parent_lba: parent_lba, 
+            lba,
+            parent_lba, 
+            pinned: false,
         };
 
         {
             let mut device = device.borrow_mut();
-            let read_result = device.read(lba);
+            let read_result = device.pin(lba);
 
             let bytes = match read_result {
-                Ok(bytes) => bytes,
+                Ok(bytes) => {
+                    page.pinned = true;
+                    bytes
+                }
                 Err(_) => {
                     /*
                      * The device will be filled with invalid records when the `Page` is dropped
@@ -37,28 +42,39 @@ impl<R: Bytes> Page<R> {
                 }
             };
 
-            let mut off = 0 as usize;
-            let len = R::get_size() as usize;
-            while off + len <= bytes.len() {
-                let record = R::from_bytes(&bytes[off..off + len]);
-                if record == R::invalid() {
-                    break;
-                }
-                page.records.push(Box::new(record));
-                off += len;
-            }
+            page.records = Page::<R>::records_from_bytes(&bytes);
         }
 
         page
     }
 
-    pub fn empty(device: &Rc<RefCell<BlockDevice>>, lba: u64, parent_lba: u64) -> Self {
+    /*
+     * Records may differ in size, each one is read from where the previous
+     * one ended, the first invalid one ends the page
+     */
+    pub fn records_from_bytes(bytes: &[u8]) -> Vec<Box<R>> {
+        let mut records = vec![];
+        let mut off = 0_usize;
+        while off + R::min_size() as usize <= bytes.len() {
+            let record = R::from_bytes(&bytes[off..]);
+            if record == R::invalid() {
+                break;
+            }
+            off += record.size() as usize;
+            records.push(Box::new(record));
+        }
+
+        records
+    }
+
+    pub fn empty(device: &Rc<RefCell<BufferPool>>, lba: u64, parent_lba: u64) -> Self {
         let mut page = Page::<R> {
             device: Rc::clone(device),
             records: Vec::<Box<R>>::new(),
             dirty: false,
-            lba: lba,
-            parent_lba: parent_lba, 
+            lba,
+            parent_lba, 
+            pinned: false,
         };
 
         page.dirty = true;
@@ -69,32 +85,41 @@ impl<R: Bytes> Page<R> {
 
 impl<K: Bytes> Drop for Page<K> {
     fn drop(&mut self) {
+        let mut device = self.device.borrow_mut();
+        let mut bytes = None;
+
         if self.dirty {
-            let mut device = self.device.borrow_mut();
-            
-            let mut bytes = vec![0u8; device.block_size as usize];
-            let mut off = 0 as usize;
-            let len = K::get_size() as usize;
+            let mut buf = vec![0u8; device.block_size as usize];
+            let mut off = 0_usize;
 
             for record in &self.records {
-                bytes[off..off + len].copy_from_slice(&record.to_bytes());
+                let len = record.size() as usize;
+                buf[off..off + len].copy_from_slice(&record.to_bytes());
                 off += len;
             }
 
             // Fill rest with invalid records
-            while off + len <= device.block_size as usize {
-                bytes[off..off + len].copy_from_slice(&K::invalid().to_bytes());
-                off += len;
+            let invalid = K::invalid().to_bytes();
+            while off + invalid.len() <= device.block_size as usize {
+                buf[off..off + invalid.len()].copy_from_slice(&invalid);
+                off += invalid.len();
             }
 
-            device.write(self.lba, &bytes).expect("Could not write into device on flush!");
+            bytes = Some(buf);
         }
+
+        let result = match (self.pinned, bytes) {
+            (true, bytes) => device.unpin(self.lba, bytes),
+            (false, Some(bytes)) => device.write(self.lba, &bytes),
+            (false, None) => Ok(()),
+        };
+        result.expect("Could not write into device on flush!");
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::btree_key::IntKey;
+    use crate::btree_key::{IntKey, StringKey};
     use crate::{btree_record::BTreeRecord};
     use crate::device::BlockDevice;
     
@@ -106,7 +131,7 @@ mod tests {
         let mut device = BlockDevice::new("test_new_empty.hex".to_string(), block_size, true).unwrap();
 
         let mut bytes = vec![0u8; block_size as usize];
-        let mut off = 0 as usize;
+        let mut off = 0_usize;
         let len = BTreeRecord::<IntKey>::get_size() as usize;
         while off + len <= block_size as usize {
             bytes[off..off + len].copy_from_slice(&BTreeRecord::<IntKey>::invalid().to_bytes());
@@ -114,7 +139,7 @@ mod tests {
         }
 
         device.write(0, &bytes).unwrap();
-        let device = Rc::new(RefCell::new(device));
+        let device = Rc::new(RefCell::new(BufferPool::new(device, 0)));
 
         let page = Page::<BTreeRecord<IntKey>>::new(&device, 0, 0);
 
@@ -129,7 +154,7 @@ mod tests {
         let mut device = BlockDevice::new("test_new_one_record.hex".to_string(), block_size, true).unwrap();
 
         let mut bytes = vec![0u8; block_size as usize];
-        let mut off = 0 as usize;
+        let mut off = 0_usize;
         let len = BTreeRecord::<IntKey>::get_size() as usize;
         while off + len <= block_size as usize {
             bytes[off..off + len].copy_from_slice(&BTreeRecord::<IntKey>::invalid().to_bytes());
@@ -140,21 +165,49 @@ mod tests {
         let record = BTreeRecord {
             child_lba: Some(0xDEADBEEFAAAABBBB),
             data_lba: 0xFFEEFFEEFFEEFFEE,
-            key: key,
+            key,
         };
         bytes[0 .. BTreeRecord::<IntKey>::get_size() as usize].copy_from_slice(&record.to_bytes());
 
         device.write(0, &bytes).unwrap();
-        let device = Rc::new(RefCell::new(device));
+        let device = Rc::new(RefCell::new(BufferPool::new(device, 0)));
 
         let page = Page::<BTreeRecord<IntKey>>::new(&device, 0, 0);
 
-        let mut expected_records = Vec::<Box<BTreeRecord<IntKey>>>::new();
-        expected_records.push(Box::new(record));
+        let expected_records = vec![Box::new(record)];
 
         assert_ne!(page.records, Vec::<Box<BTreeRecord<IntKey>>>::new());
         assert_eq!(page.records, expected_records);
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_variable_size_records() -> Result<(), std::io::Error> {
+        let device = BlockDevice::new("test_variable_size_records.hex".to_string(), 128, true)?;
+        let device = Rc::new(RefCell::new(BufferPool::new(device, 0)));
+
+        let records = ["", "ab", "a much longer key"]
+            .iter()
+            .enumerate()
+            .map(|(index, text)| {
+                Box::new(BTreeRecord {
+                    child_lba: Some(index as u64),
+                    data_lba: 7,
+                    key: StringKey::new(text).unwrap(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        {
+            let mut page = Page::<BTreeRecord<StringKey>>::empty(&device, 0, 0);
+            page.records = records.clone();
+        }
+
+        let page = Page::<BTreeRecord<StringKey>>::new(&device, 0, 0);
+        assert_eq!(page.records, records);
+        assert_eq!(page.records.iter().map(|record| record.size()).sum::<u64>(), 18 + 20 + 35);
+
+        Ok(())
+    }
+}
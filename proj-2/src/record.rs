@@ -1,4 +1,4 @@
-use std::{cmp::Ordering, mem::size_of};
+use std::{cmp::Ordering, fmt::Display, mem::size_of};
 
 use byteorder::{ByteOrder, LittleEndian};
 use primes::is_prime;
@@ -96,7 +96,13 @@ impl Record for IntRecord {
     }
 
     fn print(&self) {
-        println!("{:?} <=> {}", self.numbers, self.get_primes());
+        println!("{}", self);
+    }
+}
+
+impl Display for IntRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} <=> {}", self.numbers, self.get_primes())
     }
 }
 
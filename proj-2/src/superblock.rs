@@ -0,0 +1,132 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const SUPERBLOCK_MAGIC: [u8; 8] = *b"SBDBTREE";
+pub const BPLUS_SUPERBLOCK_MAGIC: [u8; 8] = *b"SBDBPLUS";
+pub const SUPERBLOCK_VERSION: u32 = 3;
+pub const SUPERBLOCK_SIZE: u64 = 8 + 4 + 9 * 8 + 4;
+
+/*
+ * Header stored in the first block of the index device, describes the tree
+ * well enough to resume work on it in a later session. Magic tells which
+ * kind of tree wrote the device, `duplicate_mode` follows the sizes and
+ * only has a meaning for the BTree.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Superblock {
+    pub magic: [u8; 8],
+    pub block_size: u64,
+    pub data_block_size: u64,
+    pub key_size: u64,
+    pub degree: u64,
+    pub root_lba: u64,
+    pub pages_count: u64,
+    pub data_pages_count: u64,
+    pub free_list_lba: u64,
+    pub free_pages_count: u64,
+    pub duplicate_mode: u32,
+}
+
+impl Superblock {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.block_size as usize];
+
+        bytes[0..8].copy_from_slice(&self.magic);
+        LittleEndian::write_u32(&mut bytes[8..12], SUPERBLOCK_VERSION);
+        LittleEndian::write_u64_into(
+            &[
+                self.block_size,
+                self.data_block_size,
+                self.key_size,
+                self.degree,
+                self.root_lba,
+                self.pages_count,
+                self.data_pages_count,
+                self.free_list_lba,
+                self.free_pages_count,
+            ],
+            &mut bytes[12..84],
+        );
+        LittleEndian::write_u32(&mut bytes[84..88], self.duplicate_mode);
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        if (bytes.len() as u64) < SUPERBLOCK_SIZE
+            || (bytes[0..8] != SUPERBLOCK_MAGIC && bytes[0..8] != BPLUS_SUPERBLOCK_MAGIC)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Device does not start with a BTree superblock",
+            ));
+        }
+
+        let version = LittleEndian::read_u32(&bytes[8..12]);
+        if version != SUPERBLOCK_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported superblock version {}", version),
+            ));
+        }
+
+        let mut fields = [0u64; 9];
+        LittleEndian::read_u64_into(&bytes[12..84], &mut fields);
+
+        let mut magic = [0u8; 8];
+        magic.copy_from_slice(&bytes[0..8]);
+
+        Ok(Superblock {
+            magic,
+            block_size: fields[0],
+            data_block_size: fields[1],
+            key_size: fields[2],
+            degree: fields[3],
+            root_lba: fields[4],
+            pages_count: fields[5],
+            data_pages_count: fields[6],
+            free_list_lba: fields[7],
+            free_pages_count: fields[8],
+            duplicate_mode: LittleEndian::read_u32(&bytes[84..88]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<(), std::io::Error> {
+        let superblock = Superblock {
+            magic: BPLUS_SUPERBLOCK_MAGIC,
+            block_size: 88,
+            data_block_size: 60,
+            key_size: 4,
+            degree: 2,
+            root_lba: 7,
+            pages_count: 12,
+            data_pages_count: 30,
+            free_list_lba: 5,
+            free_pages_count: 2,
+            duplicate_mode: 2,
+        };
+
+        let bytes = superblock.to_bytes();
+
+        assert_eq!(bytes.len(), 88);
+        assert_eq!(Superblock::from_bytes(&bytes)?, superblock);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_magic() -> Result<(), std::io::Error> {
+        let bytes = vec![0u8; 88];
+
+        let error = Superblock::from_bytes(&bytes).unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+}
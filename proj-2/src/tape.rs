@@ -0,0 +1,133 @@
+use std::marker::PhantomData;
+
+use crate::{device::BlockDevice, record::Record};
+
+/*
+ * Reads records from a tape written by proj-1. Records never cross block
+ * boundaries, the rest of a block that cannot hold another one is skipped.
+ * The tape ends with an empty record or with the end of the file, any other
+ * failure is returned and ends the tape as well.
+ */
+pub struct TapeReader<T: Record> {
+    device: BlockDevice,
+    lba: u64,
+    offset: u64,
+    buf: Option<Vec<u8>>,
+    ended: bool,
+    record: PhantomData<T>,
+}
+
+impl<T: Record> TapeReader<T> {
+    pub fn new(path: &str, block_size: u64) -> Result<Self, std::io::Error> {
+        if block_size < T::get_size() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Tape blocks are too small to hold a record",
+            ));
+        }
+
+        Ok(TapeReader {
+            device: BlockDevice::new(path.to_string(), block_size, false)?,
+            lba: 0,
+            offset: 0,
+            buf: None,
+            ended: false,
+            record: PhantomData,
+        })
+    }
+
+    fn read_next(&mut self) -> Result<Option<T>, std::io::Error> {
+        let size = T::get_size();
+        if self.offset + size > self.device.block_size {
+            self.lba += 1;
+            self.offset = 0;
+            self.buf = None;
+        }
+
+        let buf = match &self.buf {
+            Some(buf) => buf,
+            None => match self.device.read(self.lba) {
+                Ok(buf) => self.buf.insert(buf),
+                Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(error) => return Err(error),
+            },
+        };
+
+        let off = self.offset as usize;
+        let bytes = buf[off..off + size as usize].to_vec();
+        if bytes.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+        self.offset += size;
+
+        T::from_bytes(bytes).map(Some)
+    }
+}
+
+impl<T: Record> Iterator for TapeReader<T> {
+    type Item = Result<T, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ended {
+            return None;
+        }
+
+        let next = self.read_next().transpose();
+        self.ended = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::IntRecord;
+
+    use super::*;
+
+    #[test]
+    fn test_tape_reader() -> Result<(), std::io::Error> {
+        let path = "test_tape_reader.txt";
+        let block_size = 230; // 3 records, 50 bytes unused
+        let mut device = BlockDevice::new(path.to_string(), block_size, true)?;
+
+        let records = (1..=7)
+            .map(|value| IntRecord::from_string(format!("{} 2", value)))
+            .collect::<Result<Vec<IntRecord>, std::io::Error>>()?;
+        for (lba, chunk) in records.chunks(3).enumerate() {
+            let mut buf = vec![0u8; block_size as usize];
+            for (index, record) in chunk.iter().enumerate() {
+                let off = index * IntRecord::get_size() as usize;
+                buf[off..off + IntRecord::get_size() as usize].copy_from_slice(&record.get_bytes());
+            }
+            device.write(lba as u64, &buf)?;
+        }
+
+        let read = TapeReader::<IntRecord>::new(path, block_size)?
+            .map(|record| record.map(|record| record.get_bytes()))
+            .collect::<Result<Vec<Vec<u8>>, std::io::Error>>()?;
+        let expected = records.iter().map(|record| record.get_bytes()).collect::<Vec<Vec<u8>>>();
+        assert_eq!(read, expected);
+
+        /*
+         * An empty record ends the tape, a record that cannot be read is an
+         * error and ends it too
+         */
+        let mut buf = vec![0u8; block_size as usize];
+        buf[..IntRecord::get_size() as usize].copy_from_slice(&records[0].get_bytes());
+        device.write(0, &buf)?;
+        assert_eq!(TapeReader::<IntRecord>::new(path, block_size)?.count(), 1);
+
+        buf[IntRecord::get_size() as usize + 4] = 1;
+        device.write(0, &buf)?;
+        let mut reader = TapeReader::<IntRecord>::new(path, block_size)?;
+        assert!(reader.next().is_some_and(|record| record.is_ok()));
+        assert!(reader.next().is_some_and(|record| record.is_err()));
+        assert!(reader.next().is_none());
+
+        assert!(TapeReader::<IntRecord>::new(path, 32).is_err());
+
+        Ok(())
+    }
+}
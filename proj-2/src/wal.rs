@@ -0,0 +1,211 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const WAL_INDEX_DEVICE: u8 = 0;
+pub const WAL_DATA_DEVICE: u8 = 1;
+
+const WAL_IMAGE: u8 = 1;
+const WAL_COMMIT: u8 = 2;
+const WAL_IMAGE_HEADER_SIZE: usize = 1 + 1 + 8 + 4;
+const WAL_COMMIT_SIZE: usize = 1 + 8;
+
+/*
+ * New contents of a single block of one of the devices
+ */
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LogImage {
+    pub device: u8,
+    pub lba: u64,
+    pub bytes: Vec<u8>,
+}
+
+/*
+ * Redo log of block images. Images are appended in groups, each closed by a
+ * commit record with a checksum of the whole group, and the file is synced
+ * before any of them is written into its device. A group that was not fully
+ * written is ignored, so replaying the log applies every operation either
+ * completely or not at all.
+ */
+pub struct Wal {
+    file: File,
+    pub size: u64,
+    pub syncs: u64,
+}
+
+/*
+ * FNV-1a, enough to tell a torn write from a complete group
+ */
+fn checksum(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+const CHECKSUM_SEED: u64 = 0xcbf29ce484222325;
+
+impl Wal {
+    pub fn new(path: &str, truncate: bool) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new()
+            .truncate(truncate)
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Wal { file, size, syncs: 0 })
+    }
+
+    pub fn append(&mut self, images: &[LogImage]) -> Result<(), std::io::Error> {
+        let mut bytes = vec![];
+        for image in images {
+            let mut header = [0u8; WAL_IMAGE_HEADER_SIZE];
+            header[0] = WAL_IMAGE;
+            header[1] = image.device;
+            LittleEndian::write_u64(&mut header[2..10], image.lba);
+            LittleEndian::write_u32(&mut header[10..14], image.bytes.len() as u32);
+
+            bytes.extend_from_slice(&header);
+            bytes.extend_from_slice(&image.bytes);
+        }
+
+        let mut commit = [0u8; WAL_COMMIT_SIZE];
+        commit[0] = WAL_COMMIT;
+        LittleEndian::write_u64(&mut commit[1..], checksum(CHECKSUM_SEED, &bytes));
+        bytes.extend_from_slice(&commit);
+
+        self.file.seek(SeekFrom::Start(self.size))?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.syncs += 1;
+        self.size += bytes.len() as u64;
+
+        Ok(())
+    }
+
+    /*
+     * Images of all complete groups in the order they were appended
+     */
+    pub fn read(&mut self) -> Result<Vec<LogImage>, std::io::Error> {
+        let mut bytes = vec![];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+
+        let mut committed = vec![];
+        let mut group = vec![];
+        let mut group_start = 0;
+        let mut off = 0;
+
+        while off < bytes.len() {
+            match bytes[off] {
+                WAL_IMAGE if off + WAL_IMAGE_HEADER_SIZE <= bytes.len() => {
+                    let device = bytes[off + 1];
+                    let lba = LittleEndian::read_u64(&bytes[off + 2..off + 10]);
+                    let len = LittleEndian::read_u32(&bytes[off + 10..off + 14]) as usize;
+                    let start = off + WAL_IMAGE_HEADER_SIZE;
+                    if start + len > bytes.len() {
+                        break;
+                    }
+
+                    group.push(LogImage {
+                        device,
+                        lba,
+                        bytes: bytes[start..start + len].to_vec(),
+                    });
+                    off = start + len;
+                }
+                WAL_COMMIT if off + WAL_COMMIT_SIZE <= bytes.len() => {
+                    let expected = LittleEndian::read_u64(&bytes[off + 1..off + WAL_COMMIT_SIZE]);
+                    if checksum(CHECKSUM_SEED, &bytes[group_start..off]) != expected {
+                        break;
+                    }
+
+                    committed.append(&mut group);
+                    off += WAL_COMMIT_SIZE;
+                    group_start = off;
+                }
+                _ => break,
+            }
+        }
+
+        Ok(committed)
+    }
+
+    /*
+     * Called once every logged image reached its device
+     */
+    pub fn clear(&mut self) -> Result<(), std::io::Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.syncs += 1;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(lba: u64, value: u8) -> LogImage {
+        LogImage {
+            device: WAL_INDEX_DEVICE,
+            lba,
+            bytes: vec![value; 16],
+        }
+    }
+
+    #[test]
+    fn test_append_read() -> Result<(), std::io::Error> {
+        let mut wal = Wal::new("test_wal_append_read.wal", true)?;
+
+        wal.append(&[image(1, 1), image(2, 2)])?;
+        wal.append(&[image(1, 3)])?;
+        assert_eq!(wal.read()?, vec![image(1, 1), image(2, 2), image(1, 3)]);
+
+        let mut wal = Wal::new("test_wal_append_read.wal", false)?;
+        assert_eq!(wal.read()?.len(), 3);
+
+        wal.clear()?;
+        assert_eq!(wal.read()?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_group() -> Result<(), std::io::Error> {
+        let path = "test_wal_torn_group.wal";
+        let mut wal = Wal::new(path, true)?;
+
+        wal.append(&[image(1, 1)])?;
+        let size = wal.size;
+        wal.append(&[image(2, 2), image(3, 3)])?;
+
+        /*
+         * Group cut short anywhere is dropped as a whole
+         */
+        for cut in [1, WAL_COMMIT_SIZE as u64, 20] {
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(wal.size - cut)?;
+            assert_eq!(Wal::new(path, false)?.read()?, vec![image(1, 1)]);
+        }
+
+        /*
+         * So is a complete group with damaged contents
+         */
+        let mut wal = Wal::new(path, true)?;
+        wal.append(&[image(1, 1)])?;
+        wal.append(&[image(2, 2)])?;
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(size + WAL_IMAGE_HEADER_SIZE as u64))?;
+        file.write_all(&[7u8])?;
+        assert_eq!(Wal::new(path, false)?.read()?, vec![image(1, 1)]);
+
+        Ok(())
+    }
+}